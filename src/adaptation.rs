@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+/// How often a viewer's connection is sampled to adjust its encoder
+pub const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A snapshot of a viewer's connection, taken from the QUIC congestion controller
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Smoothed round trip time
+    pub rtt: Duration,
+    /// Congestion window in bytes
+    pub cwnd: u64,
+    /// Congestion events since the connection was opened
+    pub congestion_events: u64,
+    /// Frames skipped because the viewer fell behind, since it started watching
+    pub dropped_frames: u64,
+}
+
+/// What a viewer's encoder is asked to produce
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncoderSettings {
+    /// Target bitrate in kbit/s
+    pub bitrate: u32,
+    /// Upper bound on the frames per second
+    pub framerate: u32,
+    /// The desktop's width and height are divided by this, `0` is taken as `1`
+    pub downscale: u32,
+}
+
+impl EncoderSettings {
+    /// The encoded resolution of a `width` by `height` desktop, rounded down to even numbers
+    /// since 4:2:0 chroma can't address odd sizes
+    pub fn scaled_size(&self, width: i32, height: i32) -> (i32, i32) {
+        let downscale = self.downscale.clamp(1, i32::MAX as u32) as i32;
+        let scale = |v: i32| ((v / downscale) & !1).max(2);
+        (scale(width), scale(height))
+    }
+}
+
+/// Adjusts a viewer's encoder to its connection
+///
+/// The bitrate grows slowly while the link is healthy and is cut back sharply on congestion,
+/// lost frames or a growing round trip time. Framerate and resolution follow the bitrate along
+/// [RateController::LADDER] so the encoder never has to spread too few bits over too many pixels.
+#[derive(Clone, Debug)]
+pub struct RateController {
+    width: i32,
+    height: i32,
+    bitrate: u32,
+    level: usize,
+    min_rtt: Option<Duration>,
+    last: LinkStats,
+}
+
+impl RateController {
+    pub const MIN_BITRATE: u32 = 250;
+    pub const MAX_BITRATE: u32 = 20_000;
+    pub const START_BITRATE: u32 = 4_000;
+
+    /// `(downscale, framerate)` pairs, from best to worst
+    pub const LADDER: [(u32, u32); 5] = [(1, 60), (1, 30), (2, 30), (2, 15), (4, 15)];
+
+    /// The fewest bits per pixel a level is used with
+    const MIN_BITS_PER_PIXEL: f64 = 0.02;
+    /// How much headroom the next better level needs before switching to it
+    const UPGRADE_HEADROOM: f64 = 2.0;
+
+    pub fn new(width: i32, height: i32) -> Self {
+        let mut controller = Self {
+            width,
+            height,
+            bitrate: Self::START_BITRATE,
+            level: 0,
+            min_rtt: None,
+            last: LinkStats::default(),
+        };
+        controller.pick_level();
+        controller
+    }
+
+    pub fn settings(&self) -> EncoderSettings {
+        let (downscale, framerate) = Self::LADDER[self.level];
+        EncoderSettings {
+            bitrate: self.bitrate,
+            framerate,
+            downscale,
+        }
+    }
+
+    /// Takes a new snapshot of the connection, returning the settings if they changed
+    pub fn update(&mut self, stats: LinkStats) -> Option<EncoderSettings> {
+        let before = self.settings();
+
+        if !stats.rtt.is_zero() {
+            self.min_rtt = Some(self.min_rtt.map_or(stats.rtt, |rtt| rtt.min(stats.rtt)));
+        }
+
+        // Twice the best round trip time seen means packets are queueing somewhere
+        let queueing = self.min_rtt.is_some_and(|min_rtt| stats.rtt > min_rtt * 2);
+        let congested = stats.congestion_events > self.last.congestion_events
+            || stats.dropped_frames > self.last.dropped_frames
+            || queueing;
+
+        self.bitrate = if congested {
+            let decreased = self.bitrate / 10 * 7;
+            match Self::window_bitrate(&stats) {
+                Some(window) => decreased.min(window),
+                None => decreased,
+            }
+        } else {
+            self.bitrate + (self.bitrate / 20).max(50)
+        }
+        .clamp(Self::MIN_BITRATE, Self::MAX_BITRATE);
+
+        self.last = stats;
+        self.pick_level();
+
+        let after = self.settings();
+        (after != before).then_some(after)
+    }
+
+    /// The bitrate in kbit/s one congestion window per round trip amounts to
+    fn window_bitrate(stats: &LinkStats) -> Option<u32> {
+        if stats.rtt.is_zero() {
+            return None;
+        }
+        let kbps = stats.cwnd as f64 * 8.0 / stats.rtt.as_secs_f64() / 1000.0;
+        Some(kbps.min(u32::MAX as f64) as u32)
+    }
+
+    fn bits_per_pixel(&self, level: usize) -> f64 {
+        let (downscale, framerate) = Self::LADDER[level];
+        let pixels = (self.width as f64 / downscale as f64)
+            * (self.height as f64 / downscale as f64)
+            * framerate as f64;
+        self.bitrate as f64 * 1000.0 / pixels
+    }
+
+    fn pick_level(&mut self) {
+        while self.level + 1 < Self::LADDER.len()
+            && self.bits_per_pixel(self.level) < Self::MIN_BITS_PER_PIXEL
+        {
+            self.level += 1;
+        }
+        while self.level > 0
+            && self.bits_per_pixel(self.level - 1)
+                >= Self::MIN_BITS_PER_PIXEL * Self::UPGRADE_HEADROOM
+        {
+            self.level -= 1;
+        }
+    }
+}
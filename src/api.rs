@@ -1,44 +1,172 @@
 use std::{
-    net::{SocketAddr, TcpListener},
-    // ops::Deref,
+    collections::VecDeque,
+    net::{SocketAddr, UdpSocket},
+    path::Path,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-// use axum::{response::IntoResponse, routing::get, Extension, Json, Router};
+use futures::{SinkExt, StreamExt};
 
-use log::{debug, info};
+use log::{debug, info, warn};
 
-use tokio::sync::{broadcast::Receiver, mpsc::Sender};
+use quinn::{EndpointConfig, RecvStream, SendStream};
+
+use tokio::{
+    sync::{
+        broadcast::{error::RecvError, Receiver},
+        mpsc::Sender,
+        watch,
+    },
+    task::JoinHandle,
+    time::MissedTickBehavior,
+};
+
+use tokio_util::codec::{FramedRead, FramedWrite};
 
 use zbus::{dbus_interface, ConnectionBuilder};
 
-use crate::{capture::Desktop, input::InputManagerEvent};
+use crate::{
+    adaptation::{LinkStats, RateController, UPDATE_INTERVAL},
+    capture::{Desktop, EncodedStream, KeyframeRequester, VideoEncoder},
+    clipboard::ClipboardManager,
+    codec::{LodestarCodec, LodestarCodecError},
+    input::{ClientEvent, DesktopLayout, GamepadEvent, InputManagerEvent, PenEvent, TouchEvent},
+    protocol::{
+        ClipboardMime, LodestarCapabilities, LodestarClipboardDataPacket, LodestarClipboardEntry,
+        LodestarClipboardOfferPacket, LodestarClipboardRequestPacket, LodestarCloseReason,
+        LodestarDesktop, LodestarDesktopPacket, LodestarEndPacket, LodestarHandshakePacket,
+        LodestarHandshakeResultPacket, LodestarHandshakeStatus, LodestarInput, LodestarInputEvent,
+        LodestarInputPacket, LodestarKeyframeRequestPacket, LodestarPacket,
+        LodestarPacketParsingError, LodestarPacketType, LodestarSessionClosedPacket,
+        LodestarSwitchSourcePacket, PairingKey, MIN_API_REVISION,
+    },
+};
 
 use super::Result;
 
+/// The shortest time between two keyframe requests of a client that are honoured
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApiError {
+    #[error("ApiManager is already running")]
+    AlreadyRunning,
+    #[error("The client's api revisions {0:?} are not supported")]
+    NoCommonRevision(std::ops::RangeInclusive<u64>),
+    #[error("The client sent an unexpected {0:?} packet")]
+    UnexpectedPacket(LodestarPacketType),
+    #[error("The client requested unknown desktop {0}")]
+    UnknownDesktop(u64),
+    #[error("The InputManager is no longer receiving events")]
+    InputManagerClosed,
+    #[error("The client closed the stream before completing the handshake")]
+    ClosedDuringHandshake,
+    #[error("Refusing to listen on {0} without requiring clients to be paired")]
+    UnpairedBind(SocketAddr),
+    #[error("The client didn't present the pairing key")]
+    Unauthorized,
+    #[error("The desktop's pipeline failed")]
+    PipelineFailed,
+    #[error("Failed to parse packet: {0}")]
+    Parsing(#[from] LodestarPacketParsingError),
+    #[error("Failed to frame packet: {0}")]
+    Codec(#[from] LodestarCodecError),
+}
+
+/// The desktops of the portal session, as clients are told about them
+#[derive(Debug, Clone)]
+pub enum SessionState {
+    Opened {
+        desktops: Arc<Vec<Desktop>>,
+        /// Where the desktops are in the compositor's layout
+        layout: Arc<DesktopLayout>,
+    },
+    /// Nothing is shared until the session is opened again, if it is `reopening`
+    Closed {
+        reason: LodestarCloseReason,
+        reopening: bool,
+    },
+}
+
+impl SessionState {
+    pub fn opened(desktops: Vec<Desktop>) -> Self {
+        Self::Opened {
+            layout: Arc::new(DesktopLayout::new(&desktops)),
+            desktops: Arc::new(desktops),
+        }
+    }
+}
+
+/// Where clients reach loded and whether they have to be paired first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApiSettings {
+    pub address: SocketAddr,
+    /// Clients always have to be paired when `address` isn't a loopback address
+    pub require_pairing: bool,
+}
+
+impl Default for ApiSettings {
+    /// Only reachable from this machine, on a port picked by the system
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([127, 0, 0, 1], 0)),
+            require_pairing: false,
+        }
+    }
+}
+
+impl ApiSettings {
+    pub fn pairing_required(&self) -> bool {
+        self.require_pairing || !self.address.ip().is_loopback()
+    }
+}
+
 #[derive(Debug)]
 pub struct ApiManager {
     pub port: u16,
     ds_rx: Receiver<()>,
-    stream: Option<TcpListener>,
-    event_notifier: Arc<Sender<InputManagerEvent>>,
+    socket: Option<UdpSocket>,
+    event_notifier: Arc<Sender<ClientEvent>>,
+    clipboard: Option<Arc<ClipboardManager>>,
+    /// The key clients have to present, if pairing is required
+    pairing_key: Option<Arc<PairingKey>>,
 }
 
 impl ApiManager {
+    /// Clients only get to share the clipboard if `clipboard` is given
+    ///
+    /// If `settings` require pairing, the key clients have to present is read from `pairing-key`
+    /// in the working directory, or generated there.
     pub async fn new(
         ds_rx: Receiver<()>,
-        event_notifier: Sender<InputManagerEvent>,
+        event_notifier: Sender<ClientEvent>,
+        clipboard: Option<Arc<ClipboardManager>>,
+        settings: ApiSettings,
     ) -> Result<Self> {
-        let stream = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
-        let port = stream.local_addr()?.port();
+        let pairing_key = if settings.pairing_required() {
+            let key = helpers::read_pairing_key(Path::new(".")).await?;
+            info!("Clients have to present the pairing key stored in ./pairing-key");
+            Some(Arc::new(key))
+        } else {
+            None
+        };
+        if pairing_key.is_none() && !settings.address.ip().is_loopback() {
+            return Err(ApiError::UnpairedBind(settings.address).into());
+        }
+
+        let socket = UdpSocket::bind(settings.address)?;
+        let port = socket.local_addr()?.port();
 
-        info!("Bound to port {}", port);
+        info!("Bound to {}", socket.local_addr()?);
 
         let api = Self {
             port,
             ds_rx,
-            stream: Some(stream),
+            socket: Some(socket),
             event_notifier: Arc::new(event_notifier),
+            clipboard,
+            pairing_key,
         };
         let api_announcer = ApiManagerAnnouncer { port };
 
@@ -53,29 +181,733 @@ impl ApiManager {
         Ok(api)
     }
 
-    pub async fn run(&mut self, desktops: Vec<Desktop>) -> Result<()> {
-        /*
-        let router = Router::new()
-            .route("/desktops", get(get_desktops))
-            .layer(Extension(self.event_notifier.clone()))
-            .layer(Extension(Arc::new(desktops)));
-        let server = axum::Server::from_tcp(self.stream.take().unwrap())?
-            .serve(router.into_make_service())
-            .with_graceful_shutdown(async {
-                self.ds_rx
-                    .recv()
-                    .await
-                    .expect("Failed to receive shutdown signal");
-            });
+    /// Serves clients the desktops of `session`, following it as it is closed and reopened
+    pub async fn run(&mut self, session: watch::Receiver<SessionState>) -> Result<()> {
+        let socket = self.socket.take().ok_or(ApiError::AlreadyRunning)?;
+
+        let (key, cert) = helpers::read_certs(Arc::from(Path::new("."))).await?;
+        let config = helpers::server_config(vec![cert], key)?;
+        let (endpoint, mut incoming) =
+            quinn::Endpoint::new(EndpointConfig::default(), Some(config), socket)?;
+
+        let available_codecs = VideoEncoder::available();
+        debug!("Available codecs: {available_codecs:?}");
+
+        let mut next_client_id = 0;
 
         info!("Starting server");
 
-        server.await.map_err(|e| e.into())
-        */
+        loop {
+            tokio::select! {
+                _ = self.ds_rx.recv() => {
+                    info!("Stopping server");
+                    break;
+                }
+                connecting = incoming.next() => {
+                    let connecting = match connecting {
+                        Some(v) => v,
+                        None => break,
+                    };
+                    let client = ClientConnection {
+                        id: next_client_id,
+                        session: session.clone(),
+                        desktops: Arc::default(),
+                        layout: Arc::default(),
+                        event_notifier: self.event_notifier.clone(),
+                        clipboard: self.clipboard.clone(),
+                        pairing_key: self.pairing_key.clone(),
+                        available_codecs,
+                        revision: 0,
+                        capabilities: LodestarCapabilities::NONE,
+                        source: None,
+                        video_task: None,
+                        keyframes: None,
+                        last_keyframe_request: None,
+                    };
+                    next_client_id += 1;
+                    let event_notifier = self.event_notifier.clone();
+                    tokio::spawn(async move {
+                        let remote = connecting.remote_address();
+                        let id = client.id;
+                        match client.serve(connecting).await {
+                            Ok(_) => info!("Client {remote} disconnected"),
+                            Err(e) => warn!("Client {remote} disconnected with an error: {e}"),
+                        }
+                        // However the client left, nothing it pressed may stay held down
+                        let disconnected = ClientEvent {
+                            client: id,
+                            event: InputManagerEvent::Disconnected,
+                        };
+                        if event_notifier.send(disconnected).await.is_err() {
+                            warn!("Could not release the input of client {remote}");
+                        }
+                    });
+                }
+            }
+        }
+
+        endpoint.close(0u32.into(), b"shutdown");
+        endpoint.wait_idle().await;
+
         Ok(())
     }
 }
 
+/// State kept for a single connected client
+struct ClientConnection {
+    /// Unique among the clients of this run, tells their input apart
+    id: u64,
+    session: watch::Receiver<SessionState>,
+    /// The desktops of the session, empty while it is closed
+    desktops: Arc<Vec<Desktop>>,
+    /// Where the desktops are in the compositor's layout
+    layout: Arc<DesktopLayout>,
+    event_notifier: Arc<Sender<ClientEvent>>,
+    /// The host's clipboard, if the portal session shares it
+    clipboard: Option<Arc<ClipboardManager>>,
+    /// The key the client has to present during the handshake, if pairing is required
+    pairing_key: Option<Arc<PairingKey>>,
+    /// The codecs whose encoder is installed, offered during the handshake
+    available_codecs: LodestarCapabilities,
+    /// The protocol revision agreed on during the handshake
+    revision: u64,
+    /// Capabilities supported by both the client and loded
+    capabilities: LodestarCapabilities,
+    /// The `loded_id` of the desktop the client is currently viewing
+    source: Option<u64>,
+    /// Task forwarding the frames of `source` to the client, ending with the reason to tell the
+    /// client if the video stopped on its own
+    video_task: Option<JoinHandle<Option<LodestarCloseReason>>>,
+    /// Forces the encoder of `source` to emit a keyframe
+    keyframes: Option<KeyframeRequester>,
+    /// When the client last had a keyframe request honoured
+    last_keyframe_request: Option<Instant>,
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        if let Some(task) = self.video_task.take() {
+            task.abort();
+        }
+    }
+}
+
+type PacketStream = FramedRead<RecvStream, LodestarCodec>;
+type PacketSink = FramedWrite<SendStream, LodestarCodec>;
+
+impl ClientConnection {
+    async fn serve(mut self, connecting: quinn::Connecting) -> Result<()> {
+        let mut new_conn = connecting.await?;
+        let connection = new_conn.connection.clone();
+        info!("Accepted connection from {}", connection.remote_address());
+
+        let (send, recv) = match new_conn.bi_streams.next().await {
+            Some(v) => v?,
+            None => return Ok(()),
+        };
+        let mut sink = FramedWrite::new(send, LodestarCodec::new());
+        let mut stream = FramedRead::new(recv, LodestarCodec::new());
+
+        if let Err(e) = self.handshake(&mut sink, &mut stream).await {
+            connection.close(1u32.into(), b"handshake failed");
+            return Err(e);
+        }
+
+        // Clients expect the desktop list right after the handshake, even if it is empty
+        let state = self.session.borrow_and_update().clone();
+        self.follow_session(&state);
+        sink.send(self.desktop_packet()).await?;
+        if let SessionState::Closed { reason, reopening } = state {
+            sink.send(LodestarSessionClosedPacket { reason, reopening })
+                .await?;
+        }
+
+        let mut offers = match &self.clipboard {
+            Some(clipboard) if self.capabilities.contains(LodestarCapabilities::CLIPBOARD) => {
+                let mut offers = clipboard.subscribe();
+                let mime_types = offers.borrow_and_update().clone();
+                if !mime_types.is_empty() {
+                    sink.send(LodestarClipboardOfferPacket { mime_types })
+                        .await?;
+                }
+                Some(offers)
+            }
+            _ => None,
+        };
+        let mut pulls = VecDeque::new();
+
+        loop {
+            let packet = tokio::select! {
+                packet = stream.next() => match packet {
+                    Some(packet) => packet?,
+                    None => break,
+                },
+                Some(mime_types) = next_offer(&mut offers) => {
+                    sink.send(LodestarClipboardOfferPacket { mime_types }).await?;
+                    continue;
+                }
+                Some(reason) = video_stopped(&mut self.video_task) => {
+                    sink.send(LodestarSessionClosedPacket {
+                        reason,
+                        reopening: false,
+                    })
+                    .await?;
+                    continue;
+                }
+                answer = next_pull(&mut pulls) => {
+                    match answer {
+                        Ok(answer) => sink.send(answer).await?,
+                        Err(e) => warn!("Failed to read the host's clipboard: {e}"),
+                    }
+                    continue;
+                }
+                Ok(()) = self.session.changed() => {
+                    let state = self.session.borrow_and_update().clone();
+                    self.follow_session(&state);
+                    match state {
+                        SessionState::Opened { .. } => sink.send(self.desktop_packet()).await?,
+                        SessionState::Closed { reason, reopening } => {
+                            sink.send(LodestarSessionClosedPacket { reason, reopening })
+                                .await?
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            match packet.packet_type() {
+                LodestarPacketType::SwitchSource => {
+                    let packet = packet.parse_packet::<LodestarSwitchSourcePacket>()?;
+                    // The desktops may have changed while the switch was in flight
+                    if let Err(e) = self.switch_source(&connection, packet.new_source) {
+                        warn!("Ignoring the client's switch: {e}");
+                        sink.send(self.desktop_packet()).await?;
+                    }
+                }
+                LodestarPacketType::Input => {
+                    let packet = packet.parse_packet::<LodestarInputPacket>()?;
+                    for event in
+                        input_manager_events(packet.events, self.capabilities, &self.layout)
+                    {
+                        self.event_notifier
+                            .send(ClientEvent {
+                                client: self.id,
+                                event,
+                            })
+                            .await
+                            .map_err(|_| ApiError::InputManagerClosed)?;
+                    }
+                }
+                LodestarPacketType::KeyframeRequest => {
+                    let packet = packet.parse_packet::<LodestarKeyframeRequestPacket>()?;
+                    self.request_keyframe(packet.loded_id);
+                }
+                LodestarPacketType::ClipboardRequest => {
+                    let packet = packet.parse_packet::<LodestarClipboardRequestPacket>()?;
+                    match self.clipboard() {
+                        // Reading can take a while, which mustn't hold up the client's input
+                        Some(clipboard) => pulls.push_back(tokio::spawn(pull_clipboard(
+                            clipboard.clone(),
+                            packet.mime_type,
+                        ))),
+                        None => {
+                            warn!("Dropping clipboard request, the clipboard wasn't negotiated");
+                            sink.send(LodestarClipboardDataPacket {
+                                entries: Vec::new(),
+                            })
+                            .await?;
+                        }
+                    }
+                }
+                LodestarPacketType::ClipboardData => {
+                    let packet = packet.parse_packet::<LodestarClipboardDataPacket>()?;
+                    self.push_clipboard(packet.entries).await;
+                }
+                LodestarPacketType::End => {
+                    sink.send(LodestarEndPacket {}).await?;
+                    break;
+                }
+                ty => return Err(ApiError::UnexpectedPacket(ty).into()),
+            }
+        }
+
+        // Every send flushes, so nothing is left buffered in the sink at this point
+        sink.into_inner().finish().await?;
+        connection.close(0u32.into(), b"done");
+
+        Ok(())
+    }
+
+    /// Starts sending the frames of a different desktop to the client
+    fn switch_source(
+        &mut self,
+        connection: &quinn::Connection,
+        loded_id: u64,
+    ) -> std::result::Result<(), ApiError> {
+        let desktops = self.desktops.clone();
+        let desktop = desktops
+            .iter()
+            .find(|d| d.loded_id == loded_id)
+            .ok_or(ApiError::UnknownDesktop(loded_id))?;
+
+        self.stop_video();
+
+        debug!("Client switched to desktop {loded_id}");
+        self.source = Some(loded_id);
+
+        let encoder = match VideoEncoder::from_capabilities(self.capabilities) {
+            Some(v) => v,
+            None => {
+                warn!("Not sending video for desktop {loded_id}, no common codec was negotiated");
+                return Ok(());
+            }
+        };
+
+        let stream = match desktop.stream.as_ref() {
+            Some(v) => v,
+            None => {
+                warn!("Desktop {loded_id} has no running stream");
+                return Ok(());
+            }
+        };
+
+        let controller = RateController::new(desktop.width, desktop.height);
+        match stream.subscribe(encoder, controller.settings()) {
+            Ok(encoded) => {
+                self.keyframes = Some(encoded.keyframe_requester());
+                let connection = connection.clone();
+                self.video_task = Some(tokio::spawn(async move {
+                    match forward_video(connection, encoded, controller).await {
+                        Ok(()) => None,
+                        Err(e) => {
+                            warn!("Stopped sending video for desktop {loded_id}: {e}");
+                            // A broken connection is noticed by the client itself
+                            match e.downcast_ref::<ApiError>() {
+                                Some(ApiError::PipelineFailed) => {
+                                    Some(LodestarCloseReason::PipelineFailed)
+                                }
+                                _ => None,
+                            }
+                        }
+                    }
+                }));
+            }
+            Err(e) => warn!("Failed to encode desktop {loded_id} with {encoder:?}: {e}"),
+        }
+
+        Ok(())
+    }
+
+    fn stop_video(&mut self) {
+        if let Some(task) = self.video_task.take() {
+            task.abort();
+        }
+        self.keyframes = None;
+        self.source = None;
+    }
+
+    /// Switches to the desktops of the session's new state, which none of the previous desktops
+    /// belong to
+    fn follow_session(&mut self, state: &SessionState) {
+        self.stop_video();
+        match state {
+            SessionState::Opened { desktops, layout } => {
+                self.desktops = desktops.clone();
+                self.layout = layout.clone();
+            }
+            SessionState::Closed { .. } => {
+                self.desktops = Arc::default();
+                self.layout = Arc::default();
+            }
+        }
+    }
+
+    fn desktop_packet(&self) -> LodestarDesktopPacket {
+        LodestarDesktopPacket::new(
+            self.desktops
+                .iter()
+                .map(LodestarDesktop::from)
+                .collect::<Vec<LodestarDesktop>>(),
+        )
+    }
+
+    /// Forces a keyframe on the desktop the client is viewing, at most once per
+    /// [KEYFRAME_REQUEST_INTERVAL]
+    fn request_keyframe(&mut self, loded_id: u64) {
+        if self.source != Some(loded_id) {
+            debug!("Ignoring keyframe request for desktop {loded_id}, it isn't being viewed");
+            return;
+        }
+
+        let now = Instant::now();
+        if self
+            .last_keyframe_request
+            .is_some_and(|last| now.duration_since(last) < KEYFRAME_REQUEST_INTERVAL)
+        {
+            debug!("Ignoring keyframe request for desktop {loded_id}, the last one was too recent");
+            return;
+        }
+
+        if let Some(keyframes) = self.keyframes.as_ref() {
+            debug!("Forcing a keyframe on desktop {loded_id}");
+            keyframes.request();
+            self.last_keyframe_request = Some(now);
+        }
+    }
+
+    /// The clipboard the client negotiated to share
+    fn clipboard(&self) -> Option<&Arc<ClipboardManager>> {
+        self.clipboard
+            .as_ref()
+            .filter(|_| self.capabilities.contains(LodestarCapabilities::CLIPBOARD))
+    }
+
+    async fn push_clipboard(&self, entries: Vec<LodestarClipboardEntry>) {
+        match self.clipboard() {
+            Some(clipboard) => {
+                if let Err(e) = clipboard.push(entries).await {
+                    warn!("Could not set the host's clipboard: {e}");
+                }
+            }
+            None => warn!("Dropping clipboard contents, the clipboard wasn't negotiated"),
+        }
+    }
+
+    async fn handshake(&mut self, sink: &mut PacketSink, stream: &mut PacketStream) -> Result<()> {
+        let client_handshake = match stream.next().await {
+            Some(packet) => packet?.parse_packet::<LodestarHandshakePacket>()?,
+            None => return Err(ApiError::ClosedDuringHandshake.into()),
+        };
+
+        let result = match &client_handshake {
+            // Legacy clients can't present a pairing key
+            LodestarHandshakePacket::Legacy { .. } if self.pairing_key.is_some() => {
+                sink.send(LodestarHandshakePacket::Legacy {
+                    api_revision: MIN_API_REVISION,
+                    accepted: false,
+                })
+                .await?;
+                LodestarHandshakeResultPacket::unauthorized()
+            }
+            &LodestarHandshakePacket::Legacy { api_revision, .. } => {
+                let result = LodestarHandshakeResultPacket::negotiate(
+                    api_revision,
+                    api_revision,
+                    LodestarCapabilities::KEYBOARD | LodestarCapabilities::MOUSE_RELATIVE,
+                );
+                // An accepted client is told the revision it asked for, which is the one in use
+                let accepted = result.status == LodestarHandshakeStatus::Accepted;
+                sink.send(LodestarHandshakePacket::Legacy {
+                    api_revision: if accepted {
+                        result.revision
+                    } else {
+                        MIN_API_REVISION
+                    },
+                    accepted,
+                })
+                .await?;
+                result
+            }
+            LodestarHandshakePacket::Hello { pairing_key, .. }
+                if !self.is_paired(pairing_key.as_ref()) =>
+            {
+                let result = LodestarHandshakeResultPacket::unauthorized();
+                sink.send(result.clone()).await?;
+                result
+            }
+            &LodestarHandshakePacket::Hello {
+                min_revision,
+                max_revision,
+                capabilities,
+                ..
+            } => {
+                let mut capabilities = capabilities.restrict_codecs(self.available_codecs);
+                if self.clipboard.is_none() {
+                    capabilities = capabilities.without(LodestarCapabilities::CLIPBOARD);
+                }
+                let result = LodestarHandshakeResultPacket::negotiate(
+                    min_revision,
+                    max_revision,
+                    capabilities,
+                );
+                sink.send(result.clone()).await?;
+                result
+            }
+        };
+
+        match result.status {
+            LodestarHandshakeStatus::Accepted => {
+                debug!(
+                    "Accepted handshake for api revision {} with capabilities {:?}",
+                    result.revision, result.capabilities
+                );
+                self.revision = result.revision;
+                self.capabilities = result.capabilities;
+                Ok(())
+            }
+            LodestarHandshakeStatus::NoCommonRevision => {
+                Err(ApiError::NoCommonRevision(client_handshake.revision_range()).into())
+            }
+            LodestarHandshakeStatus::Unauthorized => Err(ApiError::Unauthorized.into()),
+        }
+    }
+
+    /// Whether `presented` is the pairing key, if one is required
+    fn is_paired(&self, presented: Option<&PairingKey>) -> bool {
+        match (&self.pairing_key, presented) {
+            (None, _) => true,
+            (Some(key), Some(presented)) => key.verify(presented),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Reads the host's clipboard for a client, which gets an answer without entries if that fails
+async fn pull_clipboard(
+    clipboard: Arc<ClipboardManager>,
+    mime_type: ClipboardMime,
+) -> LodestarClipboardDataPacket {
+    let mut entries = Vec::new();
+    match clipboard.pull(mime_type).await {
+        Ok(data) => entries.push(LodestarClipboardEntry { mime_type, data }),
+        Err(e) => warn!("Could not read the host's clipboard as {mime_type:?}: {e}"),
+    }
+    LodestarClipboardDataPacket { entries }
+}
+
+/// Why the video task stopped once it did, if the client has to be told, never resolving while
+/// there is no task
+async fn video_stopped(
+    task: &mut Option<JoinHandle<Option<LodestarCloseReason>>>,
+) -> Option<LodestarCloseReason> {
+    let Some(handle) = task else {
+        return std::future::pending().await;
+    };
+    let reason = handle.await.ok().flatten();
+    *task = None;
+    reason
+}
+
+/// The answer to the oldest clipboard request still being read, never resolving while there is
+/// none, so answers go out in the order the requests came in
+async fn next_pull(
+    pulls: &mut VecDeque<JoinHandle<LodestarClipboardDataPacket>>,
+) -> std::result::Result<LodestarClipboardDataPacket, tokio::task::JoinError> {
+    let Some(pull) = pulls.front_mut() else {
+        return std::future::pending().await;
+    };
+    let answer = pull.await;
+    pulls.pop_front();
+    answer
+}
+
+/// The formats the host's clipboard can be pulled in once it changes, never resolving while the
+/// client doesn't share the clipboard
+async fn next_offer(
+    offers: &mut Option<watch::Receiver<Vec<ClipboardMime>>>,
+) -> Option<Vec<ClipboardMime>> {
+    let Some(receiver) = offers else {
+        return std::future::pending().await;
+    };
+    match receiver.changed().await {
+        Ok(()) => Some(receiver.borrow_and_update().clone()),
+        Err(_) => {
+            // The ClipboardManager is gone, stop waiting on it
+            *offers = None;
+            None
+        }
+    }
+}
+
+/// Sends every frame on its own unidirectional stream, so a lost frame never blocks later ones
+///
+/// A viewer that just joined or fell behind is only sent frames again from the next keyframe on,
+/// which is forced right away after falling behind.
+/// Every [UPDATE_INTERVAL] the connection's stats are fed to `controller` to adapt the encoder.
+/// The viewer's encoder branch is torn down once this returns or is aborted.
+async fn forward_video(
+    connection: quinn::Connection,
+    mut encoded: EncodedStream,
+    mut controller: RateController,
+) -> Result<()> {
+    let keyframes = encoded.keyframe_requester();
+    let failed = encoded.failed();
+    tokio::pin!(failed);
+    let mut waiting_for_keyframe = true;
+    let mut dropped_frames = 0;
+    let mut updates = tokio::time::interval(UPDATE_INTERVAL);
+    updates.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let frame = tokio::select! {
+            res = encoded.frames.recv() => match res {
+                Ok(v) => v,
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("Viewer fell behind by {skipped} frames");
+                    dropped_frames += skipped;
+                    if !waiting_for_keyframe {
+                        waiting_for_keyframe = true;
+                        keyframes.request();
+                    }
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = &mut failed => return Err(ApiError::PipelineFailed.into()),
+            _ = updates.tick() => {
+                let path = connection.stats().path;
+                let stats = LinkStats {
+                    rtt: path.rtt,
+                    cwnd: path.cwnd,
+                    congestion_events: path.congestion_events,
+                    dropped_frames,
+                };
+                if let Some(settings) = controller.update(stats) {
+                    debug!("Adapting video to {stats:?}: {settings:?}");
+                    encoded.apply(settings);
+                }
+                continue;
+            }
+        };
+
+        if waiting_for_keyframe {
+            if !frame.keyframe {
+                continue;
+            }
+            waiting_for_keyframe = false;
+        }
+
+        // Dropping the stream finishes it
+        let mut stream = connection.open_uni().await?;
+        stream
+            .write_all(&LodestarPacket::encode_packet(&frame))
+            .await?;
+    }
+}
+
+/// Groups a batch of client input into [InputManagerEvent]s without reordering it
+///
+/// Events for capabilities that weren't negotiated are dropped.
+pub fn input_manager_events(
+    events: Vec<LodestarInputEvent>,
+    capabilities: LodestarCapabilities,
+    layout: &DesktopLayout,
+) -> Vec<InputManagerEvent> {
+    /// Appends `event` to `out`, merging it into the last event if they're of the same kind
+    fn push(out: &mut Vec<InputManagerEvent>, event: InputManagerEvent) {
+        use InputManagerEvent::*;
+
+        match (out.last_mut(), event) {
+            (Some(Keyboard(keys)), Keyboard(more)) => keys.extend(more),
+            // The InputManager emits motion before buttons, so motion following a button has
+            // to go into a new event
+            (Some(Mouse(Some(moves), None)), Mouse(Some(more), None)) => moves.extend(more),
+            (Some(Mouse(_, buttons)), Mouse(None, Some(more))) => {
+                buttons.get_or_insert_with(Vec::new).extend(more)
+            }
+            (Some(Absolute(positions)), Absolute(more)) => positions.extend(more),
+            (Some(Touch(touches)), Touch(more)) => touches.extend(more),
+            (Some(Pen(samples)), Pen(more)) => samples.extend(more),
+            (_, event) => out.push(event),
+        }
+    }
+
+    let keyboard = capabilities.contains(LodestarCapabilities::KEYBOARD);
+    let relative = capabilities.contains(LodestarCapabilities::MOUSE_RELATIVE);
+    let absolute = capabilities.contains(LodestarCapabilities::MOUSE_ABSOLUTE);
+    let touch = capabilities.contains(LodestarCapabilities::TOUCH);
+    let pen = capabilities.contains(LodestarCapabilities::PEN);
+    let gamepad = capabilities.contains(LodestarCapabilities::GAMEPAD);
+
+    let mut out = Vec::new();
+
+    for evt in events {
+        let event = match evt.input {
+            LodestarInput::Key(key) if keyboard => InputManagerEvent::Keyboard(vec![key]),
+            LodestarInput::Text(text) if keyboard => InputManagerEvent::Text(text),
+            LodestarInput::RelativeMotion(mme) if relative => {
+                InputManagerEvent::Mouse(Some(vec![mme]), None)
+            }
+            LodestarInput::Scroll(_) | LodestarInput::Wheel { .. } if relative => {
+                InputManagerEvent::Mouse(evt.input.hi_res_scroll().map(|scroll| vec![scroll]), None)
+            }
+            LodestarInput::AbsoluteMotion { loded_id, x, y } if absolute => {
+                match layout.to_absolute(loded_id, x, y) {
+                    Some(position) => InputManagerEvent::Absolute(vec![position]),
+                    None => {
+                        warn!("Dropping pointer position on unknown desktop {loded_id}");
+                        continue;
+                    }
+                }
+            }
+            LodestarInput::Button(btn) if relative || absolute => {
+                InputManagerEvent::Mouse(None, Some(vec![btn]))
+            }
+            LodestarInput::TouchStart {
+                loded_id,
+                contact,
+                x,
+                y,
+            }
+            | LodestarInput::TouchMove {
+                loded_id,
+                contact,
+                x,
+                y,
+            } if touch => match layout.to_absolute(loded_id, x, y) {
+                Some(position) => InputManagerEvent::Touch(vec![match evt.input {
+                    LodestarInput::TouchStart { .. } => TouchEvent::Down { contact, position },
+                    _ => TouchEvent::Move { contact, position },
+                }]),
+                None => {
+                    warn!("Dropping touch on unknown desktop {loded_id}");
+                    continue;
+                }
+            },
+            LodestarInput::TouchEnd { contact } if touch => {
+                InputManagerEvent::Touch(vec![TouchEvent::Up { contact }])
+            }
+            LodestarInput::Pen {
+                loded_id,
+                x,
+                y,
+                pressure,
+                tilt_x,
+                tilt_y,
+                buttons,
+            } if pen => match layout.to_absolute(loded_id, x, y) {
+                Some(position) => InputManagerEvent::Pen(vec![PenEvent {
+                    position,
+                    pressure,
+                    tilt_x,
+                    tilt_y,
+                    buttons,
+                }]),
+                None => {
+                    warn!("Dropping pen sample on unknown desktop {loded_id}");
+                    continue;
+                }
+            },
+            LodestarInput::Gamepad { pad, state } if gamepad => {
+                InputManagerEvent::Gamepad(GamepadEvent {
+                    pad,
+                    state: Some(state),
+                })
+            }
+            LodestarInput::GamepadRemoved { pad } if gamepad => {
+                InputManagerEvent::Gamepad(GamepadEvent { pad, state: None })
+            }
+            input => {
+                warn!("Dropping {input:?}, its capability was not negotiated");
+                continue;
+            }
+        };
+        push(&mut out, event);
+    }
+
+    out
+}
+
 #[derive(Debug)]
 pub struct ApiManagerAnnouncer {
     pub port: u16,
@@ -89,20 +921,43 @@ impl ApiManagerAnnouncer {
     }
 }
 
-/*
-async fn get_desktops(Extension(desktops): Extension<Arc<Vec<Desktop>>>) -> impl IntoResponse {
-    let raw_vec = desktops.deref().clone();
-    info!("Got request for available desktops");
-    Json(raw_vec)
-}
-*/
-
 mod helpers {
     use std::{path::Path, sync::Arc};
 
     use log::debug;
     use rustls::{Certificate, PrivateKey};
 
+    use crate::protocol::PairingKey;
+
+    /// Reads the pairing key from `root`, generating it if there is none yet
+    pub async fn read_pairing_key(root: &Path) -> Result<PairingKey, Box<dyn std::error::Error>> {
+        let path = root.join("pairing-key");
+        match tokio::fs::read_to_string(&path).await {
+            Ok(key) => Ok(key.parse()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("Generating Pairing Key");
+                let key = PairingKey::generate();
+                write_private(&path, key.to_string().as_bytes()).await?;
+                Ok(key)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes a file only the user running loded may read
+    async fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .await?;
+        file.write_all(contents).await?;
+        file.flush().await
+    }
+
     pub async fn read_certs(
         root: Arc<Path>,
     ) -> Result<(PrivateKey, Certificate), Box<dyn std::error::Error>> {
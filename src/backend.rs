@@ -0,0 +1,767 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use evdev::{
+    uinput::{VirtualDevice, VirtualDeviceBuilder},
+    AbsInfo, AbsoluteAxisType, AttributeSet, BusType, EventType, InputEvent, InputId, Key,
+    RelativeAxisType, UinputAbsSetup,
+};
+
+use log::{debug, warn};
+
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    watch,
+};
+use zvariant::{ObjectPath, OwnedObjectPath};
+
+use crate::{
+    capture::Desktop,
+    input::{
+        DesktopLayout, InputManagerError, PenEvent, TouchSlots, GAMEPAD_KEYS, JS_KEY_CODES,
+        MAX_TOUCH_CONTACTS, MOUSE_BUTTONS,
+    },
+    remote_desktop::{DeviceType, PointerAxis, RemoteDesktopProxy},
+    Result, DESTINATION, PATH,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum InputBackendError {
+    #[error("Unknown input backend {0:?}, expected uinput or portal")]
+    Unknown(String),
+}
+
+/// The virtual devices input is sent through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputDevice {
+    Keyboard,
+    /// Relative motion, the wheels and the mouse buttons
+    Mouse,
+    /// Absolute motion over the whole [DesktopLayout]
+    Tablet,
+    Touchscreen,
+    Pen,
+    /// A client's gamepad, which only exists between its first event and its removal
+    Gamepad {
+        client: u64,
+        pad: u32,
+    },
+}
+
+/// Injects evdev events into the host
+///
+/// Each call of [InputBackend::emit] is a frame of its own, SYN_REPORTs within the events split
+/// it into several.
+pub trait InputBackend: Send {
+    /// Whether events of `device` can be injected at all, the others are dropped
+    fn supports(&self, _device: InputDevice) -> bool {
+        true
+    }
+
+    fn emit(&mut self, device: InputDevice, events: &[InputEvent]) -> std::io::Result<()>;
+
+    /// Unplugs a gamepad, which releases everything held on it
+    fn remove(&mut self, device: InputDevice);
+
+    /// Makes the absolute devices span `desktops`, for when the session was reopened
+    ///
+    /// Whatever is held on those devices is released.
+    fn relayout(&mut self, _desktops: &[Desktop]) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The frames of `events`, which are separated by SYN_REPORTs
+fn frames(events: &[InputEvent]) -> impl Iterator<Item = &[InputEvent]> {
+    events
+        .split(|evt| evt.event_type() == EventType::SYNCHRONIZATION)
+        .filter(|frame| !frame.is_empty())
+}
+
+/// Which backend to inject input with, chosen at startup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputBackendKind {
+    /// Virtual devices in `/dev/uinput`, which needs write access to it
+    #[default]
+    Uinput,
+    /// The RemoteDesktop portal, which has no pen or gamepads
+    Portal,
+}
+
+impl FromStr for InputBackendKind {
+    type Err = InputBackendError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "uinput" => Ok(Self::Uinput),
+            "portal" => Ok(Self::Portal),
+            _ => Err(InputBackendError::Unknown(s.to_owned())),
+        }
+    }
+}
+
+/// Virtual devices created through uinput
+pub struct UinputBackend {
+    keyboard: VirtualDevice,
+    mouse: VirtualDevice,
+    tablet: VirtualDevice,
+    touchscreen: VirtualDevice,
+    pen: VirtualDevice,
+    /// Created when a client first uses a gamepad, by client and gamepad index
+    gamepads: HashMap<(u64, u32), VirtualDevice>,
+}
+
+impl UinputBackend {
+    pub fn new(layout: &DesktopLayout) -> std::io::Result<Self> {
+        let mut keys = AttributeSet::<Key>::new();
+        for (_, key) in JS_KEY_CODES {
+            keys.insert(*key);
+        }
+
+        debug!("Made keys: {:#?}", keys);
+
+        let keyboard = VirtualDeviceBuilder::new()?
+            .name("rdesktopd Virtal Keyboard")
+            .with_keys(&keys)?
+            .build()?;
+
+        debug!("Made keyboard");
+
+        let mut axis = AttributeSet::<RelativeAxisType>::new();
+        axis.insert(RelativeAxisType::REL_X);
+        axis.insert(RelativeAxisType::REL_Y);
+        axis.insert(RelativeAxisType::REL_WHEEL);
+        axis.insert(RelativeAxisType::REL_HWHEEL);
+        axis.insert(RelativeAxisType::REL_WHEEL_HI_RES);
+        axis.insert(RelativeAxisType::REL_HWHEEL_HI_RES);
+
+        debug!("Made axis: {:#?}", axis);
+
+        let mut buttons = AttributeSet::<Key>::new();
+        for button in MOUSE_BUTTONS {
+            buttons.insert(button);
+        }
+
+        debug!("Made buttons: {:#?}", buttons);
+
+        let mouse = VirtualDeviceBuilder::new()?
+            .name("rdesktopd Virtual Mouse")
+            .with_relative_axes(&axis)?
+            .with_keys(&buttons)?
+            .build()?;
+
+        debug!("Made mouse");
+
+        let (tablet, touchscreen, pen) = Self::make_absolute(layout)?;
+
+        Ok(Self {
+            keyboard,
+            mouse,
+            tablet,
+            touchscreen,
+            pen,
+            gamepads: HashMap::new(),
+        })
+    }
+
+    /// The tablet, touchscreen and pen, which span the whole layout
+    fn make_absolute(
+        layout: &DesktopLayout,
+    ) -> std::io::Result<(VirtualDevice, VirtualDevice, VirtualDevice)> {
+        let mut buttons = AttributeSet::<Key>::new();
+        buttons.insert(Key::BTN_LEFT);
+        buttons.insert(Key::BTN_RIGHT);
+        buttons.insert(Key::BTN_MIDDLE);
+
+        // The buttons are never pressed, but without them the device isn't seen as a pointer
+        let (width, height) = layout.size();
+        let tablet = VirtualDeviceBuilder::new()?
+            .name("rdesktopd Virtual Tablet")
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_X,
+                AbsInfo::new(0, 0, (width - 1).max(1), 0, 0, 0),
+            ))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_Y,
+                AbsInfo::new(0, 0, (height - 1).max(1), 0, 0, 0),
+            ))?
+            .with_keys(&buttons)?
+            .build()?;
+
+        debug!("Made tablet spanning {width}x{height}");
+
+        // BTN_TOUCH on absolute axes without any tool or mouse buttons is what udev and
+        // libinput take to be a touchscreen
+        let mut touch = AttributeSet::<Key>::new();
+        touch.insert(Key::BTN_TOUCH);
+
+        let touchscreen = VirtualDeviceBuilder::new()?
+            .name("rdesktopd Virtual Touchscreen")
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_X,
+                AbsInfo::new(0, 0, (width - 1).max(1), 0, 0, 0),
+            ))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_Y,
+                AbsInfo::new(0, 0, (height - 1).max(1), 0, 0, 0),
+            ))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_MT_SLOT,
+                AbsInfo::new(0, 0, MAX_TOUCH_CONTACTS as i32 - 1, 0, 0, 0),
+            ))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_MT_TRACKING_ID,
+                AbsInfo::new(0, 0, TouchSlots::MAX_TRACKING_ID, 0, 0, 0),
+            ))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_MT_POSITION_X,
+                AbsInfo::new(0, 0, (width - 1).max(1), 0, 0, 0),
+            ))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_MT_POSITION_Y,
+                AbsInfo::new(0, 0, (height - 1).max(1), 0, 0, 0),
+            ))?
+            .with_keys(&touch)?
+            .build()?;
+
+        debug!("Made touchscreen with {MAX_TOUCH_CONTACTS} slots");
+
+        let mut pen_keys = AttributeSet::<Key>::new();
+        pen_keys.insert(Key::BTN_TOOL_PEN);
+        pen_keys.insert(Key::BTN_TOOL_RUBBER);
+        pen_keys.insert(Key::BTN_TOUCH);
+        pen_keys.insert(Key::BTN_STYLUS);
+        pen_keys.insert(Key::BTN_STYLUS2);
+
+        // Tilt resolution is in units per radian, which for degrees is 180 / pi
+        let pen = VirtualDeviceBuilder::new()?
+            .name("rdesktopd Virtual Pen")
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_X,
+                AbsInfo::new(0, 0, (width - 1).max(1), 0, 0, 0),
+            ))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_Y,
+                AbsInfo::new(0, 0, (height - 1).max(1), 0, 0, 0),
+            ))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_PRESSURE,
+                AbsInfo::new(0, 0, u16::MAX.into(), 0, 0, 0),
+            ))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_TILT_X,
+                AbsInfo::new(
+                    0,
+                    -PenEvent::MAX_TILT as i32,
+                    PenEvent::MAX_TILT as i32,
+                    0,
+                    0,
+                    57,
+                ),
+            ))?
+            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsoluteAxisType::ABS_TILT_Y,
+                AbsInfo::new(
+                    0,
+                    -PenEvent::MAX_TILT as i32,
+                    PenEvent::MAX_TILT as i32,
+                    0,
+                    0,
+                    57,
+                ),
+            ))?
+            .with_keys(&pen_keys)?
+            .build()?;
+
+        debug!("Made pen");
+
+        Ok((tablet, touchscreen, pen))
+    }
+
+    fn make_gamepad(client: u64, pad: u32) -> std::io::Result<VirtualDevice> {
+        let mut keys = AttributeSet::<Key>::new();
+        for key in GAMEPAD_KEYS {
+            keys.insert(key);
+        }
+
+        let stick = AbsInfo::new(0, i16::MIN.into(), i16::MAX.into(), 16, 128, 0);
+        let trigger = AbsInfo::new(0, 0, u8::MAX.into(), 0, 0, 0);
+        let hat = AbsInfo::new(0, -1, 1, 0, 0, 0);
+
+        let name = format!("rdesktopd Virtual Gamepad {client}-{pad}");
+        let mut builder = VirtualDeviceBuilder::new()?
+            .name(&name)
+            // The Xbox 360 controller's ids, which every game knows the layout of
+            .input_id(InputId::new(BusType::BUS_USB, 0x045e, 0x028e, 0x110))
+            .with_keys(&keys)?;
+        for (axis, info) in [
+            (AbsoluteAxisType::ABS_X, stick),
+            (AbsoluteAxisType::ABS_Y, stick),
+            (AbsoluteAxisType::ABS_RX, stick),
+            (AbsoluteAxisType::ABS_RY, stick),
+            (AbsoluteAxisType::ABS_Z, trigger),
+            (AbsoluteAxisType::ABS_RZ, trigger),
+            (AbsoluteAxisType::ABS_HAT0X, hat),
+            (AbsoluteAxisType::ABS_HAT0Y, hat),
+        ] {
+            builder = builder.with_absolute_axis(&UinputAbsSetup::new(axis, info))?;
+        }
+        builder.build()
+    }
+}
+
+impl InputBackend for UinputBackend {
+    fn emit(&mut self, device: InputDevice, events: &[InputEvent]) -> std::io::Result<()> {
+        let device = match device {
+            InputDevice::Keyboard => &mut self.keyboard,
+            InputDevice::Mouse => &mut self.mouse,
+            InputDevice::Tablet => &mut self.tablet,
+            InputDevice::Touchscreen => &mut self.touchscreen,
+            InputDevice::Pen => &mut self.pen,
+            InputDevice::Gamepad { client, pad } => match self.gamepads.entry((client, pad)) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    debug!("Made gamepad {pad} of client {client}");
+                    entry.insert(Self::make_gamepad(client, pad)?)
+                }
+            },
+        };
+        device.emit(events)
+    }
+
+    fn remove(&mut self, device: InputDevice) {
+        if let InputDevice::Gamepad { client, pad } = device {
+            if self.gamepads.remove(&(client, pad)).is_some() {
+                debug!("Removed gamepad {pad} of client {client}");
+            }
+        }
+    }
+
+    fn relayout(&mut self, desktops: &[Desktop]) -> std::io::Result<()> {
+        // The old devices are destroyed once replaced, which lets go of what they held
+        (self.tablet, self.touchscreen, self.pen) =
+            Self::make_absolute(&DesktopLayout::new(desktops))?;
+        Ok(())
+    }
+}
+
+/// A frame of events sent to a device
+type Frame = (InputDevice, Vec<InputEvent>);
+
+/// Keeps every frame in memory instead of injecting it, clones share the frames
+#[derive(Debug, Clone, Default)]
+pub struct RecordingBackend {
+    frames: Arc<Mutex<Vec<Frame>>>,
+    removed: Arc<Mutex<Vec<InputDevice>>>,
+    layouts: Arc<Mutex<Vec<DesktopLayout>>>,
+}
+
+impl RecordingBackend {
+    /// Every frame emitted so far, without the SYN_REPORTs
+    pub fn frames(&self) -> Vec<Frame> {
+        self.frames.lock().map(|f| f.clone()).unwrap_or_default()
+    }
+
+    /// Every device removed so far
+    pub fn removed(&self) -> Vec<InputDevice> {
+        self.removed.lock().map(|r| r.clone()).unwrap_or_default()
+    }
+
+    /// Every layout the absolute devices were made to span since they were created
+    pub fn layouts(&self) -> Vec<DesktopLayout> {
+        self.layouts.lock().map(|l| l.clone()).unwrap_or_default()
+    }
+}
+
+impl InputBackend for RecordingBackend {
+    fn emit(&mut self, device: InputDevice, events: &[InputEvent]) -> std::io::Result<()> {
+        let mut recorded = self
+            .frames
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        recorded.extend(frames(events).map(|frame| (device, frame.to_vec())));
+        Ok(())
+    }
+
+    fn remove(&mut self, device: InputDevice) {
+        if let Ok(mut removed) = self.removed.lock() {
+            removed.push(device);
+        }
+    }
+
+    fn relayout(&mut self, desktops: &[Desktop]) -> std::io::Result<()> {
+        self.layouts
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+            .push(DesktopLayout::new(desktops));
+        Ok(())
+    }
+}
+
+/// A call on the RemoteDesktop portal, with positions in the coordinates of a screencast stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PortalCall {
+    KeyboardKeycode {
+        keycode: i32,
+        pressed: bool,
+    },
+    PointerMotion {
+        dx: f64,
+        dy: f64,
+    },
+    PointerMotionAbsolute {
+        stream: u32,
+        x: f64,
+        y: f64,
+    },
+    PointerButton {
+        button: i32,
+        pressed: bool,
+    },
+    PointerAxisDiscrete {
+        axis: PointerAxis,
+        steps: i32,
+    },
+    TouchDown {
+        stream: u32,
+        slot: u32,
+        x: f64,
+        y: f64,
+    },
+    TouchMotion {
+        stream: u32,
+        slot: u32,
+        x: f64,
+        y: f64,
+    },
+    TouchUp {
+        slot: u32,
+    },
+}
+
+impl PortalCall {
+    async fn send(self, proxy: &RemoteDesktopProxy<'_>, session: &ObjectPath<'_>) -> Result<()> {
+        let options = HashMap::new;
+        match self {
+            Self::KeyboardKeycode { keycode, pressed } => {
+                proxy
+                    .notify_keyboard_keycode(session, options(), keycode, pressed.into())
+                    .await?
+            }
+            Self::PointerMotion { dx, dy } => {
+                proxy
+                    .notify_pointer_motion(session, options(), dx, dy)
+                    .await?
+            }
+            Self::PointerMotionAbsolute { stream, x, y } => {
+                proxy
+                    .notify_pointer_motion_absolute(session, options(), stream, x, y)
+                    .await?
+            }
+            Self::PointerButton { button, pressed } => {
+                proxy
+                    .notify_pointer_button(session, options(), button, pressed.into())
+                    .await?
+            }
+            Self::PointerAxisDiscrete { axis, steps } => {
+                proxy
+                    .notify_pointer_axis_discrete(session, options(), axis, steps)
+                    .await?
+            }
+            Self::TouchDown { stream, slot, x, y } => {
+                proxy
+                    .notify_touch_down(session, options(), stream, slot, x, y)
+                    .await?
+            }
+            Self::TouchMotion { stream, slot, x, y } => {
+                proxy
+                    .notify_touch_motion(session, options(), stream, slot, x, y)
+                    .await?
+            }
+            Self::TouchUp { slot } => proxy.notify_touch_up(session, options(), slot).await?,
+        }
+        Ok(())
+    }
+}
+
+/// A finger on the touchscreen as the portal last heard of it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct PortalContact {
+    down: bool,
+    x: i32,
+    y: i32,
+}
+
+/// What happened to a touchscreen slot within a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TouchChange {
+    Down,
+    Motion,
+    Up,
+}
+
+/// Translates evdev frames into calls on the RemoteDesktop portal
+///
+/// The portal has neither pens nor gamepads, and only the devices the user allowed the session
+/// to control can be used. Scrolling is sent as whole wheel notches, the
+/// hi-res axes are ignored. The calls are made by [forward_to_portal] in the order they were
+/// emitted in.
+pub struct PortalBackend {
+    layout: DesktopLayout,
+    /// The pipewire node of each desktop, which the portal identifies streams by
+    nodes: HashMap<u64, u32>,
+    devices: DeviceType,
+    pointer: (i32, i32),
+    touch_slot: usize,
+    contacts: [PortalContact; MAX_TOUCH_CONTACTS],
+    calls: UnboundedSender<PortalCall>,
+}
+
+impl PortalBackend {
+    pub fn new(desktops: &[Desktop], devices: DeviceType) -> (Self, UnboundedReceiver<PortalCall>) {
+        Self::from_streams(
+            DesktopLayout::new(desktops),
+            desktops.iter().map(|d| (d.loded_id, d.pipewire_path)),
+            devices,
+        )
+    }
+
+    /// Builds a backend from the layout and each desktop's pipewire node
+    pub fn from_streams(
+        layout: DesktopLayout,
+        nodes: impl IntoIterator<Item = (u64, u32)>,
+        devices: DeviceType,
+    ) -> (Self, UnboundedReceiver<PortalCall>) {
+        let (calls, rx) = unbounded_channel();
+        (
+            Self {
+                layout,
+                nodes: nodes.into_iter().collect(),
+                devices,
+                pointer: (0, 0),
+                touch_slot: 0,
+                contacts: Default::default(),
+                calls,
+            },
+            rx,
+        )
+    }
+
+    fn call(&self, call: PortalCall) -> std::io::Result<()> {
+        self.calls
+            .send(call)
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+
+    /// The stream showing `(x, y)` of the layout, and the position on it
+    fn on_stream(&self, x: i32, y: i32) -> Option<(u32, f64, f64)> {
+        let (loded_id, x, y) = self.layout.to_desktop(x, y)?;
+        let stream = *self.nodes.get(&loded_id)?;
+        Some((stream, x as f64, y as f64))
+    }
+
+    fn keyboard_frame(&mut self, frame: &[InputEvent]) -> std::io::Result<()> {
+        for evt in frame {
+            // The compositor repeats held keys itself
+            if evt.event_type() == EventType::KEY && evt.value() != 2 {
+                self.call(PortalCall::KeyboardKeycode {
+                    keycode: evt.code().into(),
+                    pressed: evt.value() != 0,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn mouse_frame(&mut self, frame: &[InputEvent]) -> std::io::Result<()> {
+        let mut motion = (0, 0);
+        for evt in frame {
+            match evt.event_type() {
+                EventType::RELATIVE => match RelativeAxisType(evt.code()) {
+                    RelativeAxisType::REL_X => motion.0 += evt.value(),
+                    RelativeAxisType::REL_Y => motion.1 += evt.value(),
+                    // Wheel notches are positive upwards, the portal's steps downwards
+                    RelativeAxisType::REL_WHEEL => self.call(PortalCall::PointerAxisDiscrete {
+                        axis: PointerAxis::Vertical,
+                        steps: -evt.value(),
+                    })?,
+                    RelativeAxisType::REL_HWHEEL => self.call(PortalCall::PointerAxisDiscrete {
+                        axis: PointerAxis::Horizontal,
+                        steps: evt.value(),
+                    })?,
+                    _ => {}
+                },
+                EventType::KEY => {
+                    // Motion that came before a click has to land before it
+                    if motion != (0, 0) {
+                        self.call(PortalCall::PointerMotion {
+                            dx: motion.0 as f64,
+                            dy: motion.1 as f64,
+                        })?;
+                        motion = (0, 0);
+                    }
+                    self.call(PortalCall::PointerButton {
+                        button: evt.code().into(),
+                        pressed: evt.value() != 0,
+                    })?;
+                }
+                _ => {}
+            }
+        }
+        if motion != (0, 0) {
+            self.call(PortalCall::PointerMotion {
+                dx: motion.0 as f64,
+                dy: motion.1 as f64,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn tablet_frame(&mut self, frame: &[InputEvent]) -> std::io::Result<()> {
+        let mut moved = false;
+        for evt in frame {
+            if evt.event_type() == EventType::ABSOLUTE {
+                match AbsoluteAxisType(evt.code()) {
+                    AbsoluteAxisType::ABS_X => self.pointer.0 = evt.value(),
+                    AbsoluteAxisType::ABS_Y => self.pointer.1 = evt.value(),
+                    _ => continue,
+                }
+                moved = true;
+            }
+        }
+        if !moved {
+            return Ok(());
+        }
+        match self.on_stream(self.pointer.0, self.pointer.1) {
+            Some((stream, x, y)) => self.call(PortalCall::PointerMotionAbsolute { stream, x, y }),
+            None => Ok(()),
+        }
+    }
+
+    fn touch_frame(&mut self, frame: &[InputEvent]) -> std::io::Result<()> {
+        let mut changes = [None; MAX_TOUCH_CONTACTS];
+        for evt in frame {
+            if evt.event_type() != EventType::ABSOLUTE {
+                continue;
+            }
+            if AbsoluteAxisType(evt.code()) == AbsoluteAxisType::ABS_MT_SLOT {
+                self.touch_slot = evt.value().clamp(0, MAX_TOUCH_CONTACTS as i32 - 1) as usize;
+                continue;
+            }
+
+            let slot = self.touch_slot;
+            let contact = &mut self.contacts[slot];
+            match AbsoluteAxisType(evt.code()) {
+                AbsoluteAxisType::ABS_MT_TRACKING_ID if evt.value() < 0 => {
+                    changes[slot] = Some(TouchChange::Up)
+                }
+                AbsoluteAxisType::ABS_MT_TRACKING_ID => changes[slot] = Some(TouchChange::Down),
+                AbsoluteAxisType::ABS_MT_POSITION_X => contact.x = evt.value(),
+                AbsoluteAxisType::ABS_MT_POSITION_Y => contact.y = evt.value(),
+                _ => continue,
+            }
+            changes[slot].get_or_insert(TouchChange::Motion);
+        }
+
+        for (slot, change) in changes.into_iter().enumerate() {
+            let Some(change) = change else {
+                continue;
+            };
+            let contact = self.contacts[slot];
+            let call = match change {
+                TouchChange::Up if contact.down => PortalCall::TouchUp { slot: slot as u32 },
+                TouchChange::Up => continue,
+                TouchChange::Down | TouchChange::Motion => {
+                    let Some((stream, x, y)) = self.on_stream(contact.x, contact.y) else {
+                        continue;
+                    };
+                    if contact.down {
+                        PortalCall::TouchMotion {
+                            stream,
+                            slot: slot as u32,
+                            x,
+                            y,
+                        }
+                    } else if change == TouchChange::Down {
+                        PortalCall::TouchDown {
+                            stream,
+                            slot: slot as u32,
+                            x,
+                            y,
+                        }
+                    } else {
+                        continue;
+                    }
+                }
+            };
+            self.contacts[slot].down = !matches!(call, PortalCall::TouchUp { .. });
+            self.call(call)?;
+        }
+        Ok(())
+    }
+}
+
+impl InputBackend for PortalBackend {
+    fn supports(&self, device: InputDevice) -> bool {
+        let needed = match device {
+            InputDevice::Keyboard => DeviceType::KEYBOARD,
+            InputDevice::Mouse | InputDevice::Tablet => DeviceType::POINTER,
+            InputDevice::Touchscreen => DeviceType::TOUCHSCREEN,
+            InputDevice::Pen | InputDevice::Gamepad { .. } => return false,
+        };
+        self.devices.contains(needed)
+    }
+
+    fn emit(&mut self, device: InputDevice, events: &[InputEvent]) -> std::io::Result<()> {
+        for frame in frames(events) {
+            match device {
+                InputDevice::Keyboard => self.keyboard_frame(frame)?,
+                InputDevice::Mouse => self.mouse_frame(frame)?,
+                InputDevice::Tablet => self.tablet_frame(frame)?,
+                InputDevice::Touchscreen => self.touch_frame(frame)?,
+                InputDevice::Pen | InputDevice::Gamepad { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, _device: InputDevice) {}
+
+    fn relayout(&mut self, desktops: &[Desktop]) -> std::io::Result<()> {
+        // Fingers on the closed session's streams are gone along with them
+        self.layout = DesktopLayout::new(desktops);
+        self.nodes = desktops
+            .iter()
+            .map(|d| (d.loded_id, d.pipewire_path))
+            .collect();
+        self.pointer = (0, 0);
+        self.touch_slot = 0;
+        self.contacts = Default::default();
+        Ok(())
+    }
+}
+
+/// Makes the calls of a [PortalBackend] on the remote desktop session `session` holds at the
+/// time, until the backend is dropped
+pub async fn forward_to_portal(
+    connection: zbus::Connection,
+    session: watch::Receiver<OwnedObjectPath>,
+    mut calls: UnboundedReceiver<PortalCall>,
+) -> Result<()> {
+    let proxy = RemoteDesktopProxy::builder(&connection)
+        .path(PATH)?
+        .destination(DESTINATION)?
+        .build()
+        .await?;
+
+    while let Some(call) = calls.recv().await {
+        let session = session.borrow().clone();
+        if let Err(e) = call.send(&proxy, &session).await {
+            warn!("The RemoteDesktop portal rejected {call:?}: {e}");
+        }
+    }
+    Ok(())
+}
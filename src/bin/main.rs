@@ -1,10 +1,14 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use log::{debug, error, info, warn};
 
-use loded::{ApiManager, CaptureManager, InputManager};
+use loded::{
+    forward_to_portal, protocol::LodestarCloseReason, ApiManager, ApiSettings, CaptureManager,
+    ClipboardManager, DesktopLayout, DeviceType, InputBackend, InputBackendKind, InputManager,
+    KeymapNames, PortalBackend, SessionState, TextTyper, UinputBackend,
+};
 
-use tokio::sync::broadcast::channel;
+use tokio::sync::{broadcast::channel, watch};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -12,44 +16,186 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let (ds_tx, mut ds_rx) = channel(1);
 
-    let mut cap_manager = CaptureManager::new().await?;
+    // Injecting through the portal leaves input under the compositor's control, uinput needs
+    // write access to /dev/uinput instead
+    let backend_kind = match std::env::var("LODED_INPUT_BACKEND") {
+        Ok(v) => v.parse()?,
+        Err(_) => InputBackendKind::default(),
+    };
+    info!("Injecting input with the {backend_kind:?} backend");
 
-    let (input_manager, ime_tx) = InputManager::new(ds_tx.subscribe())?;
+    // Listening beyond this machine always requires clients to be paired
+    let mut api_settings = ApiSettings::default();
+    if let Ok(v) = std::env::var("LODED_ADDRESS") {
+        api_settings.address = v.parse()?;
+    }
+    api_settings.require_pairing = std::env::var_os("LODED_REQUIRE_PAIRING").is_some();
 
-    let mut api_manager = ApiManager::new(ds_tx.subscribe(), ime_tx).await?;
+    let reopen = std::env::var_os("LODED_REOPEN_SESSION").is_some();
 
-    let desktops = cap_manager.begin_capture(&ds_tx).await?;
+    let mut cap_manager = CaptureManager::new().await?;
+
+    // One remote desktop session covers both the screencast and the input
+    let desktops = match backend_kind {
+        InputBackendKind::Uinput => cap_manager.begin_capture(&ds_tx).await?,
+        InputBackendKind::Portal => {
+            let devices = DeviceType::KEYBOARD | DeviceType::POINTER | DeviceType::TOUCHSCREEN;
+            cap_manager.begin_remote_desktop(&ds_tx, devices).await?
+        }
+    };
 
     debug!("Desktops: {:#?}", desktops);
 
+    // localed only knows the machine's layout, the session may have one of its own
+    let keymap = match KeymapNames::from_env() {
+        Some(names) => names,
+        None => KeymapNames::from_host().await,
+    };
+    info!("Typing text on keyboard layout {:?}", keymap.layout);
+    let typer = TextTyper::new(&keymap)?;
+
+    let mut portal_task = None;
+    // Calls are made on whichever session is open, which changes when it's reopened
+    let mut portal_session = None;
+    let backend: Box<dyn InputBackend> = match backend_kind {
+        InputBackendKind::Uinput => Box::new(UinputBackend::new(&DesktopLayout::new(&desktops))?),
+        InputBackendKind::Portal => {
+            let devices = cap_manager
+                .devices()
+                .expect("Remote desktop sessions have devices");
+            let (backend, calls) = PortalBackend::new(&desktops, devices);
+            let connection = cap_manager.connection().clone();
+            let session = cap_manager
+                .session_handle()
+                .expect("The portal session exists once capture began");
+            let (session_tx, session) = watch::channel(session);
+            portal_session = Some(session_tx);
+            portal_task = Some(tokio::spawn(async move {
+                if let Err(e) = forward_to_portal(connection, session, calls).await {
+                    error!("Failed to forward input to the RemoteDesktop portal: {e}");
+                }
+            }));
+            Box::new(backend)
+        }
+    };
+
+    let (input_manager, ime_tx) = InputManager::new(ds_tx.subscribe(), backend, typer);
+    let input_manager = Arc::new(input_manager);
+
+    // Only remote desktop sessions can be given the clipboard
+    let clipboard = match cap_manager.session_handle() {
+        Some(session) if cap_manager.clipboard_enabled() => {
+            let clipboard =
+                Arc::new(ClipboardManager::new(cap_manager.connection(), session).await?);
+            let listener = clipboard.clone();
+            let die_handle = ds_tx.subscribe();
+            tokio::spawn(async move {
+                if let Err(e) = listener.listen(die_handle).await {
+                    error!("Stopped following the host's clipboard: {e}");
+                }
+            });
+            Some(clipboard)
+        }
+        _ => {
+            info!("The clipboard isn't shared with clients");
+            None
+        }
+    };
+
+    let mut api_manager =
+        ApiManager::new(ds_tx.subscribe(), ime_tx, clipboard.clone(), api_settings).await?;
+    let (session_tx, session_rx) = watch::channel(SessionState::opened(desktops));
+
     tokio::spawn(async move {
-        match api_manager.run(desktops.clone()).await {
+        match api_manager.run(session_rx).await {
             Ok(_) => info!("ApiManager exited successfully"),
             Err(e) => warn!("ApiManager failed to exit successfully: {e}"),
         };
     });
 
+    let exit_tx = ds_tx.clone();
     tokio::spawn(async move {
         tokio::signal::ctrl_c().await.unwrap();
-        ds_tx.send(()).unwrap();
+        exit_tx.send(()).unwrap();
         debug!("5 second exit timeout started");
         tokio::time::sleep(Duration::from_secs(5)).await;
         warn!("Exiting forcefully (Timeout met)");
         std::process::exit(-1);
     });
 
-    tokio::spawn(async move {
-        match input_manager.listen().await {
+    let listener = input_manager.clone();
+    let input_task = tokio::spawn(async move {
+        match listener.listen().await {
             Ok(_) => info!("InputManager terminated successfully"),
             Err(e) => error!("InputManager did not exit successfully: {e}"),
         }
     });
 
     loop {
-        if (ds_rx.recv().await).is_ok() {
-            break;
-        } else {
-            panic!("Failed to receive death signal");
+        tokio::select! {
+            res = ds_rx.recv() => {
+                if res.is_err() {
+                    panic!("Failed to receive death signal");
+                }
+                break;
+            }
+            _ = cap_manager.closed() => {
+                warn!("Sharing was revoked, stopping the desktops' pipelines");
+                cap_manager.end_session();
+                session_tx.send_replace(SessionState::Closed {
+                    reason: LodestarCloseReason::Revoked,
+                    reopening: reopen,
+                });
+                if !reopen {
+                    warn!("Nothing is shared until loded is restarted");
+                    continue;
+                }
+
+                match cap_manager.reopen(&ds_tx).await {
+                    Ok(desktops) => {
+                        info!("Reopened the session");
+                        debug!("Desktops: {:#?}", desktops);
+                        // The desktops may have changed size or position
+                        if let Err(e) = input_manager.relayout(&desktops) {
+                            error!("Failed to fit the absolute devices to the desktops: {e}");
+                        }
+                        if let (Some(portal_session), Some(session)) =
+                            (&portal_session, cap_manager.session_handle())
+                        {
+                            portal_session.send_replace(session);
+                        }
+                        match (&clipboard, cap_manager.session_handle()) {
+                            (Some(clipboard), Some(session)) if cap_manager.clipboard_enabled() => {
+                                if let Err(e) = clipboard.rebind(session).await {
+                                    error!("Failed to share the reopened session's clipboard: {e}");
+                                }
+                            }
+                            (Some(_), _) => warn!("The reopened session has no clipboard"),
+                            _ => {}
+                        }
+                        session_tx.send_replace(SessionState::opened(desktops));
+                    }
+                    Err(e) => {
+                        error!("Failed to reopen the session: {e}");
+                        session_tx.send_replace(SessionState::Closed {
+                            reason: LodestarCloseReason::ReopenFailed,
+                            reopening: false,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    // The listener's clone has to be the last one, or the backend outlives it
+    drop(input_manager);
+    // Wait for the InputManager to let go of everything clients still held
+    if let Err(e) = input_task.await {
+        error!("InputManager panicked: {e}");
+    }
+    // The forwarder runs out once the InputManager, and with it the backend, is gone
+    if let Some(portal_task) = portal_task {
+        if let Err(e) = portal_task.await {
+            error!("Portal input forwarder panicked: {e}");
         }
     }
     info!("Exiting");
@@ -1,21 +1,33 @@
-use std::{collections::HashMap, net::TcpListener, process::Command};
-// use std::process::Stdio;
+use std::{collections::HashMap, path::PathBuf};
 
+use bytes::Bytes;
+use futures::StreamExt;
+use gstreamer as gst;
+use gstreamer::prelude::*;
 use log::{debug, error, info, warn};
 use serde::Serialize;
 use tokio::{
     io::AsyncWriteExt,
-    sync::broadcast::{Receiver, Sender},
+    sync::{
+        broadcast::{self, Receiver, Sender},
+        watch,
+    },
 };
-use zvariant::{ObjectPath, OwnedValue};
+use zvariant::{ObjectPath, OwnedObjectPath, OwnedValue};
 
 use crate::{
+    adaptation::EncoderSettings,
     call_and_receive_response,
+    clipboard::ClipboardManager,
+    protocol::{LodestarCapabilities, LodestarVideoFramePacket},
+    remote_desktop::{
+        DeviceType, RemoteDesktopProxy, SelectDevicesOptions, StartRemoteDesktopResponse,
+    },
     screencast::{
         CreateSessionOptions, CreateSessionResponse, CursorMode, PersistMode, ScreencastProxy,
         SelectSourcesOptions, SourceType, StartCastOptions, StartCastResponse,
     },
-    session_request::{RequestProxy, SessionProxy},
+    session_request::{ClosedStream, PortalError, RequestProxy, SessionProxy},
     unique_token::UniqueToken,
     Result, DESTINATION, PATH,
 };
@@ -28,6 +40,9 @@ pub enum Error {
     FailedTokenOperation,
 }
 
+/// How many encoded frames a slow viewer may fall behind before it starts skipping frames
+const FRAME_BUFFER: usize = 8;
+
 /// Struct representing a desktop in an easier way
 #[derive(Serialize, Debug, Clone)]
 pub struct Desktop {
@@ -41,47 +56,189 @@ pub struct Desktop {
     pub width: i32,
     /// The desktop's height
     pub height: i32,
-    /// The port the desktop is being streamed to
-    pub port: Option<u16>,
+    /// The desktop's position in the compositor's layout in the format `(x, y)`
+    pub position: Option<(i32, i32)>,
+    /// The running pipeline of the desktop, subscribe to it to receive encoded frames
+    #[serde(skip)]
+    pub stream: Option<DesktopStream>,
 }
 
 pub struct CaptureManager<'a> {
     token: Option<String>,
+    /// Where the restore token is kept between runs
+    token_dir: PathBuf,
     connection: zbus::Connection,
     session: Option<Box<SessionProxy<'a>>>,
+    /// Subscribed to as soon as the session is created, so it can't close unnoticed
+    closed: Option<ClosedStream<'a>>,
+    /// Stops the pipelines of the session's desktops
+    streams_tx: Option<Sender<()>>,
+    /// Requested input devices until the session started, then the ones the user allowed
+    devices: Option<DeviceType>,
+    /// Whether the user let the remote desktop session share the clipboard
+    clipboard: bool,
 }
 
 impl<'a> CaptureManager<'a> {
     pub async fn new() -> Result<CaptureManager<'a>> {
-        Ok(Self {
+        gst::init()?;
+
+        Ok(Self::with_connection(
+            zbus::Connection::session().await?,
+            ".",
+        ))
+    }
+
+    /// Reaches the portal through `connection` and keeps the restore token in `token_dir`
+    ///
+    /// Unlike [CaptureManager::new] this doesn't initialise GStreamer, which has to be done
+    /// before capture begins.
+    pub fn with_connection(
+        connection: zbus::Connection,
+        token_dir: impl Into<PathBuf>,
+    ) -> CaptureManager<'a> {
+        Self {
             token: None,
-            connection: zbus::Connection::session().await?,
+            token_dir: token_dir.into(),
+            connection,
             session: None,
+            closed: None,
+            streams_tx: None,
+            devices: None,
+            clipboard: false,
+        }
+    }
+
+    /// Screencast and remote desktop sessions can't be restored with each other's tokens
+    fn token_path(&self) -> PathBuf {
+        self.token_dir.join(match self.devices {
+            Some(_) => "remote-desktop-token",
+            None => "token",
         })
     }
 
     async fn try_get_token(&self) -> Result<String> {
-        Ok(tokio::fs::read_to_string("./token").await?)
+        Ok(tokio::fs::read_to_string(self.token_path()).await?)
     }
 
     async fn try_write_token(&self) -> Result<()> {
         if let Some(token) = self.token.as_ref() {
-            let mut file = tokio::fs::File::create("./token").await?;
+            let mut file = tokio::fs::File::create(self.token_path()).await?;
             file.write_all(token.as_bytes()).await?;
+            // Tokio finishes writes in the background, the token has to be on disk once this returns
+            file.flush().await?;
             Ok(())
         } else {
             Err(Error::FailedTokenOperation.into())
         }
     }
 
+    /// The connection to the session bus the portal is reached through
+    pub fn connection(&self) -> &zbus::Connection {
+        &self.connection
+    }
+
+    /// The input devices the session may control, if it is a remote desktop session
+    pub fn devices(&self) -> Option<DeviceType> {
+        self.devices
+    }
+
+    /// Whether a [ClipboardManager] can be attached to the session
+    pub fn clipboard_enabled(&self) -> bool {
+        self.clipboard
+    }
+
+    /// The handle of the portal session, once capture began
+    pub fn session_handle(&self) -> Option<OwnedObjectPath> {
+        self.session
+            .as_ref()
+            .map(|session| session.path().to_owned().into())
+    }
+
     /// Returns desktops and File descriptor
     pub async fn begin_capture(&mut self, ds_tx: &Sender<()>) -> Result<Vec<Desktop>> {
+        self.begin(ds_tx, None).await
+    }
+
+    /// Like [CaptureManager::begin_capture], but in a remote desktop session that also lets
+    /// `devices` be controlled through the RemoteDesktop portal, so the user is only asked once
+    pub async fn begin_remote_desktop(
+        &mut self,
+        ds_tx: &Sender<()>,
+        devices: DeviceType,
+    ) -> Result<Vec<Desktop>> {
+        self.begin(ds_tx, Some(devices)).await
+    }
+
+    async fn begin(
+        &mut self,
+        ds_tx: &Sender<()>,
+        devices: Option<DeviceType>,
+    ) -> Result<Vec<Desktop>> {
+        let desktops = self.open_session(devices).await?;
+
+        // The pipelines stop with loded as well as with the session
+        let (streams_tx, _) = broadcast::channel(1);
+        let mut ds_rx = ds_tx.subscribe();
+        let death = streams_tx.clone();
+        tokio::spawn(async move {
+            let _ = ds_rx.recv().await;
+            let _ = death.send(());
+        });
+
+        let desktops = Self::start_streams(&desktops, &streams_tx);
+        self.streams_tx = Some(streams_tx);
+        Ok(desktops)
+    }
+
+    /// Opens a new session in place of one that was closed, with the devices the last one was
+    /// granted
+    ///
+    /// The restore token of the last session is handed back, so the user is only asked again if
+    /// the portal doesn't accept it.
+    pub async fn reopen(&mut self, ds_tx: &Sender<()>) -> Result<Vec<Desktop>> {
+        self.end_session();
+        self.begin(ds_tx, self.devices).await
+    }
+
+    /// Waits for the portal to close the session, because the user revoked it or the compositor
+    /// went away
+    ///
+    /// Never resolves while there's no session.
+    pub async fn closed(&mut self) {
+        let Some(closed) = self.closed.as_mut() else {
+            return std::future::pending().await;
+        };
+        // Losing the signal stream leaves the session just as unusable
+        closed.next().await;
+        info!("The portal closed the session");
+        self.closed = None;
+    }
+
+    /// Stops the pipelines of the session's desktops and forgets the session, which can't be
+    /// used after being closed
+    pub fn end_session(&mut self) {
+        if let Some(streams_tx) = self.streams_tx.take() {
+            if streams_tx.send(()).is_err() {
+                debug!("The session's pipelines already stopped");
+            }
+        }
+        self.session = None;
+        self.closed = None;
+        self.clipboard = false;
+    }
+
+    /// Asks the portal for a session and the desktops the user picked, which aren't captured yet
+    ///
+    /// The session is a remote desktop session if `devices` are requested.
+    pub async fn open_session(&mut self, devices: Option<DeviceType>) -> Result<Vec<Desktop>> {
         if self.session.is_some() {
             error!("CaptureManager is already running");
             return Err(Error::AlreadyStarted.into());
         }
 
         info!("Beginning Desktop Capture");
+        self.devices = devices;
 
         match self.try_get_token().await {
             Ok(v) => {
@@ -97,24 +254,49 @@ impl<'a> CaptureManager<'a> {
             .build()
             .await?;
 
+        let remote_desktop = match devices {
+            Some(_) => Some(
+                RemoteDesktopProxy::builder(&self.connection)
+                    .path(PATH)?
+                    .destination(DESTINATION)?
+                    .build()
+                    .await?,
+            ),
+            None => None,
+        };
+
         debug!("Getting session");
         let sess_opts = CreateSessionOptions::default();
         let sess_request =
-            RequestProxy::from_unique(&self.connection, &sess_opts.handle_token).await;
+            RequestProxy::from_unique(&self.connection, &sess_opts.handle_token).await?;
 
-        let csr: CreateSessionResponse = call_and_receive_response!(
-            proxy.create_session(&sess_opts),
-            sess_request,
-            CreateSessionResponse
-        )?;
+        let csr: CreateSessionResponse = match &remote_desktop {
+            Some(remote_desktop) => call_and_receive_response!(
+                remote_desktop.create_session(&sess_opts),
+                sess_request,
+                CreateSessionResponse
+            )?,
+            None => call_and_receive_response!(
+                proxy.create_session(&sess_opts),
+                sess_request,
+                CreateSessionResponse
+            )?,
+        };
 
-        let session = ObjectPath::try_from(
-            csr.session_handle
-                .expect("SessionHandle missing from successful CreateSessionResponse"),
-        )
-        .expect("Invalid SessionHandle in successful CreateSessionResponse");
+        let session = csr
+            .session_handle
+            .ok_or(PortalError::MissingField("session_handle"))?;
+        let session = ObjectPath::try_from(session)
+            .map_err(|_| PortalError::InvalidField("session_handle"))?;
 
-        let token = match &self.token {
+        let session_proxy = SessionProxy::builder(&self.connection)
+            .path(session.clone())?
+            .destination(DESTINATION)?
+            .build()
+            .await?;
+        let closed = session_proxy.receive_closed().await?;
+
+        let mut token = match &self.token {
             Some(v) => {
                 info!("Refresh token present, using token");
                 Some(v.clone())
@@ -125,44 +307,96 @@ impl<'a> CaptureManager<'a> {
             }
         };
 
+        // Remote desktop sessions persist their permissions with the devices, not the sources
+        if let (Some(remote_desktop), Some(devices)) = (&remote_desktop, devices) {
+            debug!("Requesting input devices");
+            let dev_request_token = UniqueToken::new();
+            let dev_request =
+                RequestProxy::from_unique(&self.connection, &dev_request_token).await?;
+            let dev_opts = SelectDevicesOptions {
+                handle_token: dev_request_token,
+                types: Some(devices),
+                restore_token: token.take(),
+                persist_mode: Some(PersistMode::ExplicitlyRevoked),
+            };
+
+            let _sdr = call_and_receive_response!(remote_desktop.select_devices(&session, &dev_opts), dev_request, HashMap<String, OwnedValue>)?;
+
+            // Without a clipboard the session is still useful, clients just can't copy and paste
+            if let Err(e) = ClipboardManager::request(&self.connection, &session).await {
+                warn!("Failed to request the clipboard: {e}");
+            }
+        }
+
         debug!("Requesting capture sources");
         let src_request_token = UniqueToken::new();
-        let src_request = RequestProxy::from_unique(&self.connection, &src_request_token).await;
+        let src_request = RequestProxy::from_unique(&self.connection, &src_request_token).await?;
         let src_opts = SelectSourcesOptions {
             handle_token: src_request_token,
             types: Some(SourceType::MONITOR),
             multiple: Some(true),
             cursor_mode: Some(CursorMode::EMBEDDED),
             restore_token: token,
-            persist_mode: Some(PersistMode::ExplicitlyRevoked),
+            persist_mode: devices.is_none().then_some(PersistMode::ExplicitlyRevoked),
         };
 
         let _ssr = call_and_receive_response!(proxy.select_sources(&session, &src_opts), src_request, HashMap<String, OwnedValue>)?;
 
         debug!("Starting stream request");
         let start_req_token = UniqueToken::new();
-        let start_req = RequestProxy::from_unique(&self.connection, &start_req_token).await;
+        let start_req = RequestProxy::from_unique(&self.connection, &start_req_token).await?;
         let start_opts = StartCastOptions::new_from(&start_req_token);
 
-        let mut start_res = call_and_receive_response!(
-            proxy.start(&session, "RDESKTOPD", &start_opts,),
-            start_req,
-            StartCastResponse
-        )?;
-
-        self.token = Some(
-            start_res
-                .restore_token
-                .take()
-                .expect("No refresh token was present"),
-        );
+        let (streams, restore_token) = match &remote_desktop {
+            Some(remote_desktop) => {
+                let start_res = call_and_receive_response!(
+                    remote_desktop.start(&session, "RDESKTOPD", &start_opts,),
+                    start_req,
+                    StartRemoteDesktopResponse
+                )?;
+
+                let granted = start_res.devices.unwrap_or(DeviceType(0));
+                if devices.is_some_and(|devices| !granted.contains(devices)) {
+                    warn!(
+                        "Only some of the requested input devices may be controlled: {granted:?}"
+                    );
+                }
+                self.devices = Some(granted);
+                self.clipboard = start_res.clipboard_enabled.unwrap_or(false);
+
+                (
+                    start_res.streams.unwrap_or_default(),
+                    start_res.restore_token,
+                )
+            }
+            None => {
+                let start_res = call_and_receive_response!(
+                    proxy.start(&session, "RDESKTOPD", &start_opts,),
+                    start_req,
+                    StartCastResponse
+                )?;
+                let streams = start_res
+                    .streams
+                    .ok_or(PortalError::MissingField("streams"))?;
+                (streams, start_res.restore_token)
+            }
+        };
 
-        match self.try_write_token().await {
-            Ok(_) => info!("Wrote refresh token"),
-            Err(e) => warn!("Failed to write refresh token. This will cause another permissions request the next time rdesktopd starts. Error: {e}"),
+        // Portals that can't persist permissions hand out no token
+        self.token = restore_token;
+        if self.token.is_none() {
+            warn!("The portal handed out no refresh token, permissions will be requested again the next time rdesktopd starts");
+        } else {
+            match self.try_write_token().await {
+                Ok(_) => info!("Wrote refresh token"),
+                Err(e) => warn!("Failed to write refresh token. This will cause another permissions request the next time rdesktopd starts. Error: {e}"),
+            }
         }
 
-        let desktops = start_res.streams.iter().enumerate().filter_map(|(idx, i)| {
+        self.closed = Some(closed);
+        self.session = Some(Box::new(session_proxy));
+
+        let desktops = streams.iter().enumerate().filter_map(|(idx, i)| {
             let (width, height) = match i.properties().size() {
                 Some(v) => v,
                 None => {
@@ -184,20 +418,27 @@ impl<'a> CaptureManager<'a> {
                     pipewire_path: i.pipewire_path(),
                     width,
                     height,
-                    port: None,
+                    position: i.properties().position(),
+                    stream: None,
                 }
             )
         }).collect::<Vec<Desktop>>();
         debug!("Filtered Viable Desktops");
 
-        let desktops_with_ports: Vec<Desktop> = desktops
+        Ok(desktops)
+    }
+
+    /// Starts a pipeline for each of `desktops`, leaving out those that fail to
+    fn start_streams(desktops: &[Desktop], streams_tx: &Sender<()>) -> Vec<Desktop> {
+        desktops
             .iter()
             .flat_map(|d| {
-                let port = match Self::stream_desktop_gstreamer(
+                let stream = match Self::stream_desktop_gstreamer(
+                    d.loded_id,
                     d.pipewire_path,
                     d.width,
                     d.height,
-                    ds_tx.subscribe(),
+                    streams_tx.subscribe(),
                 ) {
                     Ok(v) => v,
                     Err(e) => {
@@ -209,57 +450,679 @@ impl<'a> CaptureManager<'a> {
                     }
                 };
                 Some(Desktop {
-                    port: Some(port),
+                    stream: Some(stream),
                     id: d.id.clone(),
                     ..*d
                 })
             })
-            .collect::<Vec<Desktop>>();
-
-        Ok(desktops_with_ports)
+            .collect::<Vec<Desktop>>()
     }
 
+    /// Starts capturing the desktop, returning the stream viewers subscribe to
     fn stream_desktop_gstreamer(
+        loded_id: u64,
         path: u32,
         width: i32,
         height: i32,
-        mut ds_rx: Receiver<()>,
-    ) -> Result<u16> {
-        let socket = TcpListener::bind("127.0.0.1:0")?;
-        let port = socket.local_addr()?.port();
-        drop(socket);
-
-        let mut cmd = Command::new("sh");
-
-        /*
-        cmd.stderr(Stdio::null());
-        cmd.stdin(Stdio::null());
-        cmd.stdout(Stdio::null());
-        */
-
-        cmd.args([
-            "-c",
-            // &format!(r#"gst-launch-1.0 -vvv pipewiresrc path={path} ! videoconvert ! tee name=split ! queue ! autovideosink split. ! x264enc speed-preset=superfast tune=zerolatency byte-stream=true sliced-threads=true threads=12 ! video/x-h264,stream-format=byte-stream,alignment=au,width={width},height={height} ! rtph264pay ! udpsink host=127.0.0.1 port={port}"#),
-            &format!(r#"gst-launch-1.0 pipewiresrc path={path} ! video/x-raw,format=BGRx,width={width},height={height} ! videoconvert ! video/x-raw,format=Y444,width={width},height={height} ! x264enc speed-preset=superfast tune=zerolatency byte-stream=true sliced-threads=true threads=12 ! video/x-h264,stream-format=byte-stream,alignment=au,width={width},height={height} ! rtph264pay ! udpsink host=127.0.0.1 port={port}"#),
-            // &format!(r#"gst-launch-1.0 -vvv pipewiresrc path={path} ! queue ! video/x-raw,format=BGRx,width={width},height={height} ! videoconvert ! x264enc speed-preset=superfast tune=zerolatency byte-stream=true sliced-threads=true ! rtph264pay ! udpsink host=127.0.0.1 port={port}"#),
-        ]);
+        ds_rx: Receiver<()>,
+    ) -> Result<DesktopStream> {
+        Ok(DesktopStream::start(
+            loded_id,
+            VideoSource::PipeWire(path),
+            width,
+            height,
+            ds_rx,
+        )?)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PipelineError {
+    #[error("The GStreamer element {0} is missing, is the plugin providing it installed?")]
+    MissingElement(&'static str),
+    #[error("Failed to assemble the pipeline: {0}")]
+    Assembly(#[from] gst::glib::BoolError),
+    #[error("Failed to link the encoder branch: {0}")]
+    Link(#[from] gst::PadLinkError),
+    #[error("Failed to change the pipeline state: {0}")]
+    StateChange(#[from] gst::StateChangeError),
+    #[error("{element} reported an error: {error} ({debug})")]
+    Element {
+        element: String,
+        error: gst::glib::Error,
+        debug: String,
+    },
+    #[error("The pipeline ended unexpectedly")]
+    UnexpectedEos,
+    #[error("The pipeline's bus is gone")]
+    MissingBus,
+}
+
+/// Where a desktop pipeline gets its raw frames from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoSource {
+    /// A PipeWire node handed out by the ScreenCast portal
+    PipeWire(u32),
+    /// A generated test pattern, needing neither a compositor nor a GPU
+    TestPattern,
+}
+
+impl std::fmt::Display for VideoSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PipeWire(path) => write!(f, "Path {path}"),
+            Self::TestPattern => f.write_str("Test Pattern"),
+        }
+    }
+}
+
+/// Chroma layouts offered by the x264 backend
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum H264Profile {
+    /// 4:2:0 chroma, decodable by practically every client
+    High,
+    /// Full 4:4:4 chroma, keeping text sharp but unsupported by most hardware decoders
+    High444,
+}
+
+/// The encoders a desktop can be streamed to a viewer with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VideoEncoder {
+    X264(H264Profile),
+    Vp8,
+    Vp9,
+    Av1,
+    /// Uncompressed I420 frames
+    Raw,
+}
+
+impl VideoEncoder {
+    /// Every encoder, in the same order as [LodestarCapabilities::CODECS]
+    pub const ALL: [Self; 6] = [
+        Self::X264(H264Profile::High444),
+        Self::X264(H264Profile::High),
+        Self::Vp9,
+        Self::Vp8,
+        Self::Av1,
+        Self::Raw,
+    ];
+
+    /// The encoder for the most preferred codec in `capabilities`
+    pub fn from_capabilities(capabilities: LodestarCapabilities) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|encoder| capabilities.contains(encoder.capability()))
+    }
+
+    /// The codecs whose encoder is installed on this machine
+    pub fn available() -> LodestarCapabilities {
+        Self::ALL
+            .into_iter()
+            .filter(|encoder| {
+                encoder
+                    .factory()
+                    .is_none_or(|factory| gst::ElementFactory::find(factory).is_some())
+            })
+            .fold(LodestarCapabilities::NONE, |caps, encoder| {
+                caps | encoder.capability()
+            })
+    }
+
+    /// The capability a client advertises to decode this encoder's output
+    pub fn capability(self) -> LodestarCapabilities {
+        match self {
+            Self::X264(H264Profile::High) => LodestarCapabilities::CODEC_H264,
+            Self::X264(H264Profile::High444) => LodestarCapabilities::CODEC_H264_444,
+            Self::Vp8 => LodestarCapabilities::CODEC_VP8,
+            Self::Vp9 => LodestarCapabilities::CODEC_VP9,
+            Self::Av1 => LodestarCapabilities::CODEC_AV1,
+            Self::Raw => LodestarCapabilities::CODEC_RAW,
+        }
+    }
+
+    /// The GStreamer element doing the encoding, `None` for raw video
+    fn factory(self) -> Option<&'static str> {
+        match self {
+            Self::X264(_) => Some("x264enc"),
+            Self::Vp8 => Some("vp8enc"),
+            Self::Vp9 => Some("vp9enc"),
+            Self::Av1 => Some("av1enc"),
+            Self::Raw => None,
+        }
+    }
+
+    /// The raw format fed into the encoder
+    fn raw_format(self) -> &'static str {
+        match self {
+            Self::X264(H264Profile::High444) => "Y444",
+            _ => "I420",
+        }
+    }
+
+    /// The caps the encoder has to produce, `None` for raw video
+    fn encoded_caps(self) -> Option<gst::Caps> {
+        let caps = match self {
+            Self::X264(profile) => gst::Caps::builder("video/x-h264")
+                .field("stream-format", "byte-stream")
+                .field("alignment", "au")
+                .field(
+                    "profile",
+                    match profile {
+                        H264Profile::High => "high",
+                        H264Profile::High444 => "high-4:4:4",
+                    },
+                ),
+            Self::Vp8 => gst::Caps::builder("video/x-vp8"),
+            Self::Vp9 => gst::Caps::builder("video/x-vp9"),
+            Self::Av1 => gst::Caps::builder("video/x-av1")
+                .field("stream-format", "obu-stream")
+                .field("alignment", "tu"),
+            Self::Raw => return None,
+        };
+        Some(caps.build())
+    }
+
+    /// The property and value setting the target bitrate to `kbps`, `None` for raw video
+    fn bitrate_setting(self, kbps: u32) -> Option<(&'static str, String)> {
+        match self {
+            Self::X264(_) => Some(("bitrate", kbps.to_string())),
+            // libvpx counts bits, not kilobits
+            Self::Vp8 | Self::Vp9 => Some((
+                "target-bitrate",
+                (kbps.min(i32::MAX as u32 / 1000) * 1000).to_string(),
+            )),
+            Self::Av1 => Some(("target-bitrate", kbps.to_string())),
+            Self::Raw => None,
+        }
+    }
+
+    /// Tunes the encoder for low latency, skipping settings the installed plugin doesn't know
+    fn configure(self, encoder: &gst::Element) {
+        let settings: &[(&str, &str)] = match self {
+            Self::X264(_) => &[
+                ("speed-preset", "superfast"),
+                ("tune", "zerolatency"),
+                ("byte-stream", "true"),
+                ("sliced-threads", "true"),
+                ("threads", "12"),
+            ],
+            Self::Vp8 | Self::Vp9 => &[
+                ("deadline", "1"),
+                ("cpu-used", "8"),
+                ("end-usage", "cbr"),
+                ("lag-in-frames", "0"),
+                ("row-mt", "true"),
+            ],
+            Self::Av1 => &[
+                ("usage-profile", "realtime"),
+                ("cpu-used", "9"),
+                ("end-usage", "cbr"),
+                ("lag-in-frames", "0"),
+                ("row-mt", "true"),
+            ],
+            Self::Raw => &[],
+        };
+
+        for (name, value) in settings {
+            if encoder.find_property(name).is_some() {
+                encoder.set_property_from_str(name, value);
+            } else {
+                debug!("{self:?} encoder has no {name} property, leaving it unset");
+            }
+        }
+    }
+}
+
+fn make_element(factory: &'static str) -> std::result::Result<gst::Element, PipelineError> {
+    gst::ElementFactory::make(factory, None).map_err(|_| PipelineError::MissingElement(factory))
+}
+
+/// A running desktop pipeline, which every viewer attaches its own encoder branch to
+///
+/// The pipeline stops once the death signal is received, an error occurs or the stream ends.
+/// Viewers are told about the latter two through [EncodedStream::failed].
+#[derive(Clone, Debug)]
+pub struct DesktopStream {
+    loded_id: u64,
+    source: VideoSource,
+    width: i32,
+    height: i32,
+    pipeline: gst::Pipeline,
+    tee: gst::Element,
+    /// Set once the pipeline stopped on an error or the end of the stream
+    failed: watch::Receiver<bool>,
+}
+
+impl DesktopStream {
+    /// Builds `source ! capsfilter ! tee` and starts playing it
+    pub fn start(
+        loded_id: u64,
+        source: VideoSource,
+        width: i32,
+        height: i32,
+        ds_rx: Receiver<()>,
+    ) -> std::result::Result<Self, PipelineError> {
+        let src = match source {
+            VideoSource::PipeWire(path) => {
+                let src = make_element("pipewiresrc")?;
+                src.set_property("path", path.to_string());
+                src
+            }
+            VideoSource::TestPattern => {
+                let src = make_element("videotestsrc")?;
+                src.set_property("is-live", true);
+                src
+            }
+        };
+
+        let raw_caps = make_element("capsfilter")?;
+        raw_caps.set_property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("format", "BGRx")
+                .field("width", width)
+                .field("height", height)
+                .build(),
+        );
+
+        let tee = make_element("tee")?;
+        // Nobody may be watching yet, which mustn't stop the pipeline
+        tee.set_property("allow-not-linked", true);
+
+        let pipeline = gst::Pipeline::new(Some(&format!("loded-desktop-{loded_id}")));
+        let elements = [&src, &raw_caps, &tee];
+        pipeline.add_many(&elements)?;
+        gst::Element::link_many(&elements)?;
 
+        pipeline.set_state(gst::State::Playing)?;
+
+        info!("Started GStreamer Pipeline for {source}");
+
+        let watcher = DesktopPipeline {
+            source,
+            pipeline: pipeline.clone(),
+        };
+        let (failed_tx, failed) = watch::channel(false);
         tokio::spawn(async move {
-            let mut child = cmd.spawn().expect("Failed to spawn gstreamer instance");
+            match watcher.run(ds_rx).await {
+                Ok(_) => info!("Stopped GStreamer Pipeline for {source}"),
+                Err(e) => {
+                    error!("GStreamer Pipeline for {source} failed: {e}");
+                    failed_tx.send_replace(true);
+                }
+            }
+        });
+
+        Ok(Self {
+            loded_id,
+            source,
+            width,
+            height,
+            pipeline,
+            tee,
+            failed,
+        })
+    }
 
-            info!("Started GStreamer Instance");
+    /// Attaches `queue ! videorate ! videoscale ! videoconvert ! capsfilter ! encoder ! capsfilter
+    /// ! appsink` to the tee for a new viewer
+    pub fn subscribe(
+        &self,
+        encoder: VideoEncoder,
+        settings: EncoderSettings,
+    ) -> std::result::Result<EncodedStream, PipelineError> {
+        let (frame_tx, frames) = broadcast::channel(FRAME_BUFFER);
+        let mut stream = EncodedStream {
+            frames,
+            encoder,
+            width: self.width,
+            height: self.height,
+            pipeline: self.pipeline.clone(),
+            tee_pad: None,
+            elements: self.encoder_branch(encoder, frame_tx)?,
+            failed: self.failed.clone(),
+            size: None,
+        };
+        stream.apply(settings);
 
-            if (ds_rx.recv().await).is_ok() {
-                if child.kill().is_ok() {
-                    info!("Killed GStreamer Pipeline for Path {path}");
-                } else {
-                    warn!("Failed to kill GSTreamer Pipeline for Path {path}");
+        let refs = stream.elements.iter().collect::<Vec<&gst::Element>>();
+        self.pipeline.add_many(&refs)?;
+        match self.attach_branch(&refs) {
+            Ok(tee_pad) => stream.tee_pad = Some(tee_pad),
+            Err(e) => {
+                for element in refs.iter() {
+                    let _ = element.set_state(gst::State::Null);
                 }
-            } else {
-                warn!("Failed to receive death signal");
+                let _ = self.pipeline.remove_many(&refs);
+                return Err(e);
+            }
+        }
+
+        debug!("Attached {encoder:?} encoder to {}", self.source);
+
+        Ok(stream)
+    }
+
+    fn attach_branch(
+        &self,
+        elements: &[&gst::Element],
+    ) -> std::result::Result<gst::Pad, PipelineError> {
+        gst::Element::link_many(elements)?;
+        for element in elements {
+            element.sync_state_with_parent()?;
+        }
+
+        let tee_pad = self
+            .tee
+            .request_pad_simple("src_%u")
+            .ok_or_else(|| gst::glib::bool_error!("The tee has no free source pad"))?;
+        let queue_pad = elements[0]
+            .static_pad("sink")
+            .ok_or_else(|| gst::glib::bool_error!("The queue has no sink pad"))?;
+        if let Err(e) = tee_pad.link(&queue_pad) {
+            self.tee.release_request_pad(&tee_pad);
+            return Err(e.into());
+        }
+
+        Ok(tee_pad)
+    }
+
+    fn encoder_branch(
+        &self,
+        encoder: VideoEncoder,
+        frame_tx: Sender<LodestarVideoFramePacket>,
+    ) -> std::result::Result<Vec<gst::Element>, PipelineError> {
+        let queue = make_element("queue")?;
+        // A viewer whose encoder can't keep up skips frames instead of delaying everyone else
+        queue.set_property_from_str("leaky", "downstream");
+        queue.set_property("max-size-buffers", 1u32);
+        queue.set_property("max-size-bytes", 0u32);
+        queue.set_property("max-size-time", 0u64);
+
+        let rate = make_element("videorate")?;
+        rate.set_property("drop-only", true);
+
+        let scale = make_element("videoscale")?;
+        let convert = make_element("videoconvert")?;
+        // The caps are set by EncodedStream::apply
+        let convert_caps = make_element("capsfilter")?;
+
+        let mut elements = vec![queue, rate, scale, convert, convert_caps];
+
+        if let Some(factory) = encoder.factory() {
+            let element = make_element(factory)?;
+            encoder.configure(&element);
+            elements.push(element);
+        }
+
+        if let Some(caps) = encoder.encoded_caps() {
+            let encoded_caps = make_element("capsfilter")?;
+            encoded_caps.set_property("caps", caps);
+            elements.push(encoded_caps);
+        }
+
+        let loded_id = self.loded_id;
+        let source = self.source;
+        let sink = make_element("appsink")?;
+        sink.set_property("emit-signals", true);
+        sink.set_property("sync", false);
+        sink.set_property("max-buffers", 1u32);
+        sink.set_property("drop", true);
+        sink.connect("new-sample", false, move |args| {
+            let sink = args[0]
+                .get::<gst::Element>()
+                .expect("new-sample is only emitted by the appsink");
+            Some(forward_sample(loded_id, source, &sink, &frame_tx).to_value())
+        });
+        elements.push(sink);
+
+        Ok(elements)
+    }
+}
+
+/// A viewer's encoder branch, removed from the pipeline once dropped
+#[derive(Debug)]
+pub struct EncodedStream {
+    /// The encoded frames of the desktop
+    pub frames: Receiver<LodestarVideoFramePacket>,
+    encoder: VideoEncoder,
+    width: i32,
+    height: i32,
+    pipeline: gst::Pipeline,
+    /// `None` until the branch is linked to the tee
+    tee_pad: Option<gst::Pad>,
+    /// `queue`, `videorate`, `videoscale`, `videoconvert`, `capsfilter`, then the encoder unless
+    /// the video is raw, its `capsfilter` and the `appsink`
+    elements: Vec<gst::Element>,
+    failed: watch::Receiver<bool>,
+    /// The encoded resolution last applied, `None` before the first settings
+    size: Option<(i32, i32)>,
+}
+
+impl EncodedStream {
+    const RATE: usize = 1;
+    const CONVERT_CAPS: usize = 4;
+    const ENCODER: usize = 5;
+
+    pub fn encoder(&self) -> VideoEncoder {
+        self.encoder
+    }
+
+    /// Resolves once the desktop's pipeline failed or ran out, after which no frames follow
+    ///
+    /// A pipeline stopped on purpose never resolves this, its viewers are told otherwise.
+    pub fn failed(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut failed = self.failed.clone();
+        async move {
+            while !*failed.borrow_and_update() {
+                if failed.changed().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            }
+        }
+    }
+
+    pub fn keyframe_requester(&self) -> KeyframeRequester {
+        KeyframeRequester {
+            encoder_pad: self
+                .encoder
+                .factory()
+                .and_then(|_| self.elements[Self::ENCODER].static_pad("src")),
+        }
+    }
+
+    /// Changes the bitrate, framerate and resolution of the viewer's video on the fly
+    ///
+    /// A resolution change makes the encoder start over with a keyframe.
+    pub fn apply(&mut self, settings: EncoderSettings) {
+        // New caps renegotiate the branch even if they're the same, so they're only set when the
+        // resolution actually changed
+        let size = settings.scaled_size(self.width, self.height);
+        if self.size != Some(size) {
+            let (width, height) = size;
+            self.elements[Self::CONVERT_CAPS].set_property(
+                "caps",
+                gst::Caps::builder("video/x-raw")
+                    .field("format", self.encoder.raw_format())
+                    .field("width", width)
+                    .field("height", height)
+                    .build(),
+            );
+            self.size = Some(size);
+        }
+
+        self.elements[Self::RATE]
+            .set_property("max-rate", settings.framerate.min(i32::MAX as u32) as i32);
+
+        if let Some((name, value)) = self.encoder.bitrate_setting(settings.bitrate) {
+            let encoder = &self.elements[Self::ENCODER];
+            if encoder.find_property(name).is_some() {
+                encoder.set_property_from_str(name, &value);
+            }
+        }
+    }
+}
+
+/// Forces a viewer's encoder to emit a keyframe
+#[derive(Clone, Debug)]
+pub struct KeyframeRequester {
+    /// The source pad of the encoder, `None` for raw video where every frame is a keyframe
+    encoder_pad: Option<gst::Pad>,
+}
+
+impl KeyframeRequester {
+    /// Sends a force-key-unit event upstream into the encoder, which encodes the next frame it
+    /// receives as a keyframe along with its headers
+    pub fn request(&self) {
+        if let Some(pad) = self.encoder_pad.as_ref() {
+            let force_key_unit = gst::Structure::builder("GstForceKeyUnit")
+                .field("running-time", u64::MAX)
+                .field("all-headers", true)
+                .field("count", 0u32)
+                .build();
+            if !pad.send_event(gst::event::CustomUpstream::new(force_key_unit)) {
+                warn!("The encoder ignored a keyframe request");
+            }
+        }
+    }
+}
+
+impl Drop for EncodedStream {
+    fn drop(&mut self) {
+        let tee_pad = match self.tee_pad.take() {
+            Some(v) => v,
+            None => return,
+        };
+        let pipeline = self.pipeline.clone();
+        let elements = std::mem::take(&mut self.elements);
+
+        // Unlinking while the tee pushes a buffer would race its streaming thread, so the branch
+        // is only taken apart once the pad is idle
+        tee_pad.add_probe(gst::PadProbeType::IDLE, move |pad, _| {
+            if let Some(peer) = pad.peer() {
+                let _ = pad.unlink(&peer);
+            }
+            if let Some(tee) = pad.parent_element() {
+                tee.release_request_pad(pad);
+            }
+            for element in elements.iter() {
+                let _ = element.set_state(gst::State::Null);
             }
+            let _ = pipeline.remove_many(&elements.iter().collect::<Vec<&gst::Element>>());
+            gst::PadProbeReturn::Remove
         });
+    }
+}
+
+/// Pulls a sample out of an appsink and publishes it as a frame
+fn forward_sample(
+    loded_id: u64,
+    source: VideoSource,
+    sink: &gst::Element,
+    frame_tx: &Sender<LodestarVideoFramePacket>,
+) -> gst::FlowReturn {
+    let sample = sink.emit_by_name::<Option<gst::Sample>>("pull-sample", &[]);
+    let buffer = match sample.as_ref().and_then(|s| s.buffer()) {
+        Some(v) => v,
+        None => return gst::FlowReturn::Eos,
+    };
+    let map = match buffer.map_readable() {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Failed to map encoded frame for {source}: {e}");
+            return gst::FlowReturn::Error;
+        }
+    };
+
+    // Sending only fails while nobody is watching, which is fine
+    let _ = frame_tx.send(LodestarVideoFramePacket {
+        loded_id,
+        pts: buffer.pts().map_or(u64::MAX, |pts| pts.nseconds()),
+        keyframe: !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT),
+        data: Bytes::copy_from_slice(map.as_slice()),
+    });
+
+    gst::FlowReturn::Ok
+}
 
-        Ok(port)
+/// Watches the bus of a desktop pipeline
+struct DesktopPipeline {
+    source: VideoSource,
+    pipeline: gst::Pipeline,
+}
+
+impl DesktopPipeline {
+    /// Watches the bus until the death signal, an error or the end of the stream, then shuts
+    /// the pipeline down
+    async fn run(self, mut ds_rx: Receiver<()>) -> std::result::Result<(), PipelineError> {
+        let mut messages = self
+            .pipeline
+            .bus()
+            .ok_or(PipelineError::MissingBus)?
+            .stream();
+
+        let outcome = loop {
+            tokio::select! {
+                res = ds_rx.recv() => {
+                    if res.is_err() {
+                        warn!("Failed to receive death signal");
+                    }
+                    break Ok(());
+                }
+                msg = messages.next() => {
+                    let msg = match msg {
+                        Some(v) => v,
+                        None => break Err(PipelineError::MissingBus),
+                    };
+                    if let Some(res) = self.handle_message(&msg) {
+                        break res;
+                    }
+                }
+            }
+        };
+
+        self.pipeline.set_state(gst::State::Null)?;
+
+        outcome
+    }
+
+    /// Logs a bus message, returning `Some` if the pipeline can't continue
+    fn handle_message(&self, msg: &gst::Message) -> Option<std::result::Result<(), PipelineError>> {
+        use gst::MessageView;
+
+        let element = msg
+            .src()
+            .map(|s| s.path_string().to_string())
+            .unwrap_or_else(|| "Unknown element".to_owned());
+
+        match msg.view() {
+            MessageView::Error(err) => Some(Err(PipelineError::Element {
+                element,
+                error: err.error(),
+                debug: err.debug().unwrap_or_default(),
+            })),
+            MessageView::Eos(_) => Some(Err(PipelineError::UnexpectedEos)),
+            MessageView::Warning(w) => {
+                warn!(
+                    "GStreamer Pipeline for {}: {element} warned: {} ({})",
+                    self.source,
+                    w.error(),
+                    w.debug().unwrap_or_default()
+                );
+                None
+            }
+            MessageView::StateChanged(change)
+                if msg.src().as_ref() == Some(self.pipeline.upcast_ref()) =>
+            {
+                debug!(
+                    "GStreamer Pipeline for {} changed state from {:?} to {:?}",
+                    self.source,
+                    change.old(),
+                    change.current()
+                );
+                None
+            }
+            _ => None,
+        }
     }
 }
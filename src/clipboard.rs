@@ -0,0 +1,423 @@
+use std::{
+    collections::HashMap,
+    os::unix::io::{FromRawFd, IntoRawFd},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures::StreamExt;
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{broadcast::Receiver, watch},
+};
+use zbus::{dbus_proxy, fdo::Result};
+use zvariant::{DeserializeDict, ObjectPath, OwnedFd, OwnedObjectPath, SerializeDict, Type};
+
+use crate::{
+    protocol::{ClipboardMime, LodestarClipboardDataPacket, LodestarClipboardEntry},
+    DESTINATION, PATH,
+};
+
+/// How long an application on the host gets to hand over or take clipboard contents
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Options for the SetSelection method
+#[derive(SerializeDict, DeserializeDict, Type, Debug, Default)]
+#[zvariant(signature = "dict")]
+pub struct SetSelectionOptions {
+    /// The MIME types the session offers its clipboard contents in
+    pub mime_types: Option<Vec<String>>,
+}
+
+/// Options of the SelectionOwnerChanged signal
+#[derive(SerializeDict, DeserializeDict, Type, Debug, Default)]
+#[zvariant(signature = "dict")]
+pub struct SelectionOwnerChangedOptions {
+    /// The MIME types the new clipboard contents are offered in
+    pub mime_types: Option<Vec<String>>,
+    /// Whether the session itself set the clipboard
+    pub session_is_owner: Option<bool>,
+}
+
+/// Access to the compositor's clipboard from a remote desktop session
+///
+/// The clipboard has to be requested before the session is started.
+#[dbus_proxy(interface = "org.freedesktop.portal.Clipboard")]
+pub trait Clipboard {
+    fn request_clipboard(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, zvariant::Value<'_>>,
+    ) -> Result<()>;
+
+    fn set_selection(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: &SetSelectionOptions,
+    ) -> Result<()>;
+
+    /// The write end of a pipe to answer the transfer `serial` through
+    fn selection_write(&self, session_handle: &ObjectPath<'_>, serial: u32) -> Result<OwnedFd>;
+
+    fn selection_write_done(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        serial: u32,
+        success: bool,
+    ) -> Result<()>;
+
+    /// The read end of a pipe the clipboard's contents in `mime_type` arrive through
+    fn selection_read(&self, session_handle: &ObjectPath<'_>, mime_type: &str) -> Result<OwnedFd>;
+
+    #[dbus_proxy(signal)]
+    fn selection_owner_changed(
+        &self,
+        session_handle: ObjectPath<'_>,
+        options: SelectionOwnerChangedOptions,
+    ) -> Result<()>;
+
+    /// An application on the host pastes what the session put on the clipboard
+    #[dbus_proxy(signal)]
+    fn selection_transfer(
+        &self,
+        session_handle: ObjectPath<'_>,
+        mime_type: String,
+        serial: u32,
+    ) -> Result<()>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ClipboardError {
+    #[error("The host's clipboard has no {0:?} contents")]
+    Unavailable(ClipboardMime),
+    #[error(
+        "The host's clipboard holds more than {} bytes",
+        LodestarClipboardDataPacket::MAX_LENGTH
+    )]
+    TooLarge,
+    #[error("The clipboard transfer didn't finish in time")]
+    TimedOut,
+    #[error("An internal mutex was poisoned")]
+    PoisonedMutex,
+    #[error("The portal failed: {0}")]
+    Portal(#[from] zbus::fdo::Error),
+    #[error("Failed to transfer the clipboard: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Shares the compositor's clipboard with clients through the Clipboard portal
+///
+/// Contents pushed by a client are kept here and handed to applications on the host whenever
+/// they paste them, while the host's clipboard is only read once a client pulls it.
+///
+/// The portal only tells which formats the host's clipboard holds once it changes, so whatever
+/// was copied before the session started isn't offered to clients. They can still pull it,
+/// which reads it in the preferred MIME type of the format.
+#[derive(Debug)]
+pub struct ClipboardManager {
+    proxy: ClipboardProxy<'static>,
+    /// The session whose clipboard is shared, replaced when the session is reopened
+    session: Mutex<OwnedObjectPath>,
+    /// What clients last put on the host's clipboard
+    pushed: Mutex<HashMap<ClipboardMime, Bytes>>,
+    /// The formats the host's clipboard holds and the MIME type each is read as, empty while
+    /// loded owns the clipboard and `None` until it first changed
+    host: Mutex<Option<HashMap<ClipboardMime, &'static str>>>,
+    offers: watch::Sender<Vec<ClipboardMime>>,
+}
+
+impl ClipboardManager {
+    /// Takes over the clipboard of `session`, which has to have been granted access to it
+    pub async fn new(
+        connection: &zbus::Connection,
+        session: OwnedObjectPath,
+    ) -> zbus::Result<Self> {
+        let proxy = ClipboardProxy::builder(connection)
+            .path(PATH)?
+            .destination(DESTINATION)?
+            .build()
+            .await?;
+        let (offers, _) = watch::channel(Vec::new());
+
+        Ok(Self {
+            proxy,
+            session: Mutex::new(session),
+            pushed: Mutex::new(HashMap::new()),
+            host: Mutex::new(None),
+            offers,
+        })
+    }
+
+    /// Asks for the clipboard of a remote desktop session that hasn't been started yet
+    pub(crate) async fn request(
+        connection: &zbus::Connection,
+        session: &ObjectPath<'_>,
+    ) -> zbus::Result<()> {
+        let proxy = ClipboardProxy::builder(connection)
+            .path(PATH)?
+            .destination(DESTINATION)?
+            .build()
+            .await?;
+        proxy.request_clipboard(session, HashMap::new()).await?;
+        Ok(())
+    }
+
+    /// Moves over to `session`, which replaced the one the clipboard was shared from
+    ///
+    /// What clients pushed is put on the clipboard of the new session, while the host's
+    /// clipboard isn't offered again until it changes.
+    pub async fn rebind(
+        &self,
+        session: OwnedObjectPath,
+    ) -> std::result::Result<(), ClipboardError> {
+        *self
+            .session
+            .lock()
+            .map_err(|_| ClipboardError::PoisonedMutex)? = session.clone();
+        *self
+            .host
+            .lock()
+            .map_err(|_| ClipboardError::PoisonedMutex)? = None;
+        self.offers.send_replace(Vec::new());
+
+        let mime_types = self
+            .pushed
+            .lock()
+            .map_err(|_| ClipboardError::PoisonedMutex)?
+            .keys()
+            .flat_map(|mime| mime.mime_types())
+            .map(|mime_type| mime_type.to_string())
+            .collect::<Vec<_>>();
+        if !mime_types.is_empty() {
+            let options = SetSelectionOptions {
+                mime_types: Some(mime_types),
+            };
+            self.proxy.set_selection(&session, &options).await?;
+        }
+        Ok(())
+    }
+
+    fn session(&self) -> std::result::Result<OwnedObjectPath, ClipboardError> {
+        self.session
+            .lock()
+            .map(|session| session.clone())
+            .map_err(|_| ClipboardError::PoisonedMutex)
+    }
+
+    /// Whether `handle` is the session the clipboard is shared from
+    fn is_session(&self, handle: &ObjectPath<'_>) -> bool {
+        self.session
+            .lock()
+            .map(|session| session.as_str() == handle.as_str())
+            .unwrap_or(false)
+    }
+
+    /// The formats the host's clipboard can be pulled in, updated whenever an application on the
+    /// host copies something
+    pub fn subscribe(&self) -> watch::Receiver<Vec<ClipboardMime>> {
+        self.offers.subscribe()
+    }
+
+    /// Replaces the host's clipboard with `entries`
+    pub async fn push(
+        &self,
+        entries: Vec<LodestarClipboardEntry>,
+    ) -> std::result::Result<(), ClipboardError> {
+        let mime_types = entries
+            .iter()
+            .flat_map(|entry| entry.mime_type.mime_types())
+            .map(|mime_type| mime_type.to_string())
+            .collect();
+        *self
+            .pushed
+            .lock()
+            .map_err(|_| ClipboardError::PoisonedMutex)? = entries
+            .into_iter()
+            .map(|entry| (entry.mime_type, entry.data))
+            .collect();
+
+        let options = SetSelectionOptions {
+            mime_types: Some(mime_types),
+        };
+        let session = self.session()?;
+        self.proxy.set_selection(&session, &options).await?;
+        Ok(())
+    }
+
+    /// Reads the host's clipboard in `mime`
+    ///
+    /// Text is checked to be UTF-8, and contents over [LodestarClipboardDataPacket::MAX_LENGTH]
+    /// are refused rather than cut off.
+    pub async fn pull(&self, mime: ClipboardMime) -> std::result::Result<Bytes, ClipboardError> {
+        let mime_type = match &*self
+            .host
+            .lock()
+            .map_err(|_| ClipboardError::PoisonedMutex)?
+        {
+            Some(host) => host.get(&mime).copied(),
+            None => mime.mime_types().first().copied(),
+        }
+        .ok_or(ClipboardError::Unavailable(mime))?;
+
+        let session = self.session()?;
+        let fd = self.proxy.selection_read(&session, mime_type).await?;
+        // SAFETY: The descriptor was received over the bus and is owned by nothing else
+        let file = unsafe { std::fs::File::from_raw_fd(fd.into_raw_fd()) };
+        let limit = LodestarClipboardDataPacket::MAX_LENGTH as u64;
+        let mut reader = tokio::fs::File::from_std(file).take(limit + 1);
+
+        let mut data = Vec::new();
+        tokio::time::timeout(TRANSFER_TIMEOUT, reader.read_to_end(&mut data))
+            .await
+            .map_err(|_| ClipboardError::TimedOut)??;
+
+        if data.len() as u64 > limit {
+            return Err(ClipboardError::TooLarge);
+        }
+        if matches!(mime, ClipboardMime::Text | ClipboardMime::Html)
+            && std::str::from_utf8(&data).is_err()
+        {
+            return Err(ClipboardError::Unavailable(mime));
+        }
+
+        Ok(data.into())
+    }
+
+    /// Follows the host's clipboard and answers pastes of what clients pushed until told to die
+    ///
+    /// Every paste is answered by a task of its own, so an application on the host that is slow
+    /// to take the contents holds up nothing else.
+    pub async fn listen(self: Arc<Self>, mut die_handle: Receiver<()>) -> zbus::Result<()> {
+        let mut owner_changes = self.proxy.receive_selection_owner_changed().await?;
+        let mut transfers = self.proxy.receive_selection_transfer().await?;
+
+        loop {
+            tokio::select! {
+                _ = die_handle.recv() => break,
+                Some(change) = owner_changes.next() => {
+                    let args = change.args()?;
+                    if self.is_session(args.session_handle()) {
+                        if let Err(e) = self.owner_changed(args.options()) {
+                            warn!("Failed to follow the host's clipboard: {e}");
+                        }
+                    }
+                }
+                Some(transfer) = transfers.next() => {
+                    let args = transfer.args()?;
+                    if self.is_session(args.session_handle()) {
+                        let manager = self.clone();
+                        let session = args.session_handle().to_owned().into();
+                        let mime_type = args.mime_type().to_owned();
+                        let serial = *args.serial();
+                        tokio::spawn(async move {
+                            manager.transfer(&session, &mime_type, serial).await
+                        });
+                    }
+                }
+                else => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn owner_changed(
+        &self,
+        options: &SelectionOwnerChangedOptions,
+    ) -> std::result::Result<(), ClipboardError> {
+        let mut host = self
+            .host
+            .lock()
+            .map_err(|_| ClipboardError::PoisonedMutex)?;
+
+        // The portal reports the clipboard loded itself set as well
+        if options.session_is_owner.unwrap_or(false) {
+            *host = Some(HashMap::new());
+            return Ok(());
+        }
+
+        // Each format is read as the MIME type it prefers among the offered ones
+        let offered = options.mime_types.as_deref().unwrap_or_default();
+        let formats = ClipboardMime::ALL
+            .into_iter()
+            .filter_map(|mime| {
+                let mime_type = mime
+                    .mime_types()
+                    .iter()
+                    .find(|mime_type| offered.iter().any(|o| o == *mime_type))?;
+                Some((mime, *mime_type))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let offer = ClipboardMime::ALL
+            .into_iter()
+            .filter(|mime| formats.contains_key(mime))
+            .collect::<Vec<_>>();
+        debug!("The host's clipboard changed, it can be shared as {offer:?}");
+        *host = Some(formats);
+        self.offers.send_replace(offer);
+        Ok(())
+    }
+
+    /// Hands what clients pushed to an application on the host pasting it, in the session that
+    /// asked for it
+    async fn transfer(&self, session: &OwnedObjectPath, mime_type: &str, serial: u32) {
+        let data = match self.pushed.lock() {
+            Ok(pushed) => {
+                ClipboardMime::from_mime_type(mime_type).and_then(|mime| pushed.get(&mime).cloned())
+            }
+            Err(_) => {
+                warn!(
+                    "Failed to look up the clipboard: {}",
+                    ClipboardError::PoisonedMutex
+                );
+                None
+            }
+        };
+
+        let success = match data {
+            Some(data) => {
+                let written =
+                    tokio::time::timeout(TRANSFER_TIMEOUT, self.write(session, serial, &data))
+                        .await
+                        .unwrap_or(Err(ClipboardError::TimedOut));
+                match written {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("Failed to hand the clipboard over as {mime_type}: {e}");
+                        false
+                    }
+                }
+            }
+            None => {
+                debug!("Nothing was pushed to the clipboard as {mime_type}");
+                false
+            }
+        };
+
+        if let Err(e) = self
+            .proxy
+            .selection_write_done(session, serial, success)
+            .await
+        {
+            warn!("Failed to finish the clipboard transfer {serial}: {e}");
+        }
+    }
+
+    async fn write(
+        &self,
+        session: &OwnedObjectPath,
+        serial: u32,
+        data: &[u8],
+    ) -> std::result::Result<(), ClipboardError> {
+        let fd = self.proxy.selection_write(session, serial).await?;
+        // SAFETY: The descriptor was received over the bus and is owned by nothing else
+        let file = unsafe { std::fs::File::from_raw_fd(fd.into_raw_fd()) };
+        let mut file = tokio::fs::File::from_std(file);
+        file.write_all(data).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
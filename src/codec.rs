@@ -0,0 +1,92 @@
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::protocol::{Encode, LodestarPacket, LodestarPacketParsingError, PACKET_HEADER_LENGTH};
+
+/// Default upper bound on the body of a single frame
+pub const DEFAULT_MAX_FRAME_LENGTH: u64 = 1 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LodestarCodecError {
+    #[error("Frame of {0} bytes exceeds the maximum frame length")]
+    FrameTooLarge(u64),
+    #[error("Failed to parse packet: {0}")]
+    Parsing(#[from] LodestarPacketParsingError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Splits a byte stream into [LodestarPacket]s using the `packet_type`/`packet_length` header
+///
+/// Use with [tokio_util::codec::FramedRead] to get a `Stream` of packets and with
+/// [tokio_util::codec::FramedWrite] to get a `Sink` accepting any [Encode] packet.
+#[derive(Debug, Clone)]
+pub struct LodestarCodec {
+    max_frame_length: u64,
+}
+
+impl LodestarCodec {
+    pub fn new() -> Self {
+        Self::with_max_frame_length(DEFAULT_MAX_FRAME_LENGTH)
+    }
+
+    pub fn with_max_frame_length(max_frame_length: u64) -> Self {
+        Self { max_frame_length }
+    }
+
+    pub fn max_frame_length(&self) -> u64 {
+        self.max_frame_length
+    }
+}
+
+impl Default for LodestarCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for LodestarCodec {
+    type Item = LodestarPacket;
+    type Error = LodestarCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < PACKET_HEADER_LENGTH {
+            src.reserve(PACKET_HEADER_LENGTH - src.len());
+            return Ok(None);
+        }
+
+        // The header is only peeked so nothing is consumed until the whole frame is buffered
+        let (packet_type, packet_length) =
+            LodestarPacket::decode_header(&mut &src[..PACKET_HEADER_LENGTH])?;
+        if packet_length > self.max_frame_length {
+            return Err(LodestarCodecError::FrameTooLarge(packet_length));
+        }
+
+        let frame_length = PACKET_HEADER_LENGTH + packet_length as usize;
+        if src.len() < frame_length {
+            src.reserve(frame_length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(PACKET_HEADER_LENGTH);
+        let packet_data = src.split_to(packet_length as usize).freeze();
+        Ok(Some(LodestarPacket::from_parts(packet_type, packet_data)?))
+    }
+}
+
+impl<T: Encode> Encoder<T> for LodestarCodec {
+    type Error = LodestarCodecError;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let len = item.encoded_len() as u64;
+        if len > self.max_frame_length {
+            return Err(LodestarCodecError::FrameTooLarge(len));
+        }
+
+        dst.reserve(PACKET_HEADER_LENGTH + len as usize);
+        dst.put_u64_le(T::PACKET_TYPE as u64);
+        dst.put_u64_le(len);
+        item.encode(dst);
+        Ok(())
+    }
+}
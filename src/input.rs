@@ -1,10 +1,10 @@
-use std::{io::ErrorKind, sync::Mutex};
-
-use evdev::{
-    uinput::{VirtualDevice, VirtualDeviceBuilder},
-    AttributeSet, EventType, InputEvent, Key, RelativeAxisType,
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
 };
 
+use evdev::{AbsoluteAxisType, EventType, InputEvent, Key, RelativeAxisType};
+
 use log::{debug, info, warn};
 
 use tokio::sync::{
@@ -12,7 +12,12 @@ use tokio::sync::{
     mpsc::{channel, Receiver, Sender},
 };
 
-use crate::Result;
+use crate::{
+    backend::{InputBackend, InputDevice},
+    capture::Desktop,
+    keymap::TextTyper,
+    Result,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum InputManagerError {
@@ -23,14 +28,14 @@ pub enum InputManagerError {
 }
 
 #[repr(i32)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KeyDirection {
     Up = 0,
     Down = 1,
     RepeatingDown = 2,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct KeyEvent {
     pub key: Key,
     pub direction: KeyDirection,
@@ -42,78 +47,257 @@ impl From<KeyEvent> for InputEvent {
     }
 }
 
+/// The W3C UI Events `KeyboardEvent.code` values and the keys they map to
+///
+/// The virtual keyboard is created with exactly these keys, so every code accepted by
+/// [KeyEvent::from_js_key_name_with_direction] can also be emitted.
+pub const JS_KEY_CODES: &[(&str, Key)] = &[
+    // Alphanumeric section
+    ("Backquote", Key::KEY_GRAVE),
+    ("Backslash", Key::KEY_BACKSLASH),
+    ("BracketLeft", Key::KEY_LEFTBRACE),
+    ("BracketRight", Key::KEY_RIGHTBRACE),
+    ("Comma", Key::KEY_COMMA),
+    ("Digit0", Key::KEY_0),
+    ("Digit1", Key::KEY_1),
+    ("Digit2", Key::KEY_2),
+    ("Digit3", Key::KEY_3),
+    ("Digit4", Key::KEY_4),
+    ("Digit5", Key::KEY_5),
+    ("Digit6", Key::KEY_6),
+    ("Digit7", Key::KEY_7),
+    ("Digit8", Key::KEY_8),
+    ("Digit9", Key::KEY_9),
+    ("Equal", Key::KEY_EQUAL),
+    ("IntlBackslash", Key::KEY_102ND),
+    ("IntlRo", Key::KEY_RO),
+    ("IntlYen", Key::KEY_YEN),
+    ("KeyA", Key::KEY_A),
+    ("KeyB", Key::KEY_B),
+    ("KeyC", Key::KEY_C),
+    ("KeyD", Key::KEY_D),
+    ("KeyE", Key::KEY_E),
+    ("KeyF", Key::KEY_F),
+    ("KeyG", Key::KEY_G),
+    ("KeyH", Key::KEY_H),
+    ("KeyI", Key::KEY_I),
+    ("KeyJ", Key::KEY_J),
+    ("KeyK", Key::KEY_K),
+    ("KeyL", Key::KEY_L),
+    ("KeyM", Key::KEY_M),
+    ("KeyN", Key::KEY_N),
+    ("KeyO", Key::KEY_O),
+    ("KeyP", Key::KEY_P),
+    ("KeyQ", Key::KEY_Q),
+    ("KeyR", Key::KEY_R),
+    ("KeyS", Key::KEY_S),
+    ("KeyT", Key::KEY_T),
+    ("KeyU", Key::KEY_U),
+    ("KeyV", Key::KEY_V),
+    ("KeyW", Key::KEY_W),
+    ("KeyX", Key::KEY_X),
+    ("KeyY", Key::KEY_Y),
+    ("KeyZ", Key::KEY_Z),
+    ("Minus", Key::KEY_MINUS),
+    ("Period", Key::KEY_DOT),
+    ("Quote", Key::KEY_APOSTROPHE),
+    ("Semicolon", Key::KEY_SEMICOLON),
+    ("Slash", Key::KEY_SLASH),
+    // Functional keys of the alphanumeric section
+    ("AltLeft", Key::KEY_LEFTALT),
+    ("AltRight", Key::KEY_RIGHTALT),
+    ("Backspace", Key::KEY_BACKSPACE),
+    ("CapsLock", Key::KEY_CAPSLOCK),
+    ("ContextMenu", Key::KEY_COMPOSE),
+    ("ControlLeft", Key::KEY_LEFTCTRL),
+    ("ControlRight", Key::KEY_RIGHTCTRL),
+    ("Enter", Key::KEY_ENTER),
+    ("MetaLeft", Key::KEY_LEFTMETA),
+    ("MetaRight", Key::KEY_RIGHTMETA),
+    ("ShiftLeft", Key::KEY_LEFTSHIFT),
+    ("ShiftRight", Key::KEY_RIGHTSHIFT),
+    ("Space", Key::KEY_SPACE),
+    ("Tab", Key::KEY_TAB),
+    ("Convert", Key::KEY_HENKAN),
+    ("KanaMode", Key::KEY_KATAKANAHIRAGANA),
+    ("Lang1", Key::KEY_HANGEUL),
+    ("Lang2", Key::KEY_HANJA),
+    ("Lang3", Key::KEY_KATAKANA),
+    ("Lang4", Key::KEY_HIRAGANA),
+    ("Lang5", Key::KEY_ZENKAKUHANKAKU),
+    ("NonConvert", Key::KEY_MUHENKAN),
+    // Control pad section
+    ("Delete", Key::KEY_DELETE),
+    ("End", Key::KEY_END),
+    ("Help", Key::KEY_HELP),
+    ("Home", Key::KEY_HOME),
+    ("Insert", Key::KEY_INSERT),
+    ("PageDown", Key::KEY_PAGEDOWN),
+    ("PageUp", Key::KEY_PAGEUP),
+    // Arrow pad section
+    ("ArrowDown", Key::KEY_DOWN),
+    ("ArrowLeft", Key::KEY_LEFT),
+    ("ArrowRight", Key::KEY_RIGHT),
+    ("ArrowUp", Key::KEY_UP),
+    // Numpad section
+    ("NumLock", Key::KEY_NUMLOCK),
+    ("Numpad0", Key::KEY_KP0),
+    ("Numpad1", Key::KEY_KP1),
+    ("Numpad2", Key::KEY_KP2),
+    ("Numpad3", Key::KEY_KP3),
+    ("Numpad4", Key::KEY_KP4),
+    ("Numpad5", Key::KEY_KP5),
+    ("Numpad6", Key::KEY_KP6),
+    ("Numpad7", Key::KEY_KP7),
+    ("Numpad8", Key::KEY_KP8),
+    ("Numpad9", Key::KEY_KP9),
+    ("NumpadAdd", Key::KEY_KPPLUS),
+    ("NumpadComma", Key::KEY_KPCOMMA),
+    ("NumpadDecimal", Key::KEY_KPDOT),
+    ("NumpadDivide", Key::KEY_KPSLASH),
+    ("NumpadEnter", Key::KEY_KPENTER),
+    ("NumpadEqual", Key::KEY_KPEQUAL),
+    ("NumpadMultiply", Key::KEY_KPASTERISK),
+    ("NumpadParenLeft", Key::KEY_KPLEFTPAREN),
+    ("NumpadParenRight", Key::KEY_KPRIGHTPAREN),
+    ("NumpadSubtract", Key::KEY_KPMINUS),
+    // Function section
+    ("Escape", Key::KEY_ESC),
+    ("F1", Key::KEY_F1),
+    ("F2", Key::KEY_F2),
+    ("F3", Key::KEY_F3),
+    ("F4", Key::KEY_F4),
+    ("F5", Key::KEY_F5),
+    ("F6", Key::KEY_F6),
+    ("F7", Key::KEY_F7),
+    ("F8", Key::KEY_F8),
+    ("F9", Key::KEY_F9),
+    ("F10", Key::KEY_F10),
+    ("F11", Key::KEY_F11),
+    ("F12", Key::KEY_F12),
+    ("F13", Key::KEY_F13),
+    ("F14", Key::KEY_F14),
+    ("F15", Key::KEY_F15),
+    ("F16", Key::KEY_F16),
+    ("F17", Key::KEY_F17),
+    ("F18", Key::KEY_F18),
+    ("F19", Key::KEY_F19),
+    ("F20", Key::KEY_F20),
+    ("F21", Key::KEY_F21),
+    ("F22", Key::KEY_F22),
+    ("F23", Key::KEY_F23),
+    ("F24", Key::KEY_F24),
+    ("Fn", Key::KEY_FN),
+    ("PrintScreen", Key::KEY_SYSRQ),
+    ("ScrollLock", Key::KEY_SCROLLLOCK),
+    ("Pause", Key::KEY_PAUSE),
+    // Media keys
+    ("BrowserBack", Key::KEY_BACK),
+    ("BrowserFavorites", Key::KEY_BOOKMARKS),
+    ("BrowserForward", Key::KEY_FORWARD),
+    ("BrowserHome", Key::KEY_HOMEPAGE),
+    ("BrowserRefresh", Key::KEY_REFRESH),
+    ("BrowserSearch", Key::KEY_SEARCH),
+    ("BrowserStop", Key::KEY_STOP),
+    ("Eject", Key::KEY_EJECTCD),
+    ("LaunchApp1", Key::KEY_COMPUTER),
+    ("LaunchApp2", Key::KEY_CALC),
+    ("LaunchMail", Key::KEY_MAIL),
+    ("MediaPlayPause", Key::KEY_PLAYPAUSE),
+    ("MediaSelect", Key::KEY_MEDIA),
+    ("MediaStop", Key::KEY_STOPCD),
+    ("MediaTrackNext", Key::KEY_NEXTSONG),
+    ("MediaTrackPrevious", Key::KEY_PREVIOUSSONG),
+    ("Power", Key::KEY_POWER),
+    ("Sleep", Key::KEY_SLEEP),
+    ("AudioVolumeDown", Key::KEY_VOLUMEDOWN),
+    ("AudioVolumeMute", Key::KEY_MUTE),
+    ("AudioVolumeUp", Key::KEY_VOLUMEUP),
+    ("WakeUp", Key::KEY_WAKEUP),
+    // Legacy keys
+    ("Again", Key::KEY_AGAIN),
+    ("Copy", Key::KEY_COPY),
+    ("Cut", Key::KEY_CUT),
+    ("Find", Key::KEY_FIND),
+    ("Open", Key::KEY_OPEN),
+    ("Paste", Key::KEY_PASTE),
+    ("Props", Key::KEY_PROPS),
+    ("Select", Key::KEY_SELECT),
+    ("Undo", Key::KEY_UNDO),
+    // Names used by older versions of Firefox
+    ("OSLeft", Key::KEY_LEFTMETA),
+    ("OSRight", Key::KEY_RIGHTMETA),
+    ("VolumeDown", Key::KEY_VOLUMEDOWN),
+    ("VolumeMute", Key::KEY_MUTE),
+    ("VolumeUp", Key::KEY_VOLUMEUP),
+];
+
 impl KeyEvent {
+    /// Maps a `KeyboardEvent.code` to its key, using [JS_KEY_CODES]
     pub fn from_js_key_name_with_direction(key: &str, direction: KeyDirection) -> Result<Self> {
-        let key = match key {
-            "KeyA" => Key::KEY_A,
-            "KeyB" => Key::KEY_B,
-            "KeyC" => Key::KEY_C,
-            "KeyD" => Key::KEY_D,
-            "KeyE" => Key::KEY_E,
-            "KeyF" => Key::KEY_F,
-            "KeyG" => Key::KEY_G,
-            "KeyH" => Key::KEY_H,
-            "KeyI" => Key::KEY_I,
-            "KeyJ" => Key::KEY_J,
-            "KeyK" => Key::KEY_K,
-            "KeyL" => Key::KEY_L,
-            "KeyM" => Key::KEY_M,
-            "KeyN" => Key::KEY_N,
-            "KeyO" => Key::KEY_O,
-            "KeyP" => Key::KEY_P,
-            "KeyQ" => Key::KEY_Q,
-            "KeyR" => Key::KEY_R,
-            "KeyS" => Key::KEY_S,
-            "KeyT" => Key::KEY_T,
-            "KeyU" => Key::KEY_U,
-            "KeyV" => Key::KEY_V,
-            "KeyW" => Key::KEY_W,
-            "KeyX" => Key::KEY_X,
-            "KeyY" => Key::KEY_Y,
-            "KeyZ" => Key::KEY_Z,
-            "CapsLock" => Key::KEY_CAPSLOCK,
-            "Escape" => Key::KEY_ESC,
-            "Backquote" => Key::KEY_GRAVE,
-            "KEY0" => Key::KEY_0,
-            "KEY1" => Key::KEY_1,
-            "KEY2" => Key::KEY_2,
-            "KEY3" => Key::KEY_3,
-            "KEY4" => Key::KEY_4,
-            "KEY5" => Key::KEY_5,
-            "KEY6" => Key::KEY_6,
-            "KEY7" => Key::KEY_7,
-            "KEY8" => Key::KEY_8,
-            "KEY9" => Key::KEY_9,
-            "Minus" => Key::KEY_MINUS,
-            "Equal" => Key::KEY_EQUAL,
-            "Backspace" => Key::KEY_BACKSPACE,
-            "BracketLeft" => Key::KEY_LEFTBRACE,
-            "BracketRight" => Key::KEY_RIGHTBRACE,
-            "Backslash" => Key::KEY_BACKSLASH,
-            "Tab" => Key::KEY_TAB,
-            "ShiftLeft" => Key::KEY_LEFTSHIFT,
-            "ControlLeft" => Key::KEY_LEFTCTRL,
-            "AltLeft" => Key::KEY_LEFTALT,
-            "Space" => Key::KEY_SPACE,
-            "AltRight" => Key::KEY_RIGHTALT,
-            "ControlRight" => Key::KEY_RIGHTCTRL,
-            "ShiftRight" => Key::KEY_RIGHTSHIFT,
-            "Enter" => Key::KEY_ENTER,
-            k => return Err(InputManagerError::UnknownKey(k.to_string()).into()),
-        };
+        let key = JS_KEY_CODES
+            .iter()
+            .find(|(code, _)| *code == key)
+            .map(|(_, key)| *key)
+            .ok_or_else(|| InputManagerError::UnknownKey(key.to_string()))?;
         Ok(Self { key, direction })
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Hi-res wheel units making up one notch of a regular mouse wheel
+pub const WHEEL_NOTCH: i32 = 120;
+
+/// The unit of the deltas of a browser `WheelEvent`, see `WheelEvent.deltaMode`
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaMode {
+    Pixel = 0,
+    Line = 1,
+    Page = 2,
+}
+
+impl DeltaMode {
+    /// How many deltas of this unit browsers scroll per notch
+    fn per_notch(self) -> f64 {
+        match self {
+            Self::Pixel => 100.0,
+            Self::Line => 3.0,
+            // A page is taken to be ten notches
+            Self::Page => 0.1,
+        }
+    }
+}
+
+/// Relative pointer motion and scrolling
+///
+/// `wheel` and `hwheel` are in hi-res units of [WHEEL_NOTCH] per notch, positive values scroll
+/// up and right respectively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MouseMoveEvent {
     pub x: i32,
     pub y: i32,
     pub wheel: i32,
-    _padding: i32,
+    pub hwheel: i32,
 }
 
 impl MouseMoveEvent {
+    pub fn new(x: i32, y: i32, wheel: i32, hwheel: i32) -> Self {
+        Self {
+            x,
+            y,
+            wheel,
+            hwheel,
+        }
+    }
+
+    /// Converts the deltas of a browser `WheelEvent` into a scroll, flipping `delta_y` since
+    /// browsers count downwards
+    pub fn from_wheel_delta(delta_x: f64, delta_y: f64, mode: DeltaMode) -> Self {
+        let to_hi_res = |delta: f64| (delta * WHEEL_NOTCH as f64 / mode.per_notch()).round() as i32;
+        Self::new(0, 0, to_hi_res(-delta_y), to_hi_res(delta_x))
+    }
+
+    /// The motion and hi-res wheel events, see [WheelAccumulator] for the legacy wheel events
     pub fn get_input_events(&self) -> Vec<InputEvent> {
         let mut out = Vec::new();
         if self.x != 0 {
@@ -135,16 +319,70 @@ impl MouseMoveEvent {
         if self.wheel != 0 {
             out.push(InputEvent::new(
                 EventType::RELATIVE,
-                RelativeAxisType::REL_HWHEEL_HI_RES.0,
+                RelativeAxisType::REL_WHEEL_HI_RES.0,
                 self.wheel,
             ))
         }
 
+        if self.hwheel != 0 {
+            out.push(InputEvent::new(
+                EventType::RELATIVE,
+                RelativeAxisType::REL_HWHEEL_HI_RES.0,
+                self.hwheel,
+            ))
+        }
+
+        out
+    }
+}
+
+/// Collects hi-res scrolling into whole notches for applications only reading the legacy
+/// `REL_WHEEL` and `REL_HWHEEL` axes, like a physical hi-res mouse does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WheelAccumulator {
+    wheel: i32,
+    hwheel: i32,
+}
+
+impl WheelAccumulator {
+    /// Adds the scrolling of `event`, returning an event for every axis that completed a notch
+    pub fn legacy_events(&mut self, event: &MouseMoveEvent) -> Vec<InputEvent> {
+        fn accumulate(remainder: &mut i32, delta: i32) -> i32 {
+            // A change of direction throws away what was scrolled the other way
+            if remainder.signum() * delta.signum() < 0 {
+                *remainder = 0;
+            }
+            *remainder = remainder.saturating_add(delta);
+            let notches = *remainder / WHEEL_NOTCH;
+            *remainder -= notches * WHEEL_NOTCH;
+            notches
+        }
+
+        let mut out = Vec::new();
+
+        let notches = accumulate(&mut self.wheel, event.wheel);
+        if notches != 0 {
+            out.push(InputEvent::new(
+                EventType::RELATIVE,
+                RelativeAxisType::REL_WHEEL.0,
+                notches,
+            ));
+        }
+
+        let notches = accumulate(&mut self.hwheel, event.hwheel);
+        if notches != 0 {
+            out.push(InputEvent::new(
+                EventType::RELATIVE,
+                RelativeAxisType::REL_HWHEEL.0,
+                notches,
+            ));
+        }
+
         out
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MouseButtonEvent {
     pub key: Key,
     pub direction: KeyDirection,
@@ -156,123 +394,703 @@ impl From<MouseButtonEvent> for InputEvent {
     }
 }
 
+/// A pointer position in the coordinates of the whole [DesktopLayout]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbsolutePointerEvent {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl AbsolutePointerEvent {
+    pub fn get_input_events(&self) -> [InputEvent; 2] {
+        [
+            InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, self.x),
+            InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, self.y),
+        ]
+    }
+}
+
+/// A desktop's rectangle in the compositor's layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DesktopRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// The compositor's layout of every captured desktop
+///
+/// The absolute pointer device spans the bounding box of all desktops, with its origin in the
+/// box's top left corner. Desktops the portal reported no position for are placed at `(0, 0)`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DesktopLayout {
+    desktops: HashMap<u64, DesktopRect>,
+    origin: (i32, i32),
+    size: (i32, i32),
+}
+
+impl DesktopLayout {
+    pub fn new(desktops: &[Desktop]) -> Self {
+        Self::from_rects(desktops.iter().map(|d| {
+            let (x, y) = d.position.unwrap_or((0, 0));
+            (
+                d.loded_id,
+                DesktopRect {
+                    x,
+                    y,
+                    width: d.width,
+                    height: d.height,
+                },
+            )
+        }))
+    }
+
+    pub fn from_rects(desktops: impl IntoIterator<Item = (u64, DesktopRect)>) -> Self {
+        let desktops = desktops.into_iter().collect::<HashMap<u64, DesktopRect>>();
+
+        let bounds = desktops.values().fold(None, |bounds, rect| {
+            let (left, top, right, bottom) =
+                bounds.unwrap_or((rect.x, rect.y, rect.x + rect.width, rect.y + rect.height));
+            Some((
+                left.min(rect.x),
+                top.min(rect.y),
+                right.max(rect.x + rect.width),
+                bottom.max(rect.y + rect.height),
+            ))
+        });
+        let (origin, size) = match bounds {
+            Some((left, top, right, bottom)) => ((left, top), (right - left, bottom - top)),
+            None => ((0, 0), (0, 0)),
+        };
+
+        Self {
+            desktops,
+            origin,
+            size,
+        }
+    }
+
+    /// The size of the bounding box of all desktops in the format `(width, height)`
+    pub fn size(&self) -> (i32, i32) {
+        self.size
+    }
+
+    /// Translates a position on a desktop into the layout, clamping it to the desktop
+    pub fn to_absolute(&self, loded_id: u64, x: i32, y: i32) -> Option<AbsolutePointerEvent> {
+        let rect = self.desktops.get(&loded_id)?;
+        Some(AbsolutePointerEvent {
+            x: rect.x + x.clamp(0, rect.width - 1) - self.origin.0,
+            y: rect.y + y.clamp(0, rect.height - 1) - self.origin.1,
+        })
+    }
+
+    /// Finds the desktop under a position in the layout, returning its loded id and the
+    /// position on it
+    ///
+    /// Where desktops overlap the one with the lowest id wins.
+    pub fn to_desktop(&self, x: i32, y: i32) -> Option<(u64, i32, i32)> {
+        let (x, y) = (x + self.origin.0, y + self.origin.1);
+        self.desktops
+            .iter()
+            .filter(|(_, rect)| {
+                (rect.x..rect.x + rect.width).contains(&x)
+                    && (rect.y..rect.y + rect.height).contains(&y)
+            })
+            .min_by_key(|(loded_id, _)| **loded_id)
+            .map(|(loded_id, rect)| (*loded_id, x - rect.x, y - rect.y))
+    }
+}
+
+/// The most fingers the virtual touchscreen tracks at once
+pub const MAX_TOUCH_CONTACTS: usize = 10;
+
+/// A finger on the virtual touchscreen, positioned in the desktop layout
+///
+/// `contact` is picked by the client and identifies a finger from `Down` until `Up`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TouchEvent {
+    Down {
+        contact: u32,
+        position: AbsolutePointerEvent,
+    },
+    Move {
+        contact: u32,
+        position: AbsolutePointerEvent,
+    },
+    Up {
+        contact: u32,
+    },
+}
+
+/// Assigns the clients' touch contacts to multitouch slots
+///
+/// Every finger put down gets a free slot and a new tracking ID for as long as it touches the
+/// screen. The first slot is mirrored onto `ABS_X`/`ABS_Y` and `BTN_TOUCH` for applications
+/// that only understand single touch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TouchSlots {
+    /// The client and contact in each slot
+    contacts: [Option<(u64, u32)>; MAX_TOUCH_CONTACTS],
+    next_tracking_id: i32,
+}
+
+impl TouchSlots {
+    /// The largest tracking ID before they wrap around
+    pub const MAX_TRACKING_ID: i32 = u16::MAX as i32;
+
+    fn slot_of(&self, client: u64, contact: u32) -> Option<usize> {
+        self.contacts
+            .iter()
+            .position(|c| *c == Some((client, contact)))
+    }
+
+    fn is_primary(&self, slot: usize) -> bool {
+        self.contacts[..slot].iter().all(Option::is_none)
+    }
+
+    fn active(&self) -> usize {
+        self.contacts.iter().flatten().count()
+    }
+
+    fn slot_events(slot: usize, tracking_id: Option<i32>) -> Vec<InputEvent> {
+        let mut out = vec![InputEvent::new(
+            EventType::ABSOLUTE,
+            AbsoluteAxisType::ABS_MT_SLOT.0,
+            slot as i32,
+        )];
+        if let Some(tracking_id) = tracking_id {
+            out.push(InputEvent::new(
+                EventType::ABSOLUTE,
+                AbsoluteAxisType::ABS_MT_TRACKING_ID.0,
+                tracking_id,
+            ));
+        }
+        out
+    }
+
+    fn position_events(&self, slot: usize, position: &AbsolutePointerEvent) -> Vec<InputEvent> {
+        let mut out = vec![
+            InputEvent::new(
+                EventType::ABSOLUTE,
+                AbsoluteAxisType::ABS_MT_POSITION_X.0,
+                position.x,
+            ),
+            InputEvent::new(
+                EventType::ABSOLUTE,
+                AbsoluteAxisType::ABS_MT_POSITION_Y.0,
+                position.y,
+            ),
+        ];
+        if self.is_primary(slot) {
+            out.extend(position.get_input_events());
+        }
+        out
+    }
+
+    /// Applies `event`, returning what the touchscreen has to emit for it
+    ///
+    /// Contacts put down while every slot is taken, and moves or lifts of unknown contacts,
+    /// are ignored.
+    pub fn events(&mut self, client: u64, event: &TouchEvent) -> Vec<InputEvent> {
+        match *event {
+            TouchEvent::Down { contact, position } => {
+                if let Some(slot) = self.slot_of(client, contact) {
+                    let mut out = Self::slot_events(slot, None);
+                    out.extend(self.position_events(slot, &position));
+                    return out;
+                }
+                let Some(slot) = self.contacts.iter().position(Option::is_none) else {
+                    warn!("Dropping touch contact {contact}, every slot is taken");
+                    return Vec::new();
+                };
+
+                let first = self.active() == 0;
+                self.contacts[slot] = Some((client, contact));
+                let tracking_id = self.next_tracking_id;
+                self.next_tracking_id = (tracking_id + 1) % (Self::MAX_TRACKING_ID + 1);
+
+                let mut out = Self::slot_events(slot, Some(tracking_id));
+                out.extend(self.position_events(slot, &position));
+                if first {
+                    out.push(InputEvent::new(EventType::KEY, Key::BTN_TOUCH.code(), 1));
+                }
+                out
+            }
+            TouchEvent::Move { contact, position } => {
+                let Some(slot) = self.slot_of(client, contact) else {
+                    return Vec::new();
+                };
+                let mut out = Self::slot_events(slot, None);
+                out.extend(self.position_events(slot, &position));
+                out
+            }
+            TouchEvent::Up { contact } => {
+                let Some(slot) = self.slot_of(client, contact) else {
+                    return Vec::new();
+                };
+                self.contacts[slot] = None;
+
+                let mut out = Self::slot_events(slot, Some(-1));
+                if self.active() == 0 {
+                    out.push(InputEvent::new(EventType::KEY, Key::BTN_TOUCH.code(), 0));
+                }
+                out
+            }
+        }
+    }
+
+    /// Lifts every finger `client` still has on the screen
+    pub fn release(&mut self, client: u64) -> Vec<InputEvent> {
+        let contacts = self
+            .contacts
+            .iter()
+            .flatten()
+            .filter(|(owner, _)| *owner == client)
+            .map(|(_, contact)| *contact)
+            .collect::<Vec<u32>>();
+        contacts
+            .into_iter()
+            .flat_map(|contact| self.events(client, &TouchEvent::Up { contact }))
+            .collect()
+    }
+
+    /// Every client with a finger on the screen
+    fn clients(&self) -> impl Iterator<Item = u64> + '_ {
+        self.contacts.iter().flatten().map(|(client, _)| *client)
+    }
+}
+
+/// The state of a pen's buttons and tip
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PenButtons(pub u8);
+
+impl PenButtons {
+    pub const NONE: Self = Self(0);
+    /// The pen is close enough to the tablet to be tracked
+    pub const IN_RANGE: Self = Self(1 << 0);
+    /// The tip touches the tablet
+    pub const TIP: Self = Self(1 << 1);
+    /// The first barrel button is held
+    pub const BARREL: Self = Self(1 << 2);
+    /// The second barrel button is held
+    pub const SECONDARY: Self = Self(1 << 3);
+    /// The pen is flipped around to its eraser
+    pub const ERASER: Self = Self(1 << 4);
+
+    /// Every defined bit
+    pub const ALL: Self =
+        Self(Self::IN_RANGE.0 | Self::TIP.0 | Self::BARREL.0 | Self::SECONDARY.0 | Self::ERASER.0);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Which end of the pen is in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PenTool {
+    Pen,
+    Eraser,
+}
+
+impl PenTool {
+    fn key(self) -> Key {
+        match self {
+            Self::Pen => Key::BTN_TOOL_PEN,
+            Self::Eraser => Key::BTN_TOOL_RUBBER,
+        }
+    }
+}
+
+/// A pen sample, positioned in the desktop layout
+///
+/// `pressure` spans `0..=u16::MAX`, a browser's `PointerEvent.pressure` scaled up, while the
+/// tilts are `PointerEvent.tiltX` and `tiltY` in degrees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PenEvent {
+    pub position: AbsolutePointerEvent,
+    pub pressure: u16,
+    pub tilt_x: i8,
+    pub tilt_y: i8,
+    pub buttons: PenButtons,
+}
+
+impl PenEvent {
+    /// The largest tilt in either direction, in degrees
+    pub const MAX_TILT: i8 = 90;
+}
+
+/// Tracks which tool of the pen is in proximity, and which client holds it
+///
+/// Applications need a tool to leave proximity in its own frame before another one enters, so
+/// flipping the pen around emits a `SYN_REPORT` in the middle of the events.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PenState {
+    tool: Option<PenTool>,
+    owner: Option<u64>,
+}
+
+impl PenState {
+    /// Applies `event`, returning what the pen device has to emit for it
+    pub fn events(&mut self, client: u64, event: &PenEvent) -> Vec<InputEvent> {
+        let in_range = event.buttons.contains(PenButtons::IN_RANGE);
+        self.owner = in_range.then_some(client);
+        let tool = if event.buttons.contains(PenButtons::ERASER) {
+            PenTool::Eraser
+        } else {
+            PenTool::Pen
+        };
+
+        let mut out = Vec::new();
+        if let Some(current) = self.tool.filter(|current| !in_range || *current != tool) {
+            out.extend([
+                InputEvent::new(EventType::KEY, Key::BTN_TOUCH.code(), 0),
+                InputEvent::new(EventType::KEY, Key::BTN_STYLUS.code(), 0),
+                InputEvent::new(EventType::KEY, Key::BTN_STYLUS2.code(), 0),
+                InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_PRESSURE.0, 0),
+                InputEvent::new(EventType::KEY, current.key().code(), 0),
+            ]);
+            self.tool = None;
+            if in_range {
+                out.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+            }
+        }
+        if !in_range {
+            return out;
+        }
+
+        let touching = event.buttons.contains(PenButtons::TIP);
+        out.extend(event.position.get_input_events());
+        out.extend([
+            InputEvent::new(
+                EventType::ABSOLUTE,
+                AbsoluteAxisType::ABS_TILT_X.0,
+                event.tilt_x.into(),
+            ),
+            InputEvent::new(
+                EventType::ABSOLUTE,
+                AbsoluteAxisType::ABS_TILT_Y.0,
+                event.tilt_y.into(),
+            ),
+            InputEvent::new(
+                EventType::ABSOLUTE,
+                AbsoluteAxisType::ABS_PRESSURE.0,
+                if touching { event.pressure.into() } else { 0 },
+            ),
+        ]);
+        if self.tool.is_none() {
+            out.push(InputEvent::new(EventType::KEY, tool.key().code(), 1));
+            self.tool = Some(tool);
+        }
+        out.extend([
+            InputEvent::new(EventType::KEY, Key::BTN_TOUCH.code(), touching.into()),
+            InputEvent::new(
+                EventType::KEY,
+                Key::BTN_STYLUS.code(),
+                event.buttons.contains(PenButtons::BARREL).into(),
+            ),
+            InputEvent::new(
+                EventType::KEY,
+                Key::BTN_STYLUS2.code(),
+                event.buttons.contains(PenButtons::SECONDARY).into(),
+            ),
+        ]);
+        out
+    }
+
+    /// Takes the pen out of proximity if `client` was the last to use it
+    pub fn release(&mut self, client: u64) -> Vec<InputEvent> {
+        if self.owner != Some(client) {
+            return Vec::new();
+        }
+        self.events(
+            client,
+            &PenEvent {
+                position: AbsolutePointerEvent { x: 0, y: 0 },
+                pressure: 0,
+                tilt_x: 0,
+                tilt_y: 0,
+                buttons: PenButtons::NONE,
+            },
+        )
+    }
+}
+
+/// The most gamepads a single client can connect
+pub const MAX_GAMEPADS: u32 = 4;
+
+/// Pressed gamepad buttons, one bit per button of the Gamepad API's standard mapping
+///
+/// The triggers' bits are left out, [GamepadState] carries their analog values instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct GamepadButtons(pub u32);
+
+impl GamepadButtons {
+    pub const NONE: Self = Self(0);
+    pub const A: Self = Self(1 << 0);
+    pub const B: Self = Self(1 << 1);
+    pub const X: Self = Self(1 << 2);
+    pub const Y: Self = Self(1 << 3);
+    pub const LEFT_BUMPER: Self = Self(1 << 4);
+    pub const RIGHT_BUMPER: Self = Self(1 << 5);
+    pub const BACK: Self = Self(1 << 8);
+    pub const START: Self = Self(1 << 9);
+    pub const LEFT_STICK: Self = Self(1 << 10);
+    pub const RIGHT_STICK: Self = Self(1 << 11);
+    pub const DPAD_UP: Self = Self(1 << 12);
+    pub const DPAD_DOWN: Self = Self(1 << 13);
+    pub const DPAD_LEFT: Self = Self(1 << 14);
+    pub const DPAD_RIGHT: Self = Self(1 << 15);
+    pub const GUIDE: Self = Self(1 << 16);
+
+    /// Every button with a key of its own, in the order of [GAMEPAD_KEYS]
+    const KEYS: [Self; 11] = [
+        Self::A,
+        Self::B,
+        Self::X,
+        Self::Y,
+        Self::LEFT_BUMPER,
+        Self::RIGHT_BUMPER,
+        Self::BACK,
+        Self::START,
+        Self::GUIDE,
+        Self::LEFT_STICK,
+        Self::RIGHT_STICK,
+    ];
+
+    /// Every defined bit, which is all from 0 to 16 but the triggers
+    pub const ALL: Self = Self(0x1ff3f);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// The keys of the virtual gamepad, laid out like the kernel's xpad driver reports an Xbox
+/// controller so games and SDL pick the right mapping
+pub(crate) const GAMEPAD_KEYS: [Key; 11] = [
+    Key::BTN_SOUTH,
+    Key::BTN_EAST,
+    Key::BTN_NORTH,
+    Key::BTN_WEST,
+    Key::BTN_TL,
+    Key::BTN_TR,
+    Key::BTN_SELECT,
+    Key::BTN_START,
+    Key::BTN_MODE,
+    Key::BTN_THUMBL,
+    Key::BTN_THUMBR,
+];
+
+/// The buttons of the virtual mouse, `BTN_LEFT` through `BTN_TASK`, which are the only ones
+/// clients may press on it
+pub(crate) const MOUSE_BUTTONS: [Key; 8] = [
+    Key::BTN_LEFT,
+    Key::BTN_RIGHT,
+    Key::BTN_MIDDLE,
+    Key::BTN_SIDE,
+    Key::BTN_EXTRA,
+    Key::BTN_FORWARD,
+    Key::BTN_BACK,
+    Key::BTN_TASK,
+];
+
+/// A snapshot of a gamepad, as read from the Gamepad API
+///
+/// The sticks are the standard mapping's axes scaled to the full `i16` range, with down and
+/// right positive. The triggers are the analog values of buttons 6 and 7 scaled to `u8`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GamepadState {
+    pub buttons: GamepadButtons,
+    pub left_x: i16,
+    pub left_y: i16,
+    pub right_x: i16,
+    pub right_y: i16,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+}
+
+impl GamepadState {
+    /// The whole state, the kernel drops what didn't change since the last report
+    pub fn get_input_events(&self) -> Vec<InputEvent> {
+        let hat = |negative: GamepadButtons, positive: GamepadButtons| {
+            self.buttons.contains(positive) as i32 - self.buttons.contains(negative) as i32
+        };
+
+        let mut out = GamepadButtons::KEYS
+            .iter()
+            .zip(GAMEPAD_KEYS)
+            .map(|(button, key)| {
+                InputEvent::new(
+                    EventType::KEY,
+                    key.code(),
+                    self.buttons.contains(*button).into(),
+                )
+            })
+            .collect::<Vec<InputEvent>>();
+        out.extend(
+            [
+                (AbsoluteAxisType::ABS_X, self.left_x.into()),
+                (AbsoluteAxisType::ABS_Y, self.left_y.into()),
+                (AbsoluteAxisType::ABS_RX, self.right_x.into()),
+                (AbsoluteAxisType::ABS_RY, self.right_y.into()),
+                (AbsoluteAxisType::ABS_Z, self.left_trigger.into()),
+                (AbsoluteAxisType::ABS_RZ, self.right_trigger.into()),
+                (
+                    AbsoluteAxisType::ABS_HAT0X,
+                    hat(GamepadButtons::DPAD_LEFT, GamepadButtons::DPAD_RIGHT),
+                ),
+                (
+                    AbsoluteAxisType::ABS_HAT0Y,
+                    hat(GamepadButtons::DPAD_UP, GamepadButtons::DPAD_DOWN),
+                ),
+            ]
+            .into_iter()
+            .map(|(axis, value)| InputEvent::new(EventType::ABSOLUTE, axis.0, value)),
+        );
+        out
+    }
+}
+
+/// A client's gamepad changing, `state` is `None` once it's unplugged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GamepadEvent {
+    /// The Gamepad API's index of the gamepad, below [MAX_GAMEPADS]
+    pub pad: u32,
+    pub state: Option<GamepadState>,
+}
+
+/// The keys that turn typing into shortcuts or other characters while they're held
+const MODIFIER_KEYS: [Key; 8] = [
+    Key::KEY_LEFTCTRL,
+    Key::KEY_RIGHTCTRL,
+    Key::KEY_LEFTSHIFT,
+    Key::KEY_RIGHTSHIFT,
+    Key::KEY_LEFTALT,
+    Key::KEY_RIGHTALT,
+    Key::KEY_LEFTMETA,
+    Key::KEY_RIGHTMETA,
+];
+
+/// The keys or buttons every client holds down
+///
+/// Clients can vanish at any moment, and whatever they held would stay pressed on the shared
+/// virtual devices. This remembers them so they can be let go of.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeldKeys {
+    held: HashMap<u64, HashSet<Key>>,
+}
+
+impl HeldKeys {
+    pub fn update(&mut self, client: u64, key: Key, direction: KeyDirection) {
+        match direction {
+            KeyDirection::Down | KeyDirection::RepeatingDown => {
+                self.held.entry(client).or_default().insert(key);
+            }
+            KeyDirection::Up => {
+                if let Some(keys) = self.held.get_mut(&client) {
+                    keys.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Forgets everything `client` holds, returning the releases of the keys no other client
+    /// holds too
+    pub fn release(&mut self, client: u64) -> Vec<InputEvent> {
+        let Some(keys) = self.held.remove(&client) else {
+            return Vec::new();
+        };
+        let mut keys = keys
+            .into_iter()
+            .filter(|key| !self.held.values().any(|held| held.contains(key)))
+            .collect::<Vec<Key>>();
+        keys.sort_by_key(|key| key.code());
+        keys.into_iter()
+            .map(|key| InputEvent::new(EventType::KEY, key.code(), KeyDirection::Up as i32))
+            .collect()
+    }
+
+    /// The modifiers held by any client, in the order of their codes
+    fn modifiers(&self) -> Vec<Key> {
+        let mut modifiers = MODIFIER_KEYS
+            .into_iter()
+            .filter(|key| self.held.values().any(|held| held.contains(key)))
+            .collect::<Vec<Key>>();
+        modifiers.sort_by_key(|key| key.code());
+        modifiers
+    }
+
+    /// Every client currently holding something
+    fn clients(&self) -> impl Iterator<Item = u64> + '_ {
+        self.held.keys().copied()
+    }
+}
+
 #[derive(Debug)]
 pub enum InputManagerEvent {
     Keyboard(Vec<KeyEvent>),
     Mouse(Option<Vec<MouseMoveEvent>>, Option<Vec<MouseButtonEvent>>),
+    Absolute(Vec<AbsolutePointerEvent>),
+    Touch(Vec<TouchEvent>),
+    Pen(Vec<PenEvent>),
+    Gamepad(GamepadEvent),
+    /// Text to type on the host's keyboard layout
+    Text(String),
+    /// The client is gone and everything it held has to be released
+    Disconnected,
+}
+
+/// An [InputManagerEvent] along with the client it came from
+#[derive(Debug)]
+pub struct ClientEvent {
+    pub client: u64,
+    pub event: InputManagerEvent,
 }
 
 /// Struct that receives virtual key events and forwards them to the operating system
 pub struct InputManager {
-    keyboard: Mutex<VirtualDevice>,
-    mouse: Mutex<VirtualDevice>,
-    rx: Mutex<Option<Receiver<InputManagerEvent>>>,
+    backend: Mutex<Box<dyn InputBackend>>,
+    wheel: Mutex<WheelAccumulator>,
+    touches: Mutex<TouchSlots>,
+    pen_state: Mutex<PenState>,
+    /// The gamepads clients have plugged in, by client and gamepad index
+    gamepads: Mutex<HashSet<(u64, u32)>>,
+    held_keys: Mutex<HeldKeys>,
+    held_buttons: Mutex<HeldKeys>,
+    typer: TextTyper,
+    rx: Mutex<Option<Receiver<ClientEvent>>>,
     running: Mutex<Option<broadcast::Receiver<()>>>,
 }
 
 impl InputManager {
-    pub fn new(die_handle: broadcast::Receiver<()>) -> Result<(Self, Sender<InputManagerEvent>)> {
-        let mut keys = AttributeSet::<Key>::new();
-        keys.insert(Key::KEY_A);
-        keys.insert(Key::KEY_B);
-        keys.insert(Key::KEY_C);
-        keys.insert(Key::KEY_D);
-        keys.insert(Key::KEY_E);
-        keys.insert(Key::KEY_F);
-        keys.insert(Key::KEY_G);
-        keys.insert(Key::KEY_H);
-        keys.insert(Key::KEY_I);
-        keys.insert(Key::KEY_J);
-        keys.insert(Key::KEY_K);
-        keys.insert(Key::KEY_L);
-        keys.insert(Key::KEY_M);
-        keys.insert(Key::KEY_N);
-        keys.insert(Key::KEY_O);
-        keys.insert(Key::KEY_P);
-        keys.insert(Key::KEY_Q);
-        keys.insert(Key::KEY_R);
-        keys.insert(Key::KEY_S);
-        keys.insert(Key::KEY_T);
-        keys.insert(Key::KEY_U);
-        keys.insert(Key::KEY_V);
-        keys.insert(Key::KEY_W);
-        keys.insert(Key::KEY_X);
-        keys.insert(Key::KEY_Y);
-        keys.insert(Key::KEY_Z);
-        keys.insert(Key::KEY_CAPSLOCK);
-        keys.insert(Key::KEY_ESC);
-        keys.insert(Key::KEY_GRAVE);
-        keys.insert(Key::KEY_0);
-        keys.insert(Key::KEY_1);
-        keys.insert(Key::KEY_2);
-        keys.insert(Key::KEY_3);
-        keys.insert(Key::KEY_4);
-        keys.insert(Key::KEY_5);
-        keys.insert(Key::KEY_6);
-        keys.insert(Key::KEY_7);
-        keys.insert(Key::KEY_8);
-        keys.insert(Key::KEY_9);
-        keys.insert(Key::KEY_MINUS);
-        keys.insert(Key::KEY_EQUAL);
-        keys.insert(Key::KEY_BACKSPACE);
-        keys.insert(Key::KEY_LEFTBRACE);
-        keys.insert(Key::KEY_RIGHTBRACE);
-        keys.insert(Key::KEY_BACKSLASH);
-        keys.insert(Key::KEY_TAB);
-        keys.insert(Key::KEY_LEFTSHIFT);
-        keys.insert(Key::KEY_LEFTCTRL);
-        keys.insert(Key::KEY_LEFTALT);
-        keys.insert(Key::KEY_SPACE);
-        keys.insert(Key::KEY_RIGHTALT);
-        keys.insert(Key::KEY_RIGHTCTRL);
-        keys.insert(Key::KEY_RIGHTSHIFT);
-        keys.insert(Key::KEY_ENTER);
-
-        debug!("Made keys: {:#?}", keys);
-
-        let keyboard = VirtualDeviceBuilder::new()?
-            .name("rdesktopd Virtal Keyboard")
-            .with_keys(&keys)?
-            .build()?;
-
-        debug!("Made keyboard");
-
-        let mut axis = AttributeSet::<RelativeAxisType>::new();
-        axis.insert(RelativeAxisType::REL_X);
-        axis.insert(RelativeAxisType::REL_Y);
-        // axis.insert(RelativeAxisType::REL_Z);
-        axis.insert(RelativeAxisType::REL_WHEEL);
-
-        debug!("Made axis: {:#?}", axis);
-
-        let mut buttons = AttributeSet::<Key>::new();
-        buttons.insert(Key::BTN_LEFT);
-        buttons.insert(Key::BTN_RIGHT);
-        buttons.insert(Key::BTN_MIDDLE);
-
-        debug!("Made buttons: {:#?}", buttons);
-
-        let mouse = VirtualDeviceBuilder::new()?
-            .name("rdesktopd Virtual Mouse")
-            .with_relative_axes(&axis)?
-            .with_keys(&buttons)?
-            .build()?;
-
-        debug!("Made mouse");
-
+    pub fn new(
+        die_handle: broadcast::Receiver<()>,
+        backend: Box<dyn InputBackend>,
+        typer: TextTyper,
+    ) -> (Self, Sender<ClientEvent>) {
         let (tx, rx) = channel(100);
 
         info!("Intialized InputManager");
 
-        Ok((
+        (
             Self {
-                keyboard: Mutex::new(keyboard),
-                mouse: Mutex::new(mouse),
+                backend: Mutex::new(backend),
+                wheel: Mutex::new(WheelAccumulator::default()),
+                touches: Mutex::new(TouchSlots::default()),
+                pen_state: Mutex::new(PenState::default()),
+                gamepads: Mutex::new(HashSet::new()),
+                held_keys: Mutex::new(HeldKeys::default()),
+                held_buttons: Mutex::new(HeldKeys::default()),
+                typer,
                 rx: Mutex::new(Some(rx)),
                 running: Mutex::new(Some(die_handle)),
             },
             tx,
-        ))
+        )
     }
 
     pub async fn listen(&self) -> Result<()> {
@@ -290,74 +1108,378 @@ impl InputManager {
             .expect("Listen must not be called more than once");
 
         loop {
-            if ds.try_recv().is_ok() {
-                break;
+            let msg = tokio::select! {
+                _ = ds.recv() => break,
+                msg = rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+            };
+            self.handle(msg);
+        }
+
+        self.release_all()?;
+
+        Ok(())
+    }
+
+    /// Injects an event of a client, logging what couldn't be
+    pub fn handle(&self, msg: ClientEvent) {
+        let ClientEvent { client, event } = msg;
+
+        match event {
+            InputManagerEvent::Keyboard(key_evt) => {
+                match self.send_keyboard_events(client, key_evt.as_slice()) {
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to write keyboard events: {e}"),
+                }
             }
+            InputManagerEvent::Mouse(move_evt, button_evt) => {
+                let move_evts = if let Some(events) = move_evt.as_ref() {
+                    events.as_slice()
+                } else {
+                    &[]
+                };
 
-            if let Some(msg) = rx.recv().await {
-                match msg {
-                    InputManagerEvent::Keyboard(key_evt) => {
-                        match self.send_keyboard_events(key_evt.as_slice()) {
-                            Ok(_) => {}
-                            Err(e) => warn!("Failed to write keyboard events: {e}"),
-                        }
-                    }
-                    InputManagerEvent::Mouse(move_evt, button_evt) => {
-                        let move_evts = if let Some(events) = move_evt.as_ref() {
-                            events.as_slice()
-                        } else {
-                            &[]
-                        };
-
-                        let btn_evts = if let Some(events) = button_evt.as_ref() {
-                            events.as_slice()
-                        } else {
-                            &[]
-                        };
-
-                        match self.send_mouse_events(move_evts, btn_evts) {
-                            Ok(_) => {}
-                            Err(e) => warn!("Failed to write mouse events: {e}"),
-                        }
-                    }
+                let btn_evts = if let Some(events) = button_evt.as_ref() {
+                    events.as_slice()
+                } else {
+                    &[]
                 };
-            } else {
-                break;
+
+                match self.send_mouse_events(client, move_evts, btn_evts) {
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to write mouse events: {e}"),
+                }
+            }
+            InputManagerEvent::Absolute(abs_evt) => {
+                match self.send_absolute_events(abs_evt.as_slice()) {
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to write absolute pointer events: {e}"),
+                }
+            }
+            InputManagerEvent::Touch(touch_evt) => {
+                match self.send_touch_events(client, touch_evt.as_slice()) {
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to write touch events: {e}"),
+                }
+            }
+            InputManagerEvent::Pen(pen_evt) => {
+                match self.send_pen_events(client, pen_evt.as_slice()) {
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to write pen events: {e}"),
+                }
             }
+            InputManagerEvent::Gamepad(gamepad_evt) => {
+                match self.send_gamepad_event(client, &gamepad_evt) {
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to write gamepad events: {e}"),
+                }
+            }
+            InputManagerEvent::Text(text) => match self.send_text(&text) {
+                Ok(_) => {}
+                Err(e) => warn!("Failed to type text: {e}"),
+            },
+            InputManagerEvent::Disconnected => match self.release_client(client) {
+                Ok(_) => debug!("Released the input of client {client}"),
+                Err(e) => warn!("Failed to release the input of client {client}: {e}"),
+            },
         }
+    }
 
-        Ok(())
+    /// Sends `events` to `device` as one frame, unless there are none or the backend has no
+    /// such device
+    fn emit(&self, device: InputDevice, events: &[InputEvent]) -> std::io::Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let mut backend = self
+            .backend
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        if !backend.supports(device) {
+            debug!("Dropping {device:?} events, the input backend has no such device");
+            return Ok(());
+        }
+        backend.emit(device, events)
     }
 
-    pub fn send_keyboard_events(&self, key_event: &[KeyEvent]) -> std::io::Result<()> {
-        let events = key_event
-            .iter()
-            .copied()
-            .map(|i| i.into())
-            .collect::<Vec<InputEvent>>();
+    pub fn send_keyboard_events(&self, client: u64, key_event: &[KeyEvent]) -> std::io::Result<()> {
+        let mut held_keys = self
+            .held_keys
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        for evt in key_event {
+            held_keys.update(client, evt.key, evt.direction);
+        }
 
-        let mut keyboard = self
-            .keyboard
+        // A key pressed and released within one frame would be lost
+        let mut events = Vec::new();
+        for evt in key_event {
+            if !events.is_empty() {
+                events.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+            }
+            events.push((*evt).into());
+        }
+        self.emit(InputDevice::Keyboard, &events)
+    }
+
+    /// Types `text`, releasing every key it pressed before returning
+    ///
+    /// Modifiers clients hold are let go of while the text is typed, so it isn't taken as
+    /// shortcuts, and pressed again afterwards.
+    pub fn send_text(&self, text: &str) -> std::io::Result<()> {
+        let held_keys = self
+            .held_keys
             .lock()
-            .map_err(|_| std::io::Error::new(ErrorKind::Other, InputManagerError::PoisonedMutex))?;
-        keyboard.emit(&events)
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        let modifiers = held_keys.modifiers();
+        let key_events = self.typer.key_events(text);
+        if key_events.is_empty() {
+            return Ok(());
+        }
+
+        let lifted = modifiers.iter().map(|key| KeyEvent {
+            key: *key,
+            direction: KeyDirection::Up,
+        });
+        let restored = modifiers.iter().map(|key| KeyEvent {
+            key: *key,
+            direction: KeyDirection::Down,
+        });
+
+        // A key pressed and released within one frame would be lost
+        let mut events = Vec::new();
+        for evt in lifted.chain(key_events).chain(restored) {
+            if !events.is_empty() {
+                events.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+            }
+            events.push(evt.into());
+        }
+        self.emit(InputDevice::Keyboard, &events)
     }
 
     pub fn send_mouse_events(
         &self,
+        client: u64,
         move_event: &[MouseMoveEvent],
         click_events: &[MouseButtonEvent],
     ) -> std::io::Result<()> {
+        let mut held_buttons = self
+            .held_buttons
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        for evt in click_events {
+            held_buttons.update(client, evt.key, evt.direction);
+        }
+
+        let mut wheel = self
+            .wheel
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
         let mut events = move_event
             .iter()
-            .flat_map(|mme| mme.get_input_events())
+            .flat_map(|mme| {
+                let mut events = mme.get_input_events();
+                events.extend(wheel.legacy_events(mme));
+                events
+            })
+            .collect::<Vec<InputEvent>>();
+        // The same goes for clicks, only the first one shares its frame with the motion
+        for (i, evt) in click_events.iter().enumerate() {
+            if i > 0 {
+                events.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+            }
+            events.push((*evt).into());
+        }
+        self.emit(InputDevice::Mouse, &events)
+    }
+
+    pub fn send_absolute_events(&self, abs_events: &[AbsolutePointerEvent]) -> std::io::Result<()> {
+        let events = abs_events
+            .iter()
+            .flat_map(|evt| evt.get_input_events())
+            .collect::<Vec<InputEvent>>();
+        self.emit(InputDevice::Tablet, &events)
+    }
+
+    pub fn send_touch_events(
+        &self,
+        client: u64,
+        touch_events: &[TouchEvent],
+    ) -> std::io::Result<()> {
+        let mut touches = self
+            .touches
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        let events = touch_events
+            .iter()
+            .flat_map(|evt| touches.events(client, evt))
             .collect::<Vec<InputEvent>>();
-        events.extend(click_events.iter().copied().map(InputEvent::from));
+        self.emit(InputDevice::Touchscreen, &events)
+    }
 
-        let mut mouse = self
-            .mouse
+    pub fn send_pen_events(&self, client: u64, pen_events: &[PenEvent]) -> std::io::Result<()> {
+        let mut pen_state = self
+            .pen_state
             .lock()
-            .map_err(|_| std::io::Error::new(ErrorKind::Other, InputManagerError::PoisonedMutex))?;
-        mouse.emit(&events)
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        // Every sample gets its own frame so strokes keep all of their points
+        let mut events = Vec::new();
+        for evt in pen_events {
+            let frame = pen_state.events(client, evt);
+            if !frame.is_empty() && !events.is_empty() {
+                events.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+            }
+            events.extend(frame);
+        }
+        self.emit(InputDevice::Pen, &events)
+    }
+
+    pub fn send_gamepad_event(
+        &self,
+        client: u64,
+        gamepad_event: &GamepadEvent,
+    ) -> std::io::Result<()> {
+        let GamepadEvent { pad, state } = *gamepad_event;
+        let device = InputDevice::Gamepad { client, pad };
+        let mut gamepads = self
+            .gamepads
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        let mut backend = self
+            .backend
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+
+        let Some(state) = state else {
+            if gamepads.remove(&(client, pad)) {
+                backend.remove(device);
+            }
+            return Ok(());
+        };
+        if pad >= MAX_GAMEPADS {
+            warn!("Dropping gamepad {pad} of client {client}, only {MAX_GAMEPADS} are supported");
+            return Ok(());
+        }
+        if !backend.supports(device) {
+            debug!("Dropping gamepad {pad} of client {client}, the input backend has no gamepads");
+            return Ok(());
+        }
+
+        gamepads.insert((client, pad));
+        backend.emit(device, &state.get_input_events())
+    }
+
+    /// Lets go of every key, button, finger and pen `client` holds, and unplugs its gamepads
+    pub fn release_client(&self, client: u64) -> std::io::Result<()> {
+        let keys = self
+            .held_keys
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+            .release(client);
+        self.emit(InputDevice::Keyboard, &keys)?;
+
+        let buttons = self
+            .held_buttons
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+            .release(client);
+        self.emit(InputDevice::Mouse, &buttons)?;
+
+        let touches = self
+            .touches
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+            .release(client);
+        self.emit(InputDevice::Touchscreen, &touches)?;
+
+        let pen = self
+            .pen_state
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+            .release(client);
+        self.emit(InputDevice::Pen, &pen)?;
+
+        // Unplugging a gamepad releases everything held on it
+        let mut gamepads = self
+            .gamepads
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        let mut backend = self
+            .backend
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        gamepads.retain(|&(owner, pad)| {
+            if owner == client {
+                backend.remove(InputDevice::Gamepad { client, pad });
+            }
+            owner != client
+        });
+
+        Ok(())
+    }
+
+    /// Rebuilds the absolute devices to span `desktops`, for when the session was reopened
+    ///
+    /// Fingers and the pen are lifted along with the old devices.
+    pub fn relayout(&self, desktops: &[Desktop]) -> std::io::Result<()> {
+        let mut touches = self
+            .touches
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        let mut pen_state = self
+            .pen_state
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?;
+        self.backend
+            .lock()
+            .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+            .relayout(desktops)?;
+        *touches = TouchSlots::default();
+        *pen_state = PenState::default();
+        Ok(())
+    }
+
+    /// Releases the input of every client, for when loded shuts down
+    pub fn release_all(&self) -> std::io::Result<()> {
+        let mut clients = HashSet::new();
+        clients.extend(
+            self.held_keys
+                .lock()
+                .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+                .clients(),
+        );
+        clients.extend(
+            self.held_buttons
+                .lock()
+                .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+                .clients(),
+        );
+        clients.extend(
+            self.touches
+                .lock()
+                .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+                .clients(),
+        );
+        clients.extend(
+            self.pen_state
+                .lock()
+                .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+                .owner,
+        );
+        clients.extend(
+            self.gamepads
+                .lock()
+                .map_err(|_| std::io::Error::other(InputManagerError::PoisonedMutex))?
+                .iter()
+                .map(|(client, _)| *client),
+        );
+
+        for client in clients {
+            self.release_client(client)?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+use evdev::Key;
+use log::{debug, warn};
+use xkbcommon::xkb;
+use zbus::{dbus_proxy, fdo::Result};
+
+use crate::input::{KeyDirection, KeyEvent, JS_KEY_CODES};
+
+/// systemd-localed, which knows the keyboard layout configured for the machine
+#[dbus_proxy(
+    interface = "org.freedesktop.locale1",
+    default_service = "org.freedesktop.locale1",
+    default_path = "/org/freedesktop/locale1"
+)]
+trait Locale1 {
+    #[dbus_proxy(property, name = "X11Layout")]
+    fn x11_layout(&self) -> Result<String>;
+
+    #[dbus_proxy(property, name = "X11Model")]
+    fn x11_model(&self) -> Result<String>;
+
+    #[dbus_proxy(property, name = "X11Variant")]
+    fn x11_variant(&self) -> Result<String>;
+
+    #[dbus_proxy(property, name = "X11Options")]
+    fn x11_options(&self) -> Result<String>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeymapError {
+    #[error("xkbcommon could not compile the keymap for layout {0:?}")]
+    Compile(String),
+}
+
+/// The XKB names of a keyboard layout, empty ones fall back to the `XKB_DEFAULT_*` environment
+/// variables and then to xkbcommon's defaults
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeymapNames {
+    pub model: String,
+    pub layout: String,
+    pub variant: String,
+    pub options: String,
+}
+
+impl KeymapNames {
+    /// The layout set through the `LODED_XKB_MODEL`, `LODED_XKB_LAYOUT`, `LODED_XKB_VARIANT`
+    /// and `LODED_XKB_OPTIONS` environment variables, if any of them is
+    pub fn from_env() -> Option<Self> {
+        let var = |name| std::env::var(format!("LODED_XKB_{name}")).ok();
+        let (model, layout, variant, options) =
+            (var("MODEL"), var("LAYOUT"), var("VARIANT"), var("OPTIONS"));
+        if model.is_none() && layout.is_none() && variant.is_none() && options.is_none() {
+            return None;
+        }
+        Some(Self {
+            model: model.unwrap_or_default(),
+            layout: layout.unwrap_or_default(),
+            variant: variant.unwrap_or_default(),
+            options: options.unwrap_or_default(),
+        })
+    }
+
+    /// The layout the host is configured with, or the defaults if systemd-localed can't be asked
+    ///
+    /// This is the machine's layout, compositors that let users pick their own may use another
+    /// one, which has to be set with [KeymapNames::from_env].
+    pub async fn from_host() -> Self {
+        async fn query() -> Result<KeymapNames> {
+            let connection = zbus::Connection::system().await?;
+            let locale = Locale1Proxy::new(&connection).await?;
+            Ok(KeymapNames {
+                model: locale.x11_model().await?,
+                layout: locale.x11_layout().await?,
+                variant: locale.x11_variant().await?,
+                options: locale.x11_options().await?,
+            })
+        }
+
+        match query().await {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("Could not read the host's keyboard layout, using the default: {e}");
+                Self::default()
+            }
+        }
+    }
+}
+
+/// The modifiers held to reach a shift level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Level {
+    shift: bool,
+    alt_gr: bool,
+}
+
+impl Level {
+    /// In the order they're preferred in when a character is on several levels
+    const ALL: [Self; 4] = [
+        Self {
+            shift: false,
+            alt_gr: false,
+        },
+        Self {
+            shift: true,
+            alt_gr: false,
+        },
+        Self {
+            shift: false,
+            alt_gr: true,
+        },
+        Self {
+            shift: true,
+            alt_gr: true,
+        },
+    ];
+
+    fn modifiers(self) -> Vec<Key> {
+        [
+            self.shift.then_some(Key::KEY_LEFTSHIFT),
+            self.alt_gr.then_some(Key::KEY_RIGHTALT),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Turns text into the key presses typing it on the host's keyboard layout
+///
+/// Characters the layout has no key for are typed as a Unicode code point with Ctrl+Shift+U,
+/// which GTK, Qt and IBus understand. The hex digits of the code point have to be on the layout
+/// themselves, so this only works from layouts with latin letters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TextTyper {
+    keys: HashMap<char, (Key, Level)>,
+}
+
+impl TextTyper {
+    pub fn new(names: &KeymapNames) -> std::result::Result<Self, KeymapError> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_names(
+            &context,
+            "",
+            &names.model,
+            &names.layout,
+            &names.variant,
+            (!names.options.is_empty()).then(|| names.options.clone()),
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .ok_or_else(|| KeymapError::Compile(names.layout.clone()))?;
+
+        // Layouts without AltGr have no index for it, which makes it an empty mask
+        let mask = |name| 1u32.checked_shl(keymap.mod_get_index(name)).unwrap_or(0);
+        let shift = mask(xkb::MOD_NAME_SHIFT);
+        let alt_gr = mask(xkb::MOD_NAME_ISO_LEVEL3_SHIFT);
+
+        // Only keys the virtual keyboard has can be pressed, xkb keycodes are offset by 8
+        let mut keys = HashMap::new();
+        for level in Level::ALL {
+            let mut state = xkb::State::new(&keymap);
+            let mask = if level.shift { shift } else { 0 } | if level.alt_gr { alt_gr } else { 0 };
+            state.update_mask(mask, 0, 0, 0, 0, 0);
+
+            for (_, key) in JS_KEY_CODES {
+                let keycode = xkb::Keycode::new(key.code() as u32 + 8);
+                if let Some(c) = char::from_u32(state.key_get_utf32(keycode)) {
+                    if c != '\0' {
+                        keys.entry(c).or_insert((*key, level));
+                    }
+                }
+            }
+        }
+
+        debug!("Keymap {names:?} can type {} characters", keys.len());
+
+        Ok(Self { keys })
+    }
+
+    /// Whether `c` has a key of its own on the layout
+    pub fn has_key(&self, c: char) -> bool {
+        self.key(c).is_some()
+    }
+
+    fn key(&self, c: char) -> Option<(Key, Level)> {
+        // Enter produces a carriage return
+        let c = if c == '\n' { '\r' } else { c };
+        self.keys.get(&c).copied()
+    }
+
+    fn press(key: Key, modifiers: &[Key], out: &mut Vec<KeyEvent>) {
+        let event = |key, direction| KeyEvent { key, direction };
+        out.extend(modifiers.iter().map(|m| event(*m, KeyDirection::Down)));
+        out.push(event(key, KeyDirection::Down));
+        out.push(event(key, KeyDirection::Up));
+        out.extend(modifiers.iter().rev().map(|m| event(*m, KeyDirection::Up)));
+    }
+
+    /// The key presses typing `text`, each of which has to be sent as a frame of its own
+    ///
+    /// Characters that can be typed neither directly nor as a code point are skipped.
+    pub fn key_events(&self, text: &str) -> Vec<KeyEvent> {
+        let mut out = Vec::new();
+        for c in text.chars() {
+            if let Some((key, level)) = self.key(c) {
+                Self::press(key, &level.modifiers(), &mut out);
+                continue;
+            }
+
+            let digits = format!("{:x}", c as u32)
+                .chars()
+                .map(|digit| self.key(digit))
+                .collect::<Option<Vec<(Key, Level)>>>();
+            let (Some(digits), Some((space, _))) = (digits, self.key(' ')) else {
+                warn!("Cannot type {c:?} on this keyboard layout");
+                continue;
+            };
+
+            let u = self.key('u').map_or(Key::KEY_U, |(key, _)| key);
+            Self::press(u, &[Key::KEY_LEFTCTRL, Key::KEY_LEFTSHIFT], &mut out);
+            for (key, level) in digits {
+                Self::press(key, &level.modifiers(), &mut out);
+            }
+            Self::press(space, &[], &mut out);
+        }
+        out
+    }
+}
@@ -1,16 +1,43 @@
-#![feature(new_uninit)]
-
+pub mod adaptation;
 pub(crate) mod api;
+pub(crate) mod backend;
 pub(crate) mod capture;
+pub(crate) mod clipboard;
+pub mod codec;
 pub(crate) mod input;
-pub(crate) mod protocol;
+pub(crate) mod keymap;
+pub mod protocol;
+pub(crate) mod remote_desktop;
 pub(crate) mod screencast;
 pub(crate) mod session_request;
 pub(crate) mod unique_token;
 
-pub use api::ApiManager;
-pub use capture::CaptureManager;
-pub use input::{InputManager, KeyDirection};
+pub use api::{ApiManager, ApiSettings, SessionState};
+pub use backend::{
+    forward_to_portal, InputBackend, InputBackendError, InputBackendKind, InputDevice,
+    PortalBackend, UinputBackend,
+};
+pub use capture::{CaptureManager, Desktop, DesktopStream, PipelineError};
+pub use clipboard::{ClipboardError, ClipboardManager};
+pub use input::{DesktopLayout, InputManager, KeyDirection};
+pub use keymap::{KeymapError, KeymapNames, TextTyper};
+pub use remote_desktop::DeviceType;
+pub use session_request::PortalError;
+
+/// Internals the integration tests reach into, which the binary has no use for
+#[doc(hidden)]
+pub mod testing {
+    pub use crate::api::input_manager_events;
+    pub use crate::backend::{PortalCall, RecordingBackend};
+    pub use crate::capture::{EncodedStream, H264Profile, VideoEncoder, VideoSource};
+    pub use crate::input::{
+        AbsolutePointerEvent, ClientEvent, DeltaMode, DesktopRect, GamepadButtons, GamepadEvent,
+        GamepadState, HeldKeys, KeyEvent, MouseButtonEvent, MouseMoveEvent, PenButtons, PenEvent,
+        PenState, TouchEvent, TouchSlots, WheelAccumulator, JS_KEY_CODES, MAX_TOUCH_CONTACTS,
+        WHEEL_NOTCH,
+    };
+    pub use crate::remote_desktop::PointerAxis;
+}
 
 pub const DESTINATION: &str = "org.freedesktop.portal.Desktop";
 pub const PATH: &str = "/org/freedesktop/portal/desktop";
@@ -1,19 +1,98 @@
-use std::{mem::MaybeUninit, sync::Arc};
+use std::ops::{BitAnd, BitOr};
 
-static PACKET_LENGTHS: [u64; 4] = [
-    std::mem::size_of::<LodestarHandshakePacket>() as u64,
-    0,
-    std::mem::size_of::<LodestarSwitchSourcePacket>() as u64,
-    std::mem::size_of::<LodestarEndPacket>() as u64,
-];
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use evdev::Key;
+
+use crate::{
+    capture::Desktop,
+    input::{
+        DeltaMode, GamepadButtons, GamepadState, KeyDirection, KeyEvent, MouseButtonEvent,
+        MouseMoveEvent, PenButtons, PenEvent, MOUSE_BUTTONS,
+    },
+};
+
+type Result<T> = std::result::Result<T, LodestarPacketParsingError>;
+
+/// The newest protocol revision spoken by this build of loded
+pub const API_REVISION: u64 = 2;
+
+/// The oldest protocol revision this build of loded still accepts
+pub const MIN_API_REVISION: u64 = 1;
+
+/// Size of the `packet_type` and `packet_length` fields preceding every packet
+pub const PACKET_HEADER_LENGTH: usize = 16;
 
 #[repr(u64)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LodestarPacketType {
     Handshake,
     DesktopList,
     SwitchSource,
     End,
+    HandshakeResult,
+    Input,
+    VideoFrame,
+    KeyframeRequest,
+    ClipboardOffer,
+    ClipboardRequest,
+    ClipboardData,
+    SessionClosed,
+}
+
+impl TryFrom<u64> for LodestarPacketType {
+    type Error = LodestarPacketParsingError;
+
+    fn try_from(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Handshake),
+            1 => Ok(Self::DesktopList),
+            2 => Ok(Self::SwitchSource),
+            3 => Ok(Self::End),
+            4 => Ok(Self::HandshakeResult),
+            5 => Ok(Self::Input),
+            6 => Ok(Self::VideoFrame),
+            7 => Ok(Self::KeyframeRequest),
+            8 => Ok(Self::ClipboardOffer),
+            9 => Ok(Self::ClipboardRequest),
+            10 => Ok(Self::ClipboardData),
+            11 => Ok(Self::SessionClosed),
+            v => Err(LodestarPacketParsingError::UnknownPacketType(v)),
+        }
+    }
+}
+
+impl LodestarPacketType {
+    /// The body lengths allowed for this packet type, or `None` if it is variable
+    pub fn fixed_lengths(self) -> Option<&'static [u64]> {
+        match self {
+            Self::Handshake => Some(&[
+                LodestarHandshakePacket::LEGACY_LENGTH,
+                LodestarHandshakePacket::HELLO_LENGTH,
+                LodestarHandshakePacket::PAIRED_HELLO_LENGTH,
+            ]),
+            Self::DesktopList => None,
+            Self::SwitchSource => Some(&[8]),
+            Self::End => Some(&[0]),
+            Self::HandshakeResult => Some(&[40]),
+            Self::Input => None,
+            Self::VideoFrame => None,
+            Self::KeyframeRequest => Some(&[8]),
+            Self::ClipboardOffer => None,
+            Self::ClipboardRequest => Some(&[1]),
+            Self::ClipboardData => None,
+            Self::SessionClosed => Some(&[2]),
+        }
+    }
+
+    /// Checks a received `packet_length` against the lengths expected for this type
+    pub fn validate_length(self, length: u64) -> Result<()> {
+        match self.fixed_lengths() {
+            Some(lengths) if !lengths.contains(&length) => {
+                Err(LodestarPacketParsingError::InvalidPacketLength)
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -22,135 +101,1549 @@ pub enum LodestarPacketParsingError {
     InvalidPacketLength,
     #[error("An invalid field value was parsed")]
     InvalidField,
+    #[error("Unknown packet type {0}")]
+    UnknownPacketType(u64),
+    #[error("Expected a {expected:?} packet but got a {found:?} packet")]
+    UnexpectedPacketType {
+        expected: LodestarPacketType,
+        found: LodestarPacketType,
+    },
+}
+
+/// Serializes a packet body into its wire representation
+pub trait Encode {
+    /// The packet type written in the header in front of this body
+    const PACKET_TYPE: LodestarPacketType;
+
+    /// The number of bytes [Encode::encode] will write
+    fn encoded_len(&self) -> usize;
+
+    fn encode<B: BufMut>(&self, buf: &mut B);
 }
 
-#[repr(C)]
+/// Deserializes a packet body from its wire representation, validating every field
+pub trait Decode: Sized {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self>;
+}
+
+/// Fails with [LodestarPacketParsingError::InvalidPacketLength] unless `len` more bytes are available
+fn ensure_remaining<B: Buf>(buf: &B, len: usize) -> Result<()> {
+    if buf.remaining() < len {
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    } else {
+        Ok(())
+    }
+}
+
+/// A packet whose header has been validated but whose body has not yet been decoded
+#[derive(Clone, Debug)]
 pub struct LodestarPacket {
     packet_type: LodestarPacketType,
-    packet_length: u64,
-    packet_data: [u8],
+    packet_data: Bytes,
 }
 
 impl LodestarPacket {
-    pub fn parse_packet<T>(&self) -> std::result::Result<&T, LodestarPacketParsingError> {
-        let ex_len = PACKET_LENGTHS[self.packet_type as u64 as usize];
-        if self.packet_length == ex_len || ex_len == 0 {
-            unsafe {
-                Ok(&*(core::ptr::slice_from_raw_parts(
-                    self.packet_data.as_ptr().cast::<()>(),
-                    self.packet_length as usize,
-                ) as *const T))
-            }
-        } else {
+    /// Reads and validates the `packet_type` and `packet_length` header
+    pub fn decode_header<B: Buf>(buf: &mut B) -> Result<(LodestarPacketType, u64)> {
+        ensure_remaining(buf, PACKET_HEADER_LENGTH)?;
+        let packet_type = LodestarPacketType::try_from(buf.get_u64_le())?;
+        let packet_length = buf.get_u64_le();
+        packet_type.validate_length(packet_length)?;
+        Ok((packet_type, packet_length))
+    }
+
+    /// Builds a packet from an already read header and body, validating the body length
+    pub fn from_parts(packet_type: LodestarPacketType, packet_data: Bytes) -> Result<Self> {
+        packet_type.validate_length(packet_data.len() as u64)?;
+        Ok(Self {
+            packet_type,
+            packet_data,
+        })
+    }
+
+    /// Encodes a packet body along with its header
+    pub fn encode_packet<T: Encode>(packet: &T) -> Bytes {
+        let len = packet.encoded_len();
+        let mut buf = BytesMut::with_capacity(PACKET_HEADER_LENGTH + len);
+        buf.put_u64_le(T::PACKET_TYPE as u64);
+        buf.put_u64_le(len as u64);
+        packet.encode(&mut buf);
+        buf.freeze()
+    }
+
+    pub fn packet_type(&self) -> LodestarPacketType {
+        self.packet_type
+    }
+
+    /// Decodes the body as `T`, requiring the packet type to match and the whole body to be used
+    pub fn parse_packet<T: Encode + Decode>(&self) -> Result<T> {
+        if self.packet_type != T::PACKET_TYPE {
+            return Err(LodestarPacketParsingError::UnexpectedPacketType {
+                expected: T::PACKET_TYPE,
+                found: self.packet_type,
+            });
+        }
+
+        let mut data = self.packet_data.clone();
+        let packet = T::decode(&mut data)?;
+        if data.has_remaining() {
             Err(LodestarPacketParsingError::InvalidPacketLength)
+        } else {
+            Ok(packet)
         }
     }
 }
 
-#[repr(C)]
-#[derive(Clone, Debug)]
-pub struct LodestarHandshakePacket {
-    api_revision: u64,
-    accepted: bool,
-}
-
-impl Into<Arc<[u8]>> for LodestarHandshakePacket {
-    fn into(self) -> Arc<[u8]> {
-        let mut data: Arc<[MaybeUninit<u8>]> = Arc::new_uninit_slice(2 * 8);
-        let dataw = Arc::get_mut(&mut data).unwrap();
-        for (idx, item) in self
-            .api_revision
-            .to_le_bytes()
+impl Decode for LodestarPacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        let (packet_type, packet_length) = Self::decode_header(buf)?;
+        let packet_length = usize::try_from(packet_length)
+            .map_err(|_| LodestarPacketParsingError::InvalidPacketLength)?;
+        ensure_remaining(buf, packet_length)?;
+        Ok(Self {
+            packet_type,
+            packet_data: buf.copy_to_bytes(packet_length),
+        })
+    }
+}
+
+/// Features a peer supports, exchanged during the handshake
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct LodestarCapabilities(pub u64);
+
+impl LodestarCapabilities {
+    pub const NONE: Self = Self(0);
+    /// Keyboard input
+    pub const KEYBOARD: Self = Self(1 << 0);
+    /// Relative pointer motion and buttons
+    pub const MOUSE_RELATIVE: Self = Self(1 << 1);
+    /// Absolute pointer positions
+    pub const MOUSE_ABSOLUTE: Self = Self(1 << 2);
+    /// Multitouch input
+    pub const TOUCH: Self = Self(1 << 3);
+    /// Pen and stylus input
+    pub const PEN: Self = Self(1 << 4);
+    /// Gamepad input
+    pub const GAMEPAD: Self = Self(1 << 5);
+    /// Clipboard synchronization
+    pub const CLIPBOARD: Self = Self(1 << 6);
+    /// Audio playback
+    pub const AUDIO: Self = Self(1 << 7);
+    /// H.264 video
+    pub const CODEC_H264: Self = Self(1 << 16);
+    /// VP8 video
+    pub const CODEC_VP8: Self = Self(1 << 17);
+    /// VP9 video
+    pub const CODEC_VP9: Self = Self(1 << 18);
+    /// AV1 video
+    pub const CODEC_AV1: Self = Self(1 << 19);
+    /// Uncompressed video
+    pub const CODEC_RAW: Self = Self(1 << 20);
+    /// H.264 video with full 4:4:4 chroma
+    pub const CODEC_H264_444: Self = Self(1 << 21);
+
+    /// Every video codec, most preferred first
+    pub const CODECS: [Self; 6] = [
+        Self::CODEC_H264_444,
+        Self::CODEC_H264,
+        Self::CODEC_VP9,
+        Self::CODEC_VP8,
+        Self::CODEC_AV1,
+        Self::CODEC_RAW,
+    ];
+
+    /// Everything this build of loded supports
+    pub const SERVER: Self = Self(
+        Self::KEYBOARD.0
+            | Self::MOUSE_RELATIVE.0
+            | Self::MOUSE_ABSOLUTE.0
+            | Self::TOUCH.0
+            | Self::PEN.0
+            | Self::GAMEPAD.0
+            | Self::CLIPBOARD.0
+            | Self::CODEC_H264_444.0
+            | Self::CODEC_H264.0
+            | Self::CODEC_VP9.0
+            | Self::CODEC_VP8.0
+            | Self::CODEC_AV1.0
+            | Self::CODEC_RAW.0,
+    );
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The most preferred video codec in `self`
+    pub fn preferred_codec(self) -> Option<Self> {
+        Self::CODECS.into_iter().find(|codec| self.contains(*codec))
+    }
+
+    /// `self` without any of the capabilities in `other`
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Keeps every capability that isn't a codec, but only the codecs also present in `codecs`
+    pub fn restrict_codecs(self, codecs: Self) -> Self {
+        let all_codecs = Self::CODECS.into_iter().fold(Self::NONE, BitOr::bitor);
+        Self(self.0 & !(all_codecs.0 & !codecs.0))
+    }
+}
+
+impl BitOr for LodestarCapabilities {
+    type Output = LodestarCapabilities;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for LodestarCapabilities {
+    type Output = LodestarCapabilities;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// A secret handed to clients out of band, which they present in their
+/// [LodestarHandshakePacket::Hello] to prove they were paired with the server
+#[derive(Clone, PartialEq, Eq)]
+pub struct PairingKey(pub [u8; PairingKey::LENGTH]);
+
+impl PairingKey {
+    pub const LENGTH: usize = 32;
+
+    pub fn generate() -> Self {
+        Self(rand::random())
+    }
+
+    /// Compares in constant time, so how long a rejection takes gives nothing about the key away
+    pub fn verify(&self, presented: &PairingKey) -> bool {
+        self.0
             .iter()
-            .chain((self.accepted as u64).to_le_bytes().iter())
-            .enumerate()
-        {
-            dataw[idx].write(*item);
+            .zip(presented.0.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl std::fmt::Debug for PairingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PairingKey(..)")
+    }
+}
+
+/// Lowercase hex, the way the key is stored and handed to clients
+impl std::fmt::Display for PairingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("A pairing key is {} hex digits", PairingKey::LENGTH * 2)]
+pub struct InvalidPairingKey;
+
+impl std::str::FromStr for PairingKey {
+    type Err = InvalidPairingKey;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() != Self::LENGTH * 2 || !s.is_ascii() {
+            return Err(InvalidPairingKey);
+        }
+        let mut key = [0; Self::LENGTH];
+        for (byte, digits) in key.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| InvalidPairingKey)?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| InvalidPairingKey)?;
         }
+        Ok(Self(key))
+    }
+}
+
+/// The first packet a client sends
+///
+/// The variants are told apart by the packet length alone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LodestarHandshakePacket {
+    /// Revision 1 handshake, also used by the server to answer revision 1 clients
+    Legacy { api_revision: u64, accepted: bool },
+    /// Handshake offering a range of revisions, answered with a [LodestarHandshakeResultPacket]
+    Hello {
+        min_revision: u64,
+        max_revision: u64,
+        capabilities: LodestarCapabilities,
+        /// Required by servers reachable from other machines
+        pairing_key: Option<PairingKey>,
+    },
+}
+
+impl LodestarHandshakePacket {
+    const LEGACY_LENGTH: u64 = 16;
+    const HELLO_LENGTH: u64 = 24;
+    const PAIRED_HELLO_LENGTH: u64 = Self::HELLO_LENGTH + PairingKey::LENGTH as u64;
 
-        unsafe { data.assume_init() }
+    /// The revisions offered by this handshake
+    pub fn revision_range(&self) -> std::ops::RangeInclusive<u64> {
+        match self {
+            Self::Legacy { api_revision, .. } => *api_revision..=*api_revision,
+            Self::Hello {
+                min_revision,
+                max_revision,
+                ..
+            } => *min_revision..=*max_revision,
+        }
     }
 }
 
-#[repr(C)]
-#[derive(Clone, Debug)]
-pub struct LodestarDesktop {
-    loded_id: u64,
-    width: i32,
-    height: i32,
+impl Encode for LodestarHandshakePacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::Handshake;
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            Self::Legacy { .. } => Self::LEGACY_LENGTH as usize,
+            Self::Hello {
+                pairing_key: None, ..
+            } => Self::HELLO_LENGTH as usize,
+            Self::Hello {
+                pairing_key: Some(_),
+                ..
+            } => Self::PAIRED_HELLO_LENGTH as usize,
+        }
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        match self {
+            Self::Legacy {
+                api_revision,
+                accepted,
+            } => {
+                buf.put_u64_le(*api_revision);
+                buf.put_u64_le(*accepted as u64);
+            }
+            Self::Hello {
+                min_revision,
+                max_revision,
+                capabilities,
+                pairing_key,
+            } => {
+                buf.put_u64_le(*min_revision);
+                buf.put_u64_le(*max_revision);
+                buf.put_u64_le(capabilities.0);
+                if let Some(key) = pairing_key {
+                    buf.put_slice(&key.0);
+                }
+            }
+        }
+    }
 }
 
-#[repr(C)]
-pub struct LodestarDesktopPacket {
-    desktop_count: u64,
-    data: [LodestarDesktop],
+impl Decode for LodestarHandshakePacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() as u64 == Self::LEGACY_LENGTH {
+            let api_revision = buf.get_u64_le();
+            let accepted = match buf.get_u64_le() {
+                0 => false,
+                1 => true,
+                _ => return Err(LodestarPacketParsingError::InvalidField),
+            };
+            return Ok(Self::Legacy {
+                api_revision,
+                accepted,
+            });
+        }
+
+        // Anything but a bare Hello or one carrying a whole pairing key is malformed
+        let length = buf.remaining() as u64;
+        if length != Self::HELLO_LENGTH && length != Self::PAIRED_HELLO_LENGTH {
+            return Err(LodestarPacketParsingError::InvalidPacketLength);
+        }
+        let min_revision = buf.get_u64_le();
+        let max_revision = buf.get_u64_le();
+        if min_revision > max_revision {
+            return Err(LodestarPacketParsingError::InvalidField);
+        }
+        let capabilities = LodestarCapabilities(buf.get_u64_le());
+        let pairing_key = if length == Self::PAIRED_HELLO_LENGTH {
+            let mut key = [0; PairingKey::LENGTH];
+            buf.copy_to_slice(&mut key);
+            Some(PairingKey(key))
+        } else {
+            None
+        };
+        Ok(Self::Hello {
+            min_revision,
+            max_revision,
+            capabilities,
+            pairing_key,
+        })
+    }
 }
 
-impl LodestarDesktopPacket {
-    pub fn into(&self) -> Arc<[u8]> {
-        let mut data: Arc<[MaybeUninit<u8>]> = Arc::new_uninit_slice(
-            8 + (std::mem::size_of::<LodestarDesktop>() * self.desktop_count as usize),
-        );
-        let dataw = Arc::get_mut(&mut data).unwrap();
+/// Why a handshake was accepted or rejected
+#[repr(u64)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LodestarHandshakeStatus {
+    Accepted = 0,
+    /// The client's and server's revision ranges do not overlap
+    NoCommonRevision = 1,
+    /// The server requires a pairing key and the client presented none or the wrong one
+    Unauthorized = 2,
+}
+
+impl TryFrom<u64> for LodestarHandshakeStatus {
+    type Error = LodestarPacketParsingError;
+
+    fn try_from(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Accepted),
+            1 => Ok(Self::NoCommonRevision),
+            2 => Ok(Self::Unauthorized),
+            _ => Err(LodestarPacketParsingError::InvalidField),
+        }
+    }
+}
+
+/// The server's answer to a [LodestarHandshakePacket::Hello]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LodestarHandshakeResultPacket {
+    pub status: LodestarHandshakeStatus,
+    /// The revision both sides speak from now on, `0` if rejected
+    pub revision: u64,
+    /// The range of revisions the server supports
+    pub min_revision: u64,
+    pub max_revision: u64,
+    /// Capabilities supported by both sides
+    pub capabilities: LodestarCapabilities,
+}
+
+impl LodestarHandshakeResultPacket {
+    /// Picks the highest revision shared with the client's range
+    ///
+    /// Of the codecs both sides support only the most preferred one is kept, so the client knows
+    /// what the video it receives is encoded with.
+    pub fn negotiate(
+        min_revision: u64,
+        max_revision: u64,
+        capabilities: LodestarCapabilities,
+    ) -> Self {
+        let high = max_revision.min(API_REVISION);
+        let low = min_revision.max(MIN_API_REVISION);
+
+        let (status, revision, capabilities) = if low <= high {
+            let capabilities = capabilities & LodestarCapabilities::SERVER;
+            (
+                LodestarHandshakeStatus::Accepted,
+                high,
+                capabilities.restrict_codecs(
+                    capabilities
+                        .preferred_codec()
+                        .unwrap_or(LodestarCapabilities::NONE),
+                ),
+            )
+        } else {
+            (
+                LodestarHandshakeStatus::NoCommonRevision,
+                0,
+                LodestarCapabilities::NONE,
+            )
+        };
+
+        Self {
+            status,
+            revision,
+            min_revision: MIN_API_REVISION,
+            max_revision: API_REVISION,
+            capabilities,
+        }
+    }
+
+    /// Turns away a client that didn't prove being paired
+    pub fn unauthorized() -> Self {
+        Self {
+            status: LodestarHandshakeStatus::Unauthorized,
+            revision: 0,
+            min_revision: MIN_API_REVISION,
+            max_revision: API_REVISION,
+            capabilities: LodestarCapabilities::NONE,
+        }
+    }
+}
+
+impl Encode for LodestarHandshakeResultPacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::HandshakeResult;
+
+    fn encoded_len(&self) -> usize {
+        40
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u64_le(self.status as u64);
+        buf.put_u64_le(self.revision);
+        buf.put_u64_le(self.min_revision);
+        buf.put_u64_le(self.max_revision);
+        buf.put_u64_le(self.capabilities.0);
+    }
+}
 
-        for (idx, item) in self.desktop_count.to_le_bytes().iter().enumerate() {
-            dataw[idx].write(*item);
+impl Decode for LodestarHandshakeResultPacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        ensure_remaining(buf, 40)?;
+        Ok(Self {
+            status: LodestarHandshakeStatus::try_from(buf.get_u64_le())?,
+            revision: buf.get_u64_le(),
+            min_revision: buf.get_u64_le(),
+            max_revision: buf.get_u64_le(),
+            capabilities: LodestarCapabilities(buf.get_u64_le()),
+        })
+    }
+}
+
+/// A single entry of the desktop list
+///
+/// On the wire every entry starts with `loded_id`, `width`, `height` and a flags byte,
+/// followed by each optional field whose flag bit is set, in declaration order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LodestarDesktop {
+    pub loded_id: u64,
+    pub width: i32,
+    pub height: i32,
+    /// Human readable name, at most [LodestarDesktop::MAX_NAME_LENGTH] bytes of UTF-8
+    pub name: Option<String>,
+    /// Position of the desktop in the compositor's layout in the format `(x, y)`
+    pub position: Option<(i32, i32)>,
+}
+
+impl From<&Desktop> for LodestarDesktop {
+    fn from(desktop: &Desktop) -> Self {
+        Self {
+            loded_id: desktop.loded_id,
+            width: desktop.width,
+            height: desktop.height,
+            name: Some(desktop.id.clone()),
+            position: desktop.position,
         }
+    }
+}
+
+impl LodestarDesktop {
+    pub const MAX_NAME_LENGTH: usize = u8::MAX as usize;
 
-        let slice =
-            unsafe { std::slice::from_raw_parts(self.data.as_ptr(), self.desktop_count as usize) };
-        for (idx, item) in slice.iter().enumerate() {
-            for item in item
-                .loded_id
-                .to_le_bytes()
-                .iter()
-                .chain(item.width.to_le_bytes().iter())
-                .chain(item.height.to_le_bytes().iter())
-            {
-                dataw[idx + 1].write(*item);
+    const FLAG_NAME: u8 = 1 << 0;
+    const FLAG_POSITION: u8 = 1 << 1;
+    const KNOWN_FLAGS: u8 = Self::FLAG_NAME | Self::FLAG_POSITION;
+
+    /// `loded_id`, `width`, `height` and the flags byte
+    const MIN_ENCODED_LEN: usize = 17;
+
+    /// The name as it is written on the wire, truncated to a character boundary if too long
+    fn wire_name(&self) -> Option<&str> {
+        self.name.as_deref().map(|name| {
+            let mut end = name.len().min(Self::MAX_NAME_LENGTH);
+            while !name.is_char_boundary(end) {
+                end -= 1;
             }
+            &name[..end]
+        })
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.name.is_some() {
+            flags |= Self::FLAG_NAME;
+        }
+        if self.position.is_some() {
+            flags |= Self::FLAG_POSITION;
+        }
+        flags
+    }
+
+    fn encoded_len(&self) -> usize {
+        Self::MIN_ENCODED_LEN
+            + self.wire_name().map_or(0, |name| 1 + name.len())
+            + self.position.map_or(0, |_| 8)
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u64_le(self.loded_id);
+        buf.put_i32_le(self.width);
+        buf.put_i32_le(self.height);
+        buf.put_u8(self.flags());
+        if let Some(name) = self.wire_name() {
+            buf.put_u8(name.len() as u8);
+            buf.put_slice(name.as_bytes());
+        }
+        if let Some((x, y)) = self.position {
+            buf.put_i32_le(x);
+            buf.put_i32_le(y);
+        }
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        ensure_remaining(buf, Self::MIN_ENCODED_LEN)?;
+        let loded_id = buf.get_u64_le();
+        let width = buf.get_i32_le();
+        let height = buf.get_i32_le();
+        if width <= 0 || height <= 0 {
+            return Err(LodestarPacketParsingError::InvalidField);
+        }
+
+        let flags = buf.get_u8();
+        if flags & !Self::KNOWN_FLAGS != 0 {
+            return Err(LodestarPacketParsingError::InvalidField);
         }
 
-        unsafe { data.assume_init() }
+        let name = if flags & Self::FLAG_NAME != 0 {
+            ensure_remaining(buf, 1)?;
+            let len = buf.get_u8() as usize;
+            ensure_remaining(buf, len)?;
+            let raw = buf.copy_to_bytes(len);
+            let name =
+                std::str::from_utf8(&raw).map_err(|_| LodestarPacketParsingError::InvalidField)?;
+            Some(name.to_owned())
+        } else {
+            None
+        };
+
+        let position = if flags & Self::FLAG_POSITION != 0 {
+            ensure_remaining(buf, 8)?;
+            Some((buf.get_i32_le(), buf.get_i32_le()))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            loded_id,
+            width,
+            height,
+            name,
+            position,
+        })
     }
 }
 
+/// The list of desktops available to the client, encoded as a `u64` count followed by the entries
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LodestarDesktopPacket {
+    data: Vec<LodestarDesktop>,
+}
+
 impl LodestarDesktopPacket {
+    pub fn new(data: Vec<LodestarDesktop>) -> Self {
+        Self { data }
+    }
+
     pub fn get_desktops(&self) -> &[LodestarDesktop] {
-        unsafe {
-            std::slice::from_raw_parts(
-                self.data.as_ptr() as *const LodestarDesktop,
-                self.desktop_count as usize,
-            )
+        &self.data
+    }
+}
+
+impl Encode for LodestarDesktopPacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::DesktopList;
+
+    fn encoded_len(&self) -> usize {
+        8 + self
+            .data
+            .iter()
+            .map(LodestarDesktop::encoded_len)
+            .sum::<usize>()
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u64_le(self.data.len() as u64);
+
+        for item in self.data.iter() {
+            item.encode(buf);
         }
     }
 }
 
-#[repr(C)]
-#[derive(Clone, Debug)]
+impl Decode for LodestarDesktopPacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        ensure_remaining(buf, 8)?;
+        let desktop_count = usize::try_from(buf.get_u64_le())
+            .map_err(|_| LodestarPacketParsingError::InvalidPacketLength)?;
+
+        // Every entry takes at least MIN_ENCODED_LEN bytes, so a count that cannot fit in the
+        // remaining data is rejected before anything is allocated for it
+        let min_len = desktop_count
+            .checked_mul(LodestarDesktop::MIN_ENCODED_LEN)
+            .ok_or(LodestarPacketParsingError::InvalidPacketLength)?;
+        ensure_remaining(buf, min_len)?;
+
+        let mut data = Vec::with_capacity(desktop_count);
+        for _ in 0..desktop_count {
+            data.push(LodestarDesktop::decode(buf)?);
+        }
+
+        Ok(Self { data })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct LodestarSwitchSourcePacket {
-    new_source: u64,
+    pub new_source: u64,
 }
 
-impl Into<Arc<[u8]>> for LodestarSwitchSourcePacket {
-    fn into(self) -> Arc<[u8]> {
-        let mut data: Arc<[MaybeUninit<u8>]> = Arc::new_uninit_slice(8);
-        let dataw = Arc::get_mut(&mut data).unwrap();
+impl Encode for LodestarSwitchSourcePacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::SwitchSource;
 
-        for (idx, item) in self.new_source.to_le_bytes().iter().enumerate() {
-            dataw[idx].write(*item);
-        }
+    fn encoded_len(&self) -> usize {
+        8
+    }
 
-        unsafe { data.assume_init() }
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u64_le(self.new_source);
     }
 }
 
-#[repr(C)]
-#[derive(Clone, Debug)]
+impl Decode for LodestarSwitchSourcePacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        ensure_remaining(buf, 8)?;
+        Ok(Self {
+            new_source: buf.get_u64_le(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct LodestarEndPacket {}
 
-impl Into<Arc<[u8]>> for LodestarEndPacket {
-    fn into(self) -> Arc<[u8]> {
-        unsafe { Arc::new_uninit_slice(0).assume_init() }
+impl Encode for LodestarEndPacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::End;
+
+    fn encoded_len(&self) -> usize {
+        0
+    }
+
+    fn encode<B: BufMut>(&self, _buf: &mut B) {}
+}
+
+impl Decode for LodestarEndPacket {
+    fn decode<B: Buf>(_buf: &mut B) -> Result<Self> {
+        Ok(Self {})
+    }
+}
+
+/// A single input event sent by a client
+#[derive(Clone, Debug, PartialEq)]
+pub enum LodestarInput {
+    Key(KeyEvent),
+    /// Relative pointer motion, only `x` and `y` are used
+    RelativeMotion(MouseMoveEvent),
+    /// Pointer position in pixels relative to the top left corner of a desktop
+    AbsoluteMotion {
+        loded_id: u64,
+        x: i32,
+        y: i32,
+    },
+    Button(MouseButtonEvent),
+    /// Vertical scrolling in hi-res units sent by older clients, only `wheel` is used
+    Scroll(MouseMoveEvent),
+    /// The deltas of a browser `WheelEvent`, converted into hi-res units by the server
+    Wheel {
+        delta_x: f64,
+        delta_y: f64,
+        mode: DeltaMode,
+    },
+    /// A finger put down on a desktop, `contact` identifies it until [LodestarInput::TouchEnd]
+    TouchStart {
+        loded_id: u64,
+        contact: u32,
+        x: i32,
+        y: i32,
+    },
+    /// A finger moved to a new position on a desktop
+    TouchMove {
+        loded_id: u64,
+        contact: u32,
+        x: i32,
+        y: i32,
+    },
+    /// A finger lifted or its touch cancelled
+    TouchEnd {
+        contact: u32,
+    },
+    /// A pen sample on a desktop, see [PenEvent] for the units
+    ///
+    /// A pen leaving the tablet is sent without [PenButtons::IN_RANGE].
+    Pen {
+        loded_id: u64,
+        x: i32,
+        y: i32,
+        pressure: u16,
+        tilt_x: i8,
+        tilt_y: i8,
+        buttons: PenButtons,
+    },
+    /// The state of a client's gamepad, `pad` being its index in the Gamepad API
+    Gamepad {
+        pad: u32,
+        state: GamepadState,
+    },
+    /// A gamepad unplugged from the client
+    GamepadRemoved {
+        pad: u32,
+    },
+    /// Text to type, like a paste or what an input method composed, of at most
+    /// [LodestarInput::MAX_TEXT_LENGTH] bytes
+    Text(String),
+}
+
+impl LodestarInput {
+    const TAG_KEY: u8 = 0;
+    const TAG_RELATIVE_MOTION: u8 = 1;
+    const TAG_ABSOLUTE_MOTION: u8 = 2;
+    const TAG_BUTTON: u8 = 3;
+    /// Vertical only scrolling sent by older clients
+    const TAG_SCROLL: u8 = 4;
+    const TAG_WHEEL: u8 = 5;
+    const TAG_TOUCH_START: u8 = 6;
+    const TAG_TOUCH_MOVE: u8 = 7;
+    const TAG_TOUCH_END: u8 = 8;
+    const TAG_PEN: u8 = 9;
+    const TAG_GAMEPAD: u8 = 10;
+    const TAG_GAMEPAD_REMOVED: u8 = 11;
+    const TAG_TEXT: u8 = 12;
+
+    pub const MAX_TEXT_LENGTH: usize = 4096;
+
+    /// Keyboard keys, excluding the `BTN_*` ranges
+    fn is_keyboard_key(key: Key) -> bool {
+        matches!(key.code(), 0x01..=0xff | 0x160..=0x2bf)
+    }
+
+    fn is_mouse_button(key: Key) -> bool {
+        MOUSE_BUTTONS.contains(&key)
+    }
+
+    /// The scrolling of a [LodestarInput::Scroll] or [LodestarInput::Wheel] in hi-res units
+    pub fn hi_res_scroll(&self) -> Option<MouseMoveEvent> {
+        match *self {
+            Self::Scroll(evt) => Some(MouseMoveEvent::new(0, 0, evt.wheel, 0)),
+            Self::Wheel {
+                delta_x,
+                delta_y,
+                mode,
+            } => Some(MouseMoveEvent::from_wheel_delta(delta_x, delta_y, mode)),
+            _ => None,
+        }
+    }
+
+    /// The text as it is written on the wire, truncated to a character boundary if too long
+    fn wire_text(&self) -> Option<&str> {
+        match self {
+            Self::Text(text) => {
+                let mut end = text.len().min(Self::MAX_TEXT_LENGTH);
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Some(&text[..end])
+            }
+            _ => None,
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            Self::Key(_) | Self::Button(_) => 3,
+            Self::RelativeMotion(_) => 8,
+            Self::AbsoluteMotion { .. } => 16,
+            Self::Scroll(_) => 4,
+            Self::Wheel { .. } => 17,
+            Self::TouchStart { .. } | Self::TouchMove { .. } => 20,
+            Self::TouchEnd { .. } => 4,
+            Self::Pen { .. } => 21,
+            Self::Gamepad { .. } => 18,
+            Self::GamepadRemoved { .. } => 4,
+            Self::Text(_) => 4 + self.wire_text().map_or(0, str::len),
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Self::Key(_) => Self::TAG_KEY,
+            Self::RelativeMotion(_) => Self::TAG_RELATIVE_MOTION,
+            Self::AbsoluteMotion { .. } => Self::TAG_ABSOLUTE_MOTION,
+            Self::Button(_) => Self::TAG_BUTTON,
+            Self::Scroll(_) => Self::TAG_SCROLL,
+            Self::Wheel { .. } => Self::TAG_WHEEL,
+            Self::TouchStart { .. } => Self::TAG_TOUCH_START,
+            Self::TouchMove { .. } => Self::TAG_TOUCH_MOVE,
+            Self::TouchEnd { .. } => Self::TAG_TOUCH_END,
+            Self::Pen { .. } => Self::TAG_PEN,
+            Self::Gamepad { .. } => Self::TAG_GAMEPAD,
+            Self::GamepadRemoved { .. } => Self::TAG_GAMEPAD_REMOVED,
+            Self::Text(_) => Self::TAG_TEXT,
+        }
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        match self {
+            Self::Key(KeyEvent { key, direction })
+            | Self::Button(MouseButtonEvent { key, direction }) => {
+                buf.put_u16_le(key.code());
+                buf.put_u8(*direction as u8);
+            }
+            Self::RelativeMotion(evt) => {
+                buf.put_i32_le(evt.x);
+                buf.put_i32_le(evt.y);
+            }
+            Self::AbsoluteMotion { loded_id, x, y } => {
+                buf.put_u64_le(*loded_id);
+                buf.put_i32_le(*x);
+                buf.put_i32_le(*y);
+            }
+            Self::Scroll(evt) => buf.put_i32_le(evt.wheel),
+            Self::Wheel {
+                delta_x,
+                delta_y,
+                mode,
+            } => {
+                buf.put_f64_le(*delta_x);
+                buf.put_f64_le(*delta_y);
+                buf.put_u8(*mode as u8);
+            }
+            Self::TouchStart {
+                loded_id,
+                contact,
+                x,
+                y,
+            }
+            | Self::TouchMove {
+                loded_id,
+                contact,
+                x,
+                y,
+            } => {
+                buf.put_u64_le(*loded_id);
+                buf.put_u32_le(*contact);
+                buf.put_i32_le(*x);
+                buf.put_i32_le(*y);
+            }
+            Self::TouchEnd { contact } => buf.put_u32_le(*contact),
+            Self::Pen {
+                loded_id,
+                x,
+                y,
+                pressure,
+                tilt_x,
+                tilt_y,
+                buttons,
+            } => {
+                buf.put_u64_le(*loded_id);
+                buf.put_i32_le(*x);
+                buf.put_i32_le(*y);
+                buf.put_u16_le(*pressure);
+                buf.put_i8(*tilt_x);
+                buf.put_i8(*tilt_y);
+                buf.put_u8(buttons.0);
+            }
+            Self::Gamepad { pad, state } => {
+                buf.put_u32_le(*pad);
+                buf.put_u32_le(state.buttons.0);
+                buf.put_i16_le(state.left_x);
+                buf.put_i16_le(state.left_y);
+                buf.put_i16_le(state.right_x);
+                buf.put_i16_le(state.right_y);
+                buf.put_u8(state.left_trigger);
+                buf.put_u8(state.right_trigger);
+            }
+            Self::GamepadRemoved { pad } => buf.put_u32_le(*pad),
+            Self::Text(_) => {
+                let text = self.wire_text().unwrap_or_default();
+                buf.put_u32_le(text.len() as u32);
+                buf.put_slice(text.as_bytes());
+            }
+        }
+    }
+
+    fn decode_key<B: Buf>(buf: &mut B) -> Result<(Key, KeyDirection)> {
+        ensure_remaining(buf, 3)?;
+        let key = Key::new(buf.get_u16_le());
+        let direction = match buf.get_u8() {
+            0 => KeyDirection::Up,
+            1 => KeyDirection::Down,
+            2 => KeyDirection::RepeatingDown,
+            _ => return Err(LodestarPacketParsingError::InvalidField),
+        };
+        Ok((key, direction))
+    }
+
+    fn decode<B: Buf>(tag: u8, buf: &mut B) -> Result<Self> {
+        match tag {
+            Self::TAG_KEY => {
+                let (key, direction) = Self::decode_key(buf)?;
+                if !Self::is_keyboard_key(key) {
+                    return Err(LodestarPacketParsingError::InvalidField);
+                }
+                Ok(Self::Key(KeyEvent { key, direction }))
+            }
+            Self::TAG_RELATIVE_MOTION => {
+                ensure_remaining(buf, 8)?;
+                Ok(Self::RelativeMotion(MouseMoveEvent::new(
+                    buf.get_i32_le(),
+                    buf.get_i32_le(),
+                    0,
+                    0,
+                )))
+            }
+            Self::TAG_ABSOLUTE_MOTION => {
+                ensure_remaining(buf, 16)?;
+                Ok(Self::AbsoluteMotion {
+                    loded_id: buf.get_u64_le(),
+                    x: buf.get_i32_le(),
+                    y: buf.get_i32_le(),
+                })
+            }
+            Self::TAG_BUTTON => {
+                let (key, direction) = Self::decode_key(buf)?;
+                if !Self::is_mouse_button(key) {
+                    return Err(LodestarPacketParsingError::InvalidField);
+                }
+                Ok(Self::Button(MouseButtonEvent { key, direction }))
+            }
+            Self::TAG_SCROLL => {
+                ensure_remaining(buf, 4)?;
+                Ok(Self::Scroll(MouseMoveEvent::new(0, 0, buf.get_i32_le(), 0)))
+            }
+            Self::TAG_WHEEL => {
+                ensure_remaining(buf, 17)?;
+                let delta_x = buf.get_f64_le();
+                let delta_y = buf.get_f64_le();
+                if !delta_x.is_finite() || !delta_y.is_finite() {
+                    return Err(LodestarPacketParsingError::InvalidField);
+                }
+                let mode = match buf.get_u8() {
+                    0 => DeltaMode::Pixel,
+                    1 => DeltaMode::Line,
+                    2 => DeltaMode::Page,
+                    _ => return Err(LodestarPacketParsingError::InvalidField),
+                };
+                Ok(Self::Wheel {
+                    delta_x,
+                    delta_y,
+                    mode,
+                })
+            }
+            Self::TAG_TOUCH_START => {
+                ensure_remaining(buf, 20)?;
+                Ok(Self::TouchStart {
+                    loded_id: buf.get_u64_le(),
+                    contact: buf.get_u32_le(),
+                    x: buf.get_i32_le(),
+                    y: buf.get_i32_le(),
+                })
+            }
+            Self::TAG_TOUCH_MOVE => {
+                ensure_remaining(buf, 20)?;
+                Ok(Self::TouchMove {
+                    loded_id: buf.get_u64_le(),
+                    contact: buf.get_u32_le(),
+                    x: buf.get_i32_le(),
+                    y: buf.get_i32_le(),
+                })
+            }
+            Self::TAG_TOUCH_END => {
+                ensure_remaining(buf, 4)?;
+                Ok(Self::TouchEnd {
+                    contact: buf.get_u32_le(),
+                })
+            }
+            Self::TAG_PEN => {
+                ensure_remaining(buf, 21)?;
+                let loded_id = buf.get_u64_le();
+                let x = buf.get_i32_le();
+                let y = buf.get_i32_le();
+                let pressure = buf.get_u16_le();
+                let tilt_x = buf.get_i8();
+                let tilt_y = buf.get_i8();
+                let buttons = PenButtons(buf.get_u8());
+
+                let tilt = -PenEvent::MAX_TILT..=PenEvent::MAX_TILT;
+                if !tilt.contains(&tilt_x)
+                    || !tilt.contains(&tilt_y)
+                    || !PenButtons::ALL.contains(buttons)
+                {
+                    return Err(LodestarPacketParsingError::InvalidField);
+                }
+                Ok(Self::Pen {
+                    loded_id,
+                    x,
+                    y,
+                    pressure,
+                    tilt_x,
+                    tilt_y,
+                    buttons,
+                })
+            }
+            Self::TAG_GAMEPAD => {
+                ensure_remaining(buf, 18)?;
+                let pad = buf.get_u32_le();
+                let buttons = GamepadButtons(buf.get_u32_le());
+                if !GamepadButtons::ALL.contains(buttons) {
+                    return Err(LodestarPacketParsingError::InvalidField);
+                }
+                Ok(Self::Gamepad {
+                    pad,
+                    state: GamepadState {
+                        buttons,
+                        left_x: buf.get_i16_le(),
+                        left_y: buf.get_i16_le(),
+                        right_x: buf.get_i16_le(),
+                        right_y: buf.get_i16_le(),
+                        left_trigger: buf.get_u8(),
+                        right_trigger: buf.get_u8(),
+                    },
+                })
+            }
+            Self::TAG_GAMEPAD_REMOVED => {
+                ensure_remaining(buf, 4)?;
+                Ok(Self::GamepadRemoved {
+                    pad: buf.get_u32_le(),
+                })
+            }
+            Self::TAG_TEXT => {
+                ensure_remaining(buf, 4)?;
+                let len = buf.get_u32_le() as usize;
+                if len > Self::MAX_TEXT_LENGTH {
+                    return Err(LodestarPacketParsingError::InvalidField);
+                }
+                ensure_remaining(buf, len)?;
+                let raw = buf.copy_to_bytes(len);
+                let text = std::str::from_utf8(&raw)
+                    .map_err(|_| LodestarPacketParsingError::InvalidField)?;
+                Ok(Self::Text(text.to_owned()))
+            }
+            _ => Err(LodestarPacketParsingError::InvalidField),
+        }
+    }
+}
+
+/// An input event along with the time the client observed it
+#[derive(Clone, Debug, PartialEq)]
+pub struct LodestarInputEvent {
+    /// Microseconds on the client's monotonic clock
+    pub timestamp: u64,
+    pub input: LodestarInput,
+}
+
+impl LodestarInputEvent {
+    /// The tag byte and timestamp preceding every event's payload
+    const PREFIX_LEN: usize = 9;
+    /// The prefix plus the smallest payload
+    const MIN_ENCODED_LEN: usize = Self::PREFIX_LEN + 3;
+}
+
+/// A batch of input events, encoded as a `u64` count followed by the events in order
+///
+/// Every event is a tag byte, a `u64` timestamp and a payload depending on the tag.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LodestarInputPacket {
+    pub events: Vec<LodestarInputEvent>,
+}
+
+impl Encode for LodestarInputPacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::Input;
+
+    fn encoded_len(&self) -> usize {
+        8 + self
+            .events
+            .iter()
+            .map(|evt| LodestarInputEvent::PREFIX_LEN + evt.input.encoded_len())
+            .sum::<usize>()
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u64_le(self.events.len() as u64);
+        for evt in self.events.iter() {
+            buf.put_u8(evt.input.tag());
+            buf.put_u64_le(evt.timestamp);
+            evt.input.encode(buf);
+        }
+    }
+}
+
+impl Decode for LodestarInputPacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        ensure_remaining(buf, 8)?;
+        let event_count = usize::try_from(buf.get_u64_le())
+            .map_err(|_| LodestarPacketParsingError::InvalidPacketLength)?;
+        let min_len = event_count
+            .checked_mul(LodestarInputEvent::MIN_ENCODED_LEN)
+            .ok_or(LodestarPacketParsingError::InvalidPacketLength)?;
+        ensure_remaining(buf, min_len)?;
+
+        let mut events = Vec::with_capacity(event_count);
+        for _ in 0..event_count {
+            ensure_remaining(buf, LodestarInputEvent::PREFIX_LEN)?;
+            let tag = buf.get_u8();
+            let timestamp = buf.get_u64_le();
+            events.push(LodestarInputEvent {
+                timestamp,
+                input: LodestarInput::decode(tag, buf)?,
+            });
+        }
+
+        Ok(Self { events })
+    }
+}
+
+/// An encoded video frame, sent by the server on its own unidirectional stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LodestarVideoFramePacket {
+    /// The desktop this frame belongs to
+    pub loded_id: u64,
+    /// Presentation timestamp in nanoseconds, `u64::MAX` if unknown
+    pub pts: u64,
+    /// Whether the frame can be decoded without any preceding frames
+    pub keyframe: bool,
+    /// The encoded frame, filling the rest of the packet
+    pub data: Bytes,
+}
+
+impl LodestarVideoFramePacket {
+    const FLAG_KEYFRAME: u8 = 1 << 0;
+
+    /// `loded_id`, `pts` and the flags byte
+    const HEADER_LEN: usize = 17;
+}
+
+impl Encode for LodestarVideoFramePacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::VideoFrame;
+
+    fn encoded_len(&self) -> usize {
+        Self::HEADER_LEN + self.data.len()
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u64_le(self.loded_id);
+        buf.put_u64_le(self.pts);
+        buf.put_u8(if self.keyframe {
+            Self::FLAG_KEYFRAME
+        } else {
+            0
+        });
+        buf.put_slice(&self.data);
+    }
+}
+
+impl Decode for LodestarVideoFramePacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        ensure_remaining(buf, Self::HEADER_LEN)?;
+        let loded_id = buf.get_u64_le();
+        let pts = buf.get_u64_le();
+        let flags = buf.get_u8();
+        if flags & !Self::FLAG_KEYFRAME != 0 {
+            return Err(LodestarPacketParsingError::InvalidField);
+        }
+        Ok(Self {
+            loded_id,
+            pts,
+            keyframe: flags & Self::FLAG_KEYFRAME != 0,
+            data: buf.copy_to_bytes(buf.remaining()),
+        })
+    }
+}
+
+/// Asks the server to encode the next frame of a desktop as a keyframe, for example after the
+/// client lost frames it can't decode without
+///
+/// Requests arriving faster than the server allows are ignored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LodestarKeyframeRequestPacket {
+    pub loded_id: u64,
+}
+
+impl Encode for LodestarKeyframeRequestPacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::KeyframeRequest;
+
+    fn encoded_len(&self) -> usize {
+        8
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u64_le(self.loded_id);
+    }
+}
+
+impl Decode for LodestarKeyframeRequestPacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        ensure_remaining(buf, 8)?;
+        Ok(Self {
+            loded_id: buf.get_u64_le(),
+        })
+    }
+}
+
+/// A clipboard format both sides can exchange
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ClipboardMime {
+    /// UTF-8 text
+    Text = 0,
+    /// UTF-8 HTML
+    Html = 1,
+    Png = 2,
+}
+
+impl TryFrom<u8> for ClipboardMime {
+    type Error = LodestarPacketParsingError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Text),
+            1 => Ok(Self::Html),
+            2 => Ok(Self::Png),
+            _ => Err(LodestarPacketParsingError::InvalidField),
+        }
+    }
+}
+
+impl ClipboardMime {
+    pub const ALL: [Self; 3] = [Self::Text, Self::Html, Self::Png];
+
+    /// The MIME types this format goes by on the host, the preferred one first
+    pub fn mime_types(self) -> &'static [&'static str] {
+        match self {
+            Self::Text => &["text/plain;charset=utf-8", "UTF8_STRING", "text/plain"],
+            Self::Html => &["text/html"],
+            Self::Png => &["image/png"],
+        }
+    }
+
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|mime| mime.mime_types().contains(&mime_type))
+    }
+
+    fn is_text(self) -> bool {
+        matches!(self, Self::Text | Self::Html)
+    }
+
+    /// Reads a list of distinct formats, prefixed by its `u8` length
+    fn decode_list<B: Buf>(buf: &mut B) -> Result<Vec<Self>> {
+        ensure_remaining(buf, 1)?;
+        let count = buf.get_u8() as usize;
+        if count > Self::ALL.len() {
+            return Err(LodestarPacketParsingError::InvalidField);
+        }
+        ensure_remaining(buf, count)?;
+
+        let mut mime_types = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mime = Self::try_from(buf.get_u8())?;
+            if mime_types.contains(&mime) {
+                return Err(LodestarPacketParsingError::InvalidField);
+            }
+            mime_types.push(mime);
+        }
+        Ok(mime_types)
+    }
+}
+
+/// Tells the client the host's clipboard changed and which formats it can be pulled in
+///
+/// An empty list means the host's clipboard holds nothing loded can share.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LodestarClipboardOfferPacket {
+    pub mime_types: Vec<ClipboardMime>,
+}
+
+impl Encode for LodestarClipboardOfferPacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::ClipboardOffer;
+
+    fn encoded_len(&self) -> usize {
+        1 + self.mime_types.len()
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(self.mime_types.len() as u8);
+        for mime in &self.mime_types {
+            buf.put_u8(*mime as u8);
+        }
+    }
+}
+
+impl Decode for LodestarClipboardOfferPacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        Ok(Self {
+            mime_types: ClipboardMime::decode_list(buf)?,
+        })
+    }
+}
+
+/// Pulls the host's clipboard in one format, answered with a [LodestarClipboardDataPacket]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LodestarClipboardRequestPacket {
+    pub mime_type: ClipboardMime,
+}
+
+impl Encode for LodestarClipboardRequestPacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::ClipboardRequest;
+
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(self.mime_type as u8);
+    }
+}
+
+impl Decode for LodestarClipboardRequestPacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        ensure_remaining(buf, 1)?;
+        Ok(Self {
+            mime_type: ClipboardMime::try_from(buf.get_u8())?,
+        })
+    }
+}
+
+/// The clipboard's contents in one format
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LodestarClipboardEntry {
+    pub mime_type: ClipboardMime,
+    /// Valid UTF-8 for the text formats
+    pub data: Bytes,
+}
+
+/// Clipboard contents, pushed by the client to replace the host's clipboard or sent by the
+/// server in answer to a [LodestarClipboardRequestPacket]
+///
+/// Every format appears at most once and together they hold at most
+/// [LodestarClipboardDataPacket::MAX_LENGTH] bytes, senders have to leave out whatever doesn't
+/// fit. An answer without entries means the host's clipboard couldn't be read in that format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LodestarClipboardDataPacket {
+    pub entries: Vec<LodestarClipboardEntry>,
+}
+
+impl LodestarClipboardDataPacket {
+    pub const MAX_LENGTH: usize = 512 * 1024;
+
+    /// The format and length in front of each entry's data
+    const ENTRY_HEADER_LEN: usize = 5;
+}
+
+impl Encode for LodestarClipboardDataPacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::ClipboardData;
+
+    fn encoded_len(&self) -> usize {
+        1 + self
+            .entries
+            .iter()
+            .map(|entry| Self::ENTRY_HEADER_LEN + entry.data.len())
+            .sum::<usize>()
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(self.entries.len() as u8);
+        for entry in &self.entries {
+            buf.put_u8(entry.mime_type as u8);
+            buf.put_u32_le(entry.data.len() as u32);
+            buf.put_slice(&entry.data);
+        }
+    }
+}
+
+impl Decode for LodestarClipboardDataPacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        ensure_remaining(buf, 1)?;
+        let count = buf.get_u8() as usize;
+        if count > ClipboardMime::ALL.len() {
+            return Err(LodestarPacketParsingError::InvalidField);
+        }
+
+        let mut entries = Vec::<LodestarClipboardEntry>::with_capacity(count);
+        let mut total = 0;
+        for _ in 0..count {
+            ensure_remaining(buf, Self::ENTRY_HEADER_LEN)?;
+            let mime_type = ClipboardMime::try_from(buf.get_u8())?;
+            if entries.iter().any(|entry| entry.mime_type == mime_type) {
+                return Err(LodestarPacketParsingError::InvalidField);
+            }
+
+            let len = buf.get_u32_le() as usize;
+            total += len;
+            if total > Self::MAX_LENGTH {
+                return Err(LodestarPacketParsingError::InvalidField);
+            }
+            ensure_remaining(buf, len)?;
+            let data = buf.copy_to_bytes(len);
+            if mime_type.is_text() && std::str::from_utf8(&data).is_err() {
+                return Err(LodestarPacketParsingError::InvalidField);
+            }
+
+            entries.push(LodestarClipboardEntry { mime_type, data });
+        }
+
+        Ok(Self { entries })
+    }
+}
+
+/// Why the desktops stopped being shared, or the video of one of them stopped
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LodestarCloseReason {
+    /// The user or the compositor stopped the portal session
+    Revoked = 0,
+    /// A new portal session couldn't be opened after the last one was revoked
+    ReopenFailed = 1,
+    /// The pipeline of the desktop the client watched failed or ran out, the other desktops
+    /// are still shared
+    PipelineFailed = 2,
+}
+
+impl TryFrom<u8> for LodestarCloseReason {
+    type Error = LodestarPacketParsingError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Revoked),
+            1 => Ok(Self::ReopenFailed),
+            2 => Ok(Self::PipelineFailed),
+            _ => Err(LodestarPacketParsingError::InvalidField),
+        }
+    }
+}
+
+/// Tells the client that none of the desktops are shared anymore and video stopped
+///
+/// With [LodestarCloseReason::PipelineFailed] only the video stopped, and the client may switch
+/// to another desktop. If the server is `reopening` the session, a new [LodestarDesktopPacket] follows once it
+/// succeeded, from which the client picks a source again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LodestarSessionClosedPacket {
+    pub reason: LodestarCloseReason,
+    pub reopening: bool,
+}
+
+impl Encode for LodestarSessionClosedPacket {
+    const PACKET_TYPE: LodestarPacketType = LodestarPacketType::SessionClosed;
+
+    fn encoded_len(&self) -> usize {
+        2
+    }
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(self.reason as u8);
+        buf.put_u8(self.reopening as u8);
+    }
+}
+
+impl Decode for LodestarSessionClosedPacket {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        ensure_remaining(buf, 2)?;
+        let reason = LodestarCloseReason::try_from(buf.get_u8())?;
+        let reopening = match buf.get_u8() {
+            0 => false,
+            1 => true,
+            _ => return Err(LodestarPacketParsingError::InvalidField),
+        };
+        Ok(Self { reason, reopening })
     }
 }
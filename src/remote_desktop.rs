@@ -0,0 +1,206 @@
+use std::{collections::HashMap, ops::BitOr};
+
+use serde::{Deserialize, Serialize};
+use zbus::{dbus_proxy, fdo::Result};
+use zvariant::{DeserializeDict, ObjectPath, SerializeDict, Type, Value};
+
+use crate::screencast::{CreateSessionOptions, PersistMode, StartCastOptions, Stream};
+use crate::session_request::*;
+use crate::unique_token::UniqueToken;
+
+/// The input devices a remote desktop session may control
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[zvariant(signature = "u")]
+#[repr(transparent)]
+pub struct DeviceType(pub u32);
+
+impl DeviceType {
+    pub const KEYBOARD: Self = Self(1 << 0);
+    pub const POINTER: Self = Self(1 << 1);
+    pub const TOUCHSCREEN: Self = Self(1 << 2);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for DeviceType {
+    type Output = DeviceType;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Options for the SelectDevices method
+///
+/// Permissions of a remote desktop session are persisted here rather than when selecting its
+/// screencast sources.
+#[derive(DeserializeDict, SerializeDict, Type, Debug)]
+#[zvariant(signature = "dict")]
+pub struct SelectDevicesOptions {
+    /// String to use as last element of handle
+    pub handle_token: UniqueToken,
+    /// Types of input device to control (Use [DeviceType])
+    pub types: Option<DeviceType>,
+    /// The restore token
+    pub restore_token: Option<String>,
+    /// Permission persistence mode (Use [PersistMode])
+    pub persist_mode: Option<PersistMode>,
+}
+
+#[derive(SerializeDict, DeserializeDict, Type, Debug)]
+#[zvariant(signature = "dict")]
+pub struct StartRemoteDesktopResponse {
+    /// The devices the user allowed to be controlled
+    pub devices: Option<DeviceType>,
+    /// Present if screencast sources were selected on the session
+    pub streams: Option<Vec<Stream>>,
+    /// Whether the clipboard requested before starting may be used
+    pub clipboard_enabled: Option<bool>,
+    pub restore_token: Option<String>,
+}
+
+/// The axis of a discrete scroll
+#[repr(u32)]
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[zvariant(signature = "u")]
+pub enum PointerAxis {
+    Vertical = 0,
+    Horizontal = 1,
+}
+
+/// Whether a key or button went down or up
+#[repr(u32)]
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[zvariant(signature = "u")]
+pub enum InputState {
+    Released = 0,
+    Pressed = 1,
+}
+
+impl From<bool> for InputState {
+    fn from(pressed: bool) -> Self {
+        if pressed {
+            Self::Pressed
+        } else {
+            Self::Released
+        }
+    }
+}
+
+/// Sessions in which the compositor's input devices are controlled, which can also select
+/// screencast sources so a single consent covers both
+///
+/// The notify methods take no options yet, an empty map is passed for them.
+#[dbus_proxy(interface = "org.freedesktop.portal.RemoteDesktop")]
+pub trait RemoteDesktop {
+    #[dbus_proxy(object = "Request")]
+    fn create_session(&self, options: &CreateSessionOptions);
+
+    #[dbus_proxy(object = "Request")]
+    fn select_devices(&self, session_handle: &ObjectPath<'_>, options: &SelectDevicesOptions);
+
+    #[dbus_proxy(object = "Request")]
+    fn start(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        parent_window: &str,
+        options: &StartCastOptions,
+    );
+
+    fn notify_pointer_motion(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+        dx: f64,
+        dy: f64,
+    ) -> Result<()>;
+
+    /// `x` and `y` are in the logical coordinates of the screencast stream `stream`
+    fn notify_pointer_motion_absolute(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+        stream: u32,
+        x: f64,
+        y: f64,
+    ) -> Result<()>;
+
+    /// `button` is an evdev button code
+    fn notify_pointer_button(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+        button: i32,
+        state: InputState,
+    ) -> Result<()>;
+
+    fn notify_pointer_axis(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+        dx: f64,
+        dy: f64,
+    ) -> Result<()>;
+
+    /// Positive steps scroll down or right
+    fn notify_pointer_axis_discrete(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+        axis: PointerAxis,
+        steps: i32,
+    ) -> Result<()>;
+
+    /// `keycode` is an evdev key code
+    fn notify_keyboard_keycode(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+        keycode: i32,
+        state: InputState,
+    ) -> Result<()>;
+
+    fn notify_keyboard_keysym(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+        keysym: i32,
+        state: InputState,
+    ) -> Result<()>;
+
+    fn notify_touch_down(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+        stream: u32,
+        slot: u32,
+        x: f64,
+        y: f64,
+    ) -> Result<()>;
+
+    fn notify_touch_motion(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+        stream: u32,
+        slot: u32,
+        x: f64,
+        y: f64,
+    ) -> Result<()>;
+
+    fn notify_touch_up(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+        slot: u32,
+    ) -> Result<()>;
+
+    /// This returns [DeviceType] as a u32
+    #[dbus_proxy(property)]
+    fn available_device_types(&self) -> Result<u32>;
+
+    #[dbus_proxy(property)]
+    fn version(&self) -> zbus::Result<u32>;
+}
@@ -118,7 +118,7 @@ impl StartCastOptions {
 #[derive(SerializeDict, DeserializeDict, Type, Debug)]
 #[zvariant(signature = "dict")]
 pub struct StartCastResponse {
-    pub streams: Vec<Stream>,
+    pub streams: Option<Vec<Stream>>,
     pub restore_token: Option<String>,
 }
 
@@ -1,12 +1,41 @@
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
-use zvariant::{OwnedValue, Type};
+use zvariant::{OwnedObjectPath, OwnedValue, Type};
 
 use zbus::{dbus_proxy, fdo::Result, Connection};
 
 use crate::{unique_token::UniqueToken, DESTINATION};
 
+#[derive(thiserror::Error, Debug)]
+pub enum PortalError {
+    #[error("The connection to the bus has no unique name to derive request paths from")]
+    NoUniqueName,
+    #[error("The user cancelled the interaction")]
+    Cancelled,
+    #[error("The interaction ended without the user deciding")]
+    UnknownEnded,
+    #[error("The portal answered with unknown response code {0}")]
+    UnknownResponse(u32),
+    #[error("The request was closed without a response")]
+    NoResponse,
+    #[error("The portal's response is missing {0}")]
+    MissingField(&'static str),
+    #[error("The portal's response has an invalid {0}")]
+    InvalidField(&'static str),
+    #[error(
+        "The portal answered on {} rather than {}",
+        found.as_str(),
+        expected.as_str()
+    )]
+    PathMismatch {
+        expected: OwnedObjectPath,
+        found: OwnedObjectPath,
+    },
+    #[error("The bus failed: {0}")]
+    Bus(#[from] zbus::Error),
+}
+
 #[dbus_proxy(
     interface = "org.freedesktop.portal.Session",
     default_service = "org.freedesktop.portal.Session",
@@ -31,6 +60,18 @@ pub enum ResponseCode {
     UnknownEnded = 2,
 }
 
+impl ResponseCode {
+    /// Fails unless `code` is [ResponseCode::Success]
+    pub fn check(code: u32) -> std::result::Result<(), PortalError> {
+        match code {
+            c if c == Self::Success as u32 => Ok(()),
+            c if c == Self::Cancelled as u32 => Err(PortalError::Cancelled),
+            c if c == Self::UnknownEnded as u32 => Err(PortalError::UnknownEnded),
+            c => Err(PortalError::UnknownResponse(c)),
+        }
+    }
+}
+
 #[dbus_proxy(interface = "org.freedesktop.portal.Request")]
 pub trait Request {
     fn close(&self) -> Result<()>;
@@ -40,60 +81,68 @@ pub trait Request {
 }
 
 impl<'a> RequestProxy<'a> {
-    pub async fn from_unique(conn: &Connection, handle: &UniqueToken) -> RequestProxy<'a> {
-        RequestProxy::builder(conn)
-            .path(get_path_by_unique_id("request", conn, handle).await)
-            .unwrap()
-            .destination(DESTINATION)
-            .unwrap()
+    pub async fn from_unique(
+        conn: &Connection,
+        handle: &UniqueToken,
+    ) -> std::result::Result<RequestProxy<'a>, PortalError> {
+        Ok(RequestProxy::builder(conn)
+            .path(get_path_by_unique_id("request", conn, handle)?)?
+            .destination(DESTINATION)?
             .build()
-            .await
-            .unwrap()
+            .await?)
     }
 }
 
-pub async fn get_path_by_unique_id(ty: &str, conn: &Connection, handle: &UniqueToken) -> String {
-    format!(
+pub fn get_path_by_unique_id(
+    ty: &str,
+    conn: &Connection,
+    handle: &UniqueToken,
+) -> std::result::Result<String, PortalError> {
+    let unique_name = conn.unique_name().ok_or(PortalError::NoUniqueName)?;
+    Ok(format!(
         "/org/freedesktop/portal/desktop/{ty}/{}/{handle}",
-        conn.unique_name()
-            .unwrap()
-            .trim_start_matches(':')
-            .replace('.', "_")
-    )
+        unique_name.trim_start_matches(':').replace('.', "_")
+    ))
 }
 
 #[macro_export]
 /// Future, request, desired type
+///
+/// Evaluates to a `Result<$ty, PortalError>`, the results are only deserialized as `$ty` once
+/// the interaction succeeded.
 macro_rules! call_and_receive_response {
-    ($future:expr, $req:ident, $ty:ty) => {{
-        use ::futures::StreamExt;
-        let mut stream = $req.receive_response().await?;
-        let (res, rp): ($ty, $crate::session_request::RequestProxy) = futures::try_join!(
-            async {
-                let res_item: $crate::session_request::Response = stream
-                    .next()
-                    .await
-                    .ok_or(::zbus::fdo::Error::ZBus(::zbus::Error::InvalidReply))?;
-
-                let (res_code, res) = res_item
-                    .body::<(u32, $ty)>()
-                    .map_err(|e| ::zbus::fdo::Error::ZBus(e))?;
-
-                if res_code == 0 {
-                    Ok(res)
-                } else {
-                    Err(::zbus::fdo::Error::Failed(
-                        "Interaction Cancelled: {res_code}".to_owned(),
-                    ))
-                }
-            },
-            async { $future.await.map_err(|e| ::zbus::fdo::Error::ZBus(e)) }
-        )?;
-
-        if $req.path() == rp.path() {
-            Ok(res)
-        } else {
-            Err(::zbus::fdo::Error::ZBus(::zbus::Error::InvalidReply))
+    ($future:expr, $req:ident, $ty:ty) => {
+        async {
+            use ::futures::StreamExt;
+            use $crate::session_request::PortalError;
+
+            let mut stream = $req.receive_response().await?;
+            let (res, rp): ($ty, $crate::session_request::RequestProxy) = futures::try_join!(
+                async {
+                    let res_item: $crate::session_request::Response =
+                        stream.next().await.ok_or(PortalError::NoResponse)?;
+
+                    let (res_code, _) = res_item.body::<(
+                        u32,
+                        ::std::collections::HashMap<String, ::zvariant::OwnedValue>,
+                    )>()?;
+                    $crate::session_request::ResponseCode::check(res_code)?;
+
+                    let (_, res) = res_item.body::<(u32, $ty)>()?;
+                    Ok::<$ty, PortalError>(res)
+                },
+                async { Ok::<_, PortalError>($future.await?) }
+            )?;
+
+            if $req.path() == rp.path() {
+                Ok(res)
+            } else {
+                Err(PortalError::PathMismatch {
+                    expected: $req.path().to_owned().into(),
+                    found: rp.path().to_owned().into(),
+                })
+            }
         }
-    }};
+        .await
+    };
 }
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use loded::adaptation::{EncoderSettings, LinkStats, RateController};
+
+fn healthy(rtt_ms: u64) -> LinkStats {
+    LinkStats {
+        rtt: Duration::from_millis(rtt_ms),
+        cwnd: 1 << 20,
+        congestion_events: 0,
+        dropped_frames: 0,
+    }
+}
+
+#[test]
+fn bitrate_grows_on_a_healthy_link() {
+    let mut controller = RateController::new(1920, 1080);
+    let start = controller.settings();
+    assert_eq!(start.bitrate, RateController::START_BITRATE);
+
+    for _ in 0..200 {
+        controller.update(healthy(20));
+    }
+    assert_eq!(controller.settings().bitrate, RateController::MAX_BITRATE);
+    assert_eq!(controller.settings().downscale, 1);
+    assert_eq!(controller.settings().framerate, 60);
+}
+
+#[test]
+fn congestion_cuts_the_bitrate_down_to_the_window() {
+    let mut controller = RateController::new(1920, 1080);
+    controller.update(healthy(20));
+    let before = controller.settings().bitrate;
+
+    let settings = controller
+        .update(LinkStats {
+            congestion_events: 1,
+            ..healthy(20)
+        })
+        .unwrap();
+    assert!(settings.bitrate <= before / 10 * 7);
+
+    // 25 kB per 100 ms round trip is 2000 kbit/s
+    let settings = controller
+        .update(LinkStats {
+            rtt: Duration::from_millis(100),
+            cwnd: 25_000,
+            congestion_events: 2,
+            dropped_frames: 0,
+        })
+        .unwrap();
+    assert_eq!(settings.bitrate, 2_000);
+}
+
+#[test]
+fn growing_rtt_and_dropped_frames_count_as_congestion() {
+    let mut controller = RateController::new(1920, 1080);
+    controller.update(healthy(20));
+    let before = controller.settings().bitrate;
+    controller.update(healthy(50));
+    assert!(controller.settings().bitrate < before);
+
+    let before = controller.settings().bitrate;
+    controller.update(LinkStats {
+        dropped_frames: 3,
+        ..healthy(20)
+    });
+    assert!(controller.settings().bitrate < before);
+}
+
+#[test]
+fn starved_links_lower_framerate_then_resolution() {
+    let mut controller = RateController::new(1920, 1080);
+    let mut congestion_events = 0;
+    let mut seen = vec![controller.settings()];
+    for _ in 0..30 {
+        congestion_events += 1;
+        if let Some(settings) = controller.update(LinkStats {
+            congestion_events,
+            ..healthy(20)
+        }) {
+            seen.push(settings);
+        }
+    }
+
+    let last = *seen.last().unwrap();
+    assert_eq!(last.bitrate, RateController::MIN_BITRATE);
+    assert!(last.downscale > 1);
+    assert_eq!(last.framerate, 15);
+    // Quality only ever went down
+    for pair in seen.windows(2) {
+        let level = |s: &EncoderSettings| {
+            RateController::LADDER
+                .iter()
+                .position(|l| *l == (s.downscale, s.framerate))
+                .unwrap()
+        };
+        assert!(level(&pair[0]) <= level(&pair[1]));
+    }
+
+    // and comes back once the link recovers
+    for _ in 0..200 {
+        controller.update(LinkStats {
+            congestion_events,
+            ..healthy(20)
+        });
+    }
+    assert_eq!(controller.settings().downscale, 1);
+}
+
+#[test]
+fn scaled_sizes_stay_even() {
+    let settings = EncoderSettings {
+        bitrate: 1000,
+        framerate: 30,
+        downscale: 4,
+    };
+    assert_eq!(settings.scaled_size(1366, 767), (340, 190));
+    assert_eq!(settings.scaled_size(3, 3), (2, 2));
+}
+
+#[test]
+fn zero_downscale_keeps_the_full_size() {
+    let settings = EncoderSettings {
+        bitrate: 1000,
+        framerate: 30,
+        downscale: 0,
+    };
+    assert_eq!(settings.scaled_size(1366, 767), (1366, 766));
+}
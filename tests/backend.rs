@@ -0,0 +1,482 @@
+use evdev::{EventType, InputEvent, Key, RelativeAxisType};
+use tokio::sync::{broadcast, mpsc::UnboundedReceiver};
+
+use loded::{
+    protocol::{LodestarCapabilities, LodestarInput, LodestarInputEvent, LodestarInputPacket},
+    testing::{
+        input_manager_events, ClientEvent, DesktopRect, GamepadEvent, GamepadState, KeyEvent,
+        MouseButtonEvent, MouseMoveEvent, PointerAxis, PortalCall, RecordingBackend, TouchEvent,
+        TouchSlots,
+    },
+    Desktop, DesktopLayout, DeviceType, InputBackend, InputDevice, InputManager, KeyDirection,
+    KeymapNames, PortalBackend, TextTyper,
+};
+
+const CLIENT: u64 = 1;
+
+fn manager(backend: impl InputBackend + 'static, typer: TextTyper) -> InputManager {
+    let (_, die_handle) = broadcast::channel(1);
+    InputManager::new(die_handle, Box::new(backend), typer).0
+}
+
+fn layout() -> DesktopLayout {
+    let rect = |x| DesktopRect {
+        x,
+        y: 0,
+        width: 1920,
+        height: 1080,
+    };
+    DesktopLayout::from_rects([(0, rect(0)), (1, rect(1920))])
+}
+
+/// The only desktop of a session that was reopened after the one of [layout] closed
+fn reopened_desktop() -> Desktop {
+    Desktop {
+        id: "DP-2".to_owned(),
+        loded_id: 2,
+        pipewire_path: 52,
+        width: 2560,
+        height: 1440,
+        position: Some((0, 0)),
+        stream: None,
+    }
+}
+
+fn portal() -> (PortalBackend, UnboundedReceiver<PortalCall>) {
+    PortalBackend::from_streams(
+        layout(),
+        [(0, 40), (1, 41)],
+        DeviceType::KEYBOARD | DeviceType::POINTER | DeviceType::TOUCHSCREEN,
+    )
+}
+
+fn calls(rx: &mut UnboundedReceiver<PortalCall>) -> Vec<PortalCall> {
+    let mut calls = Vec::new();
+    while let Ok(call) = rx.try_recv() {
+        calls.push(call);
+    }
+    calls
+}
+
+fn frame(events: &[InputEvent]) -> Vec<(EventType, u16, i32)> {
+    events
+        .iter()
+        .map(|evt| (evt.event_type(), evt.code(), evt.value()))
+        .collect()
+}
+
+#[test]
+fn leaving_clients_are_released_through_the_backend() {
+    let backend = RecordingBackend::default();
+    let input = manager(backend.clone(), TextTyper::default());
+
+    let press = KeyEvent {
+        key: Key::KEY_A,
+        direction: KeyDirection::Down,
+    };
+    input.send_keyboard_events(CLIENT, &[press]).unwrap();
+    input
+        .send_mouse_events(
+            CLIENT,
+            &[],
+            &[MouseButtonEvent {
+                key: Key::BTN_LEFT,
+                direction: KeyDirection::Down,
+            }],
+        )
+        .unwrap();
+    input
+        .send_gamepad_event(
+            CLIENT,
+            &GamepadEvent {
+                pad: 0,
+                state: Some(GamepadState::default()),
+            },
+        )
+        .unwrap();
+    assert_eq!(backend.frames().len(), 3);
+
+    input.release_client(CLIENT).unwrap();
+
+    let frames = backend.frames();
+    let released = frames[3..]
+        .iter()
+        .map(|(device, events)| (*device, frame(events)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        released,
+        [
+            (
+                InputDevice::Keyboard,
+                vec![(EventType::KEY, Key::KEY_A.code(), 0)]
+            ),
+            (
+                InputDevice::Mouse,
+                vec![(EventType::KEY, Key::BTN_LEFT.code(), 0)]
+            ),
+        ]
+    );
+    assert_eq!(
+        backend.removed(),
+        [InputDevice::Gamepad {
+            client: CLIENT,
+            pad: 0
+        }]
+    );
+
+    input.release_client(CLIENT).unwrap();
+    assert_eq!(backend.frames().len(), 5);
+}
+
+#[test]
+fn taps_in_one_packet_get_a_frame_per_transition() {
+    let backend = RecordingBackend::default();
+    let input = manager(backend.clone(), TextTyper::default());
+
+    let key = |direction| LodestarInputEvent {
+        timestamp: 0,
+        input: LodestarInput::Key(KeyEvent {
+            key: Key::KEY_A,
+            direction,
+        }),
+    };
+    let packet = LodestarInputPacket {
+        events: vec![key(KeyDirection::Down), key(KeyDirection::Up)],
+    };
+    for event in input_manager_events(packet.events, LodestarCapabilities::KEYBOARD, &layout()) {
+        input.handle(ClientEvent {
+            client: CLIENT,
+            event,
+        });
+    }
+
+    let frames = backend
+        .frames()
+        .into_iter()
+        .map(|(device, events)| (device, frame(&events)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        frames,
+        [
+            (
+                InputDevice::Keyboard,
+                vec![(EventType::KEY, Key::KEY_A.code(), 1)]
+            ),
+            (
+                InputDevice::Keyboard,
+                vec![(EventType::KEY, Key::KEY_A.code(), 0)]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn clicks_get_a_frame_per_transition() {
+    let backend = RecordingBackend::default();
+    let input = manager(backend.clone(), TextTyper::default());
+
+    let button = |direction| MouseButtonEvent {
+        key: Key::BTN_LEFT,
+        direction,
+    };
+    input
+        .send_mouse_events(
+            CLIENT,
+            &[],
+            &[button(KeyDirection::Down), button(KeyDirection::Up)],
+        )
+        .unwrap();
+
+    let frames = backend
+        .frames()
+        .into_iter()
+        .map(|(_, events)| frame(&events))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        frames,
+        [
+            vec![(EventType::KEY, Key::BTN_LEFT.code(), 1)],
+            vec![(EventType::KEY, Key::BTN_LEFT.code(), 0)],
+        ]
+    );
+}
+
+#[test]
+fn text_is_typed_one_key_per_frame() {
+    let backend = RecordingBackend::default();
+    let names = KeymapNames {
+        layout: "us".to_owned(),
+        ..Default::default()
+    };
+    let input = manager(backend.clone(), TextTyper::new(&names).unwrap());
+
+    input.send_text("Ab").unwrap();
+
+    let frames = backend
+        .frames()
+        .into_iter()
+        .map(|(device, events)| {
+            assert_eq!(device, InputDevice::Keyboard);
+            frame(&events)
+        })
+        .collect::<Vec<_>>();
+    let key = |key: Key, value| vec![(EventType::KEY, key.code(), value)];
+    assert_eq!(
+        frames,
+        [
+            key(Key::KEY_LEFTSHIFT, 1),
+            key(Key::KEY_A, 1),
+            key(Key::KEY_A, 0),
+            key(Key::KEY_LEFTSHIFT, 0),
+            key(Key::KEY_B, 1),
+            key(Key::KEY_B, 0),
+        ]
+    );
+}
+
+#[test]
+fn held_modifiers_are_lifted_while_text_is_typed() {
+    let backend = RecordingBackend::default();
+    let names = KeymapNames {
+        layout: "us".to_owned(),
+        ..Default::default()
+    };
+    let input = manager(backend.clone(), TextTyper::new(&names).unwrap());
+
+    let ctrl = KeyEvent {
+        key: Key::KEY_LEFTCTRL,
+        direction: KeyDirection::Down,
+    };
+    input.send_keyboard_events(CLIENT, &[ctrl]).unwrap();
+    input.send_text("a").unwrap();
+
+    let frames = backend
+        .frames()
+        .into_iter()
+        .skip(1)
+        .map(|(_, events)| frame(&events))
+        .collect::<Vec<_>>();
+    let key = |key: Key, value| vec![(EventType::KEY, key.code(), value)];
+    assert_eq!(
+        frames,
+        [
+            key(Key::KEY_LEFTCTRL, 0),
+            key(Key::KEY_A, 1),
+            key(Key::KEY_A, 0),
+            key(Key::KEY_LEFTCTRL, 1),
+        ]
+    );
+}
+
+#[test]
+fn portal_keys_leave_repeating_to_the_compositor() {
+    let (mut backend, mut rx) = portal();
+    let events = [
+        KeyDirection::Down,
+        KeyDirection::RepeatingDown,
+        KeyDirection::Up,
+    ]
+    .map(|direction| {
+        KeyEvent {
+            key: Key::KEY_Q,
+            direction,
+        }
+        .into()
+    });
+    backend.emit(InputDevice::Keyboard, &events).unwrap();
+
+    let keycode = Key::KEY_Q.code().into();
+    assert_eq!(
+        calls(&mut rx),
+        [
+            PortalCall::KeyboardKeycode {
+                keycode,
+                pressed: true
+            },
+            PortalCall::KeyboardKeycode {
+                keycode,
+                pressed: false
+            },
+        ]
+    );
+}
+
+#[test]
+fn portal_motion_lands_before_clicks_and_wheels_flip() {
+    let (mut backend, mut rx) = portal();
+    let mut events = MouseMoveEvent::new(3, -4, 0, 0).get_input_events();
+    events.extend(MouseMoveEvent::new(2, 1, 0, 0).get_input_events());
+    events.push(InputEvent::new(
+        EventType::RELATIVE,
+        RelativeAxisType::REL_WHEEL.0,
+        2,
+    ));
+    events.push(InputEvent::new(
+        EventType::RELATIVE,
+        RelativeAxisType::REL_HWHEEL.0,
+        -1,
+    ));
+    events.push(
+        MouseButtonEvent {
+            key: Key::BTN_RIGHT,
+            direction: KeyDirection::Down,
+        }
+        .into(),
+    );
+    backend.emit(InputDevice::Mouse, &events).unwrap();
+
+    assert_eq!(
+        calls(&mut rx),
+        [
+            PortalCall::PointerAxisDiscrete {
+                axis: PointerAxis::Vertical,
+                steps: -2
+            },
+            PortalCall::PointerAxisDiscrete {
+                axis: PointerAxis::Horizontal,
+                steps: -1
+            },
+            PortalCall::PointerMotion { dx: 5.0, dy: -3.0 },
+            PortalCall::PointerButton {
+                button: Key::BTN_RIGHT.code().into(),
+                pressed: true
+            },
+        ]
+    );
+}
+
+#[test]
+fn portal_absolute_motion_targets_the_stream_under_the_pointer() {
+    let (mut backend, mut rx) = portal();
+    let layout = layout();
+    let position = layout.to_absolute(1, 100, 200).unwrap();
+    backend
+        .emit(InputDevice::Tablet, &position.get_input_events())
+        .unwrap();
+
+    assert_eq!(
+        calls(&mut rx),
+        [PortalCall::PointerMotionAbsolute {
+            stream: 41,
+            x: 100.0,
+            y: 200.0
+        }]
+    );
+}
+
+#[test]
+fn portal_touches_follow_their_slots() {
+    let (mut backend, mut rx) = portal();
+    let layout = layout();
+    let mut slots = TouchSlots::default();
+    let mut touch = |event: TouchEvent| {
+        let events = slots.events(CLIENT, &event);
+        backend.emit(InputDevice::Touchscreen, &events).unwrap();
+    };
+
+    touch(TouchEvent::Down {
+        contact: 7,
+        position: layout.to_absolute(0, 10, 20).unwrap(),
+    });
+    touch(TouchEvent::Move {
+        contact: 7,
+        position: layout.to_absolute(1, 30, 40).unwrap(),
+    });
+    touch(TouchEvent::Up { contact: 7 });
+    // Lifting a finger that is already up doesn't reach the portal
+    touch(TouchEvent::Up { contact: 7 });
+
+    assert_eq!(
+        calls(&mut rx),
+        [
+            PortalCall::TouchDown {
+                stream: 40,
+                slot: 0,
+                x: 10.0,
+                y: 20.0
+            },
+            PortalCall::TouchMotion {
+                stream: 41,
+                slot: 0,
+                x: 30.0,
+                y: 40.0
+            },
+            PortalCall::TouchUp { slot: 0 },
+        ]
+    );
+}
+
+#[test]
+fn portal_drops_pens_and_gamepads() {
+    let (backend, mut rx) = portal();
+    assert!(!backend.supports(InputDevice::Pen));
+    assert!(backend.supports(InputDevice::Touchscreen));
+
+    let input = manager(backend, TextTyper::default());
+    input
+        .send_gamepad_event(
+            CLIENT,
+            &GamepadEvent {
+                pad: 0,
+                state: Some(GamepadState::default()),
+            },
+        )
+        .unwrap();
+    input.release_client(CLIENT).unwrap();
+    assert!(calls(&mut rx).is_empty());
+}
+
+#[test]
+fn portal_only_drives_the_devices_the_user_allowed() {
+    let (backend, _) = PortalBackend::from_streams(layout(), [(0, 40)], DeviceType::POINTER);
+    assert!(backend.supports(InputDevice::Mouse));
+    assert!(backend.supports(InputDevice::Tablet));
+    assert!(!backend.supports(InputDevice::Keyboard));
+    assert!(!backend.supports(InputDevice::Touchscreen));
+}
+
+#[test]
+fn relayouts_lift_fingers_with_the_old_devices() {
+    let backend = RecordingBackend::default();
+    let input = manager(backend.clone(), TextTyper::default());
+    let layout = layout();
+
+    input
+        .send_touch_events(
+            CLIENT,
+            &[TouchEvent::Down {
+                contact: 7,
+                position: layout.to_absolute(0, 10, 20).unwrap(),
+            }],
+        )
+        .unwrap();
+    let reopened = [reopened_desktop()];
+    input.relayout(&reopened).unwrap();
+    assert_eq!(backend.layouts(), [DesktopLayout::new(&reopened)]);
+
+    // The finger went away with the old touchscreen, so nothing is left to lift
+    let frames = backend.frames().len();
+    input.release_client(CLIENT).unwrap();
+    assert_eq!(backend.frames().len(), frames);
+}
+
+#[test]
+fn portal_relayouts_follow_the_new_streams() {
+    let (mut backend, mut rx) = portal();
+    backend.relayout(&[reopened_desktop()]).unwrap();
+
+    let position = DesktopLayout::new(&[reopened_desktop()])
+        .to_absolute(2, 30, 40)
+        .unwrap();
+    backend
+        .emit(InputDevice::Tablet, &position.get_input_events())
+        .unwrap();
+    assert_eq!(
+        calls(&mut rx),
+        [PortalCall::PointerMotionAbsolute {
+            stream: 52,
+            x: 30.0,
+            y: 40.0,
+        }]
+    );
+}
@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use loded::{
+    adaptation::{EncoderSettings, RateController},
+    protocol::LodestarVideoFramePacket,
+    testing::{EncodedStream, H264Profile, VideoEncoder, VideoSource},
+    DesktopStream,
+};
+
+async fn next_frame(encoded: &mut EncodedStream) -> LodestarVideoFramePacket {
+    tokio::time::timeout(Duration::from_secs(10), encoded.frames.recv())
+        .await
+        .expect("No frame was produced")
+        .unwrap()
+}
+
+/// Encodes a test pattern with every installed encoder, which needs neither a compositor nor a GPU
+#[tokio::test]
+async fn test_pattern_encodes_with_every_available_encoder() {
+    gstreamer::init().unwrap();
+
+    let (ds_tx, _) = broadcast::channel(1);
+    let stream =
+        DesktopStream::start(0, VideoSource::TestPattern, 320, 240, ds_tx.subscribe()).unwrap();
+    let available = VideoEncoder::available();
+
+    for encoder in VideoEncoder::ALL {
+        if !available.contains(encoder.capability()) {
+            eprintln!("Skipping {encoder:?}, its encoder isn't installed");
+            continue;
+        }
+
+        let settings = RateController::new(320, 240).settings();
+        let mut encoded = stream.subscribe(encoder, settings).unwrap();
+        let frame = next_frame(&mut encoded).await;
+        assert_eq!(frame.loded_id, 0);
+        assert!(frame.keyframe, "{encoder:?} didn't start with a keyframe");
+        assert!(!frame.data.is_empty());
+    }
+
+    ds_tx.send(()).unwrap();
+}
+
+/// Halving the resolution mid-stream has to restart the encoder with a keyframe
+#[tokio::test]
+async fn downscaling_restarts_with_a_keyframe() {
+    gstreamer::init().unwrap();
+
+    let encoder = VideoEncoder::X264(H264Profile::High);
+    if !VideoEncoder::available().contains(encoder.capability()) {
+        eprintln!("Skipping, x264enc isn't installed");
+        return;
+    }
+
+    let (ds_tx, _) = broadcast::channel(1);
+    let stream =
+        DesktopStream::start(0, VideoSource::TestPattern, 320, 240, ds_tx.subscribe()).unwrap();
+    let settings = RateController::new(320, 240).settings();
+    let mut encoded = stream.subscribe(encoder, settings).unwrap();
+
+    assert!(next_frame(&mut encoded).await.keyframe);
+
+    encoded.apply(EncoderSettings {
+        downscale: 2,
+        ..settings
+    });
+    let mut restarted = false;
+    for _ in 0..30 {
+        if next_frame(&mut encoded).await.keyframe {
+            restarted = true;
+            break;
+        }
+    }
+    assert!(restarted);
+
+    ds_tx.send(()).unwrap();
+}
+
+/// A requested keyframe has to arrive long before x264's natural keyframe interval
+#[tokio::test]
+async fn keyframe_requests_force_a_keyframe() {
+    gstreamer::init().unwrap();
+
+    let encoder = VideoEncoder::X264(H264Profile::High);
+    if !VideoEncoder::available().contains(encoder.capability()) {
+        eprintln!("Skipping, x264enc isn't installed");
+        return;
+    }
+
+    let (ds_tx, _) = broadcast::channel(1);
+    let stream =
+        DesktopStream::start(0, VideoSource::TestPattern, 320, 240, ds_tx.subscribe()).unwrap();
+    let settings = RateController::new(320, 240).settings();
+    let mut encoded = stream.subscribe(encoder, settings).unwrap();
+
+    assert!(next_frame(&mut encoded).await.keyframe);
+    assert!(!next_frame(&mut encoded).await.keyframe);
+
+    encoded.keyframe_requester().request();
+    let mut forced = false;
+    for _ in 0..10 {
+        if next_frame(&mut encoded).await.keyframe {
+            forced = true;
+            break;
+        }
+    }
+    assert!(forced);
+
+    ds_tx.send(()).unwrap();
+}
@@ -0,0 +1,117 @@
+use bytes::{BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+use loded::{
+    codec::{LodestarCodec, LodestarCodecError},
+    protocol::{
+        LodestarCapabilities, LodestarHandshakePacket, LodestarPacket, LodestarPacketParsingError,
+        LodestarPacketType, LodestarSwitchSourcePacket,
+    },
+};
+
+#[test]
+fn reassembles_partial_frames() {
+    let mut codec = LodestarCodec::new();
+    let mut encoded = BytesMut::new();
+    codec
+        .encode(
+            LodestarHandshakePacket::Hello {
+                min_revision: 1,
+                max_revision: 7,
+                capabilities: LodestarCapabilities::KEYBOARD,
+                pairing_key: None,
+            },
+            &mut encoded,
+        )
+        .unwrap();
+    codec
+        .encode(LodestarSwitchSourcePacket { new_source: 3 }, &mut encoded)
+        .unwrap();
+
+    let mut src = BytesMut::new();
+    let mut packets = Vec::new();
+    for byte in encoded {
+        src.put_u8(byte);
+        if let Some(packet) = codec.decode(&mut src).unwrap() {
+            packets.push(packet);
+        }
+    }
+
+    assert!(src.is_empty());
+    assert_eq!(packets.len(), 2);
+    assert_eq!(
+        packets[0]
+            .parse_packet::<LodestarHandshakePacket>()
+            .unwrap(),
+        LodestarHandshakePacket::Hello {
+            min_revision: 1,
+            max_revision: 7,
+            capabilities: LodestarCapabilities::KEYBOARD,
+            pairing_key: None,
+        }
+    );
+    assert_eq!(
+        packets[1]
+            .parse_packet::<LodestarSwitchSourcePacket>()
+            .unwrap(),
+        LodestarSwitchSourcePacket { new_source: 3 }
+    );
+}
+
+#[test]
+fn rejects_oversized_frames_from_the_header() {
+    let mut codec = LodestarCodec::with_max_frame_length(32);
+    let mut src = BytesMut::new();
+    src.put_u64_le(LodestarPacketType::DesktopList as u64);
+    src.put_u64_le(33);
+
+    assert!(matches!(
+        codec.decode(&mut src),
+        Err(LodestarCodecError::FrameTooLarge(33))
+    ));
+}
+
+#[test]
+fn rejects_unknown_packet_types_from_the_header() {
+    let mut codec = LodestarCodec::new();
+    let mut src = BytesMut::new();
+    src.put_u64_le(1000);
+    src.put_u64_le(0);
+
+    assert!(matches!(
+        codec.decode(&mut src),
+        Err(LodestarCodecError::Parsing(
+            LodestarPacketParsingError::UnknownPacketType(1000)
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn stream_and_sink_round_trip() {
+    let (client, server) = tokio::io::duplex(7);
+    let mut sink = FramedWrite::new(client, LodestarCodec::new());
+    let mut stream = FramedRead::new(server, LodestarCodec::new());
+
+    let writer = tokio::spawn(async move {
+        for new_source in 0..16 {
+            sink.send(LodestarSwitchSourcePacket { new_source })
+                .await
+                .unwrap();
+        }
+    });
+
+    let mut received = Vec::new();
+    while let Some(packet) = stream.next().await {
+        let packet: LodestarPacket = packet.unwrap();
+        received.push(
+            packet
+                .parse_packet::<LodestarSwitchSourcePacket>()
+                .unwrap()
+                .new_source,
+        );
+    }
+    writer.await.unwrap();
+
+    assert_eq!(received, (0..16).collect::<Vec<u64>>());
+}
@@ -0,0 +1,499 @@
+use std::collections::HashSet;
+
+use evdev::{AbsoluteAxisType, EventType, InputEvent, Key, RelativeAxisType};
+use loded::{
+    protocol::{Decode, LodestarInput, LodestarInputEvent, LodestarInputPacket, LodestarPacket},
+    testing::{
+        AbsolutePointerEvent, DeltaMode, DesktopRect, GamepadButtons, GamepadState, HeldKeys,
+        KeyEvent, MouseMoveEvent, PenButtons, PenEvent, PenState, TouchEvent, TouchSlots,
+        WheelAccumulator, JS_KEY_CODES, MAX_TOUCH_CONTACTS, WHEEL_NOTCH,
+    },
+    DesktopLayout, KeyDirection,
+};
+
+#[test]
+fn every_code_maps_to_its_key() {
+    for (code, key) in JS_KEY_CODES {
+        let evt = KeyEvent::from_js_key_name_with_direction(code, KeyDirection::Down).unwrap();
+        assert_eq!(evt.key, *key, "{code}");
+        assert_eq!(evt.direction, KeyDirection::Down);
+    }
+}
+
+#[test]
+fn codes_are_unique() {
+    let mut seen = HashSet::new();
+    for (code, _) in JS_KEY_CODES {
+        assert!(seen.insert(code), "{code} is listed twice");
+    }
+}
+
+#[test]
+fn common_codes_are_covered() {
+    let expected = [
+        ("Digit0", Key::KEY_0),
+        ("F12", Key::KEY_F12),
+        ("ArrowLeft", Key::KEY_LEFT),
+        ("PageDown", Key::KEY_PAGEDOWN),
+        ("Delete", Key::KEY_DELETE),
+        ("NumpadEnter", Key::KEY_KPENTER),
+        ("Semicolon", Key::KEY_SEMICOLON),
+        ("Quote", Key::KEY_APOSTROPHE),
+        ("MetaLeft", Key::KEY_LEFTMETA),
+        ("ContextMenu", Key::KEY_COMPOSE),
+        ("PrintScreen", Key::KEY_SYSRQ),
+        ("MediaPlayPause", Key::KEY_PLAYPAUSE),
+        ("IntlBackslash", Key::KEY_102ND),
+    ];
+    for (code, key) in expected {
+        assert_eq!(
+            KeyEvent::from_js_key_name_with_direction(code, KeyDirection::Up)
+                .unwrap()
+                .key,
+            key,
+            "{code}"
+        );
+    }
+}
+
+#[test]
+fn unknown_codes_are_rejected() {
+    assert!(KeyEvent::from_js_key_name_with_direction("KEY0", KeyDirection::Down).is_err());
+    assert!(KeyEvent::from_js_key_name_with_direction("keya", KeyDirection::Down).is_err());
+    assert!(KeyEvent::from_js_key_name_with_direction("", KeyDirection::Down).is_err());
+}
+
+/// Every key a client can name also has to make it through the protocol
+#[test]
+fn every_key_can_be_sent() {
+    let packet = LodestarInputPacket {
+        events: JS_KEY_CODES
+            .iter()
+            .map(|(_, key)| LodestarInputEvent {
+                timestamp: 0,
+                input: LodestarInput::Key(KeyEvent {
+                    key: *key,
+                    direction: KeyDirection::Down,
+                }),
+            })
+            .collect(),
+    };
+    let mut encoded = LodestarPacket::encode_packet(&packet);
+    let decoded = LodestarPacket::decode(&mut encoded)
+        .unwrap()
+        .parse_packet::<LodestarInputPacket>()
+        .unwrap();
+    assert_eq!(decoded, packet);
+}
+
+fn rect(x: i32, y: i32, width: i32, height: i32) -> DesktopRect {
+    DesktopRect {
+        x,
+        y,
+        width,
+        height,
+    }
+}
+
+#[test]
+fn layout_spans_every_desktop() {
+    // A 1080p monitor to the left of and slightly above a 1440p one
+    let layout = DesktopLayout::from_rects([
+        (0, rect(-1920, -200, 1920, 1080)),
+        (1, rect(0, 0, 2560, 1440)),
+    ]);
+    assert_eq!(layout.size(), (4480, 1640));
+
+    assert_eq!(
+        layout.to_absolute(0, 0, 0),
+        Some(AbsolutePointerEvent { x: 0, y: 0 })
+    );
+    assert_eq!(
+        layout.to_absolute(1, 100, 50),
+        Some(AbsolutePointerEvent { x: 2020, y: 250 })
+    );
+    assert_eq!(layout.to_absolute(2, 0, 0), None);
+}
+
+#[test]
+fn positions_are_clamped_to_their_desktop() {
+    let layout =
+        DesktopLayout::from_rects([(0, rect(0, 0, 1920, 1080)), (1, rect(1920, 0, 1920, 1080))]);
+    assert_eq!(
+        layout.to_absolute(0, 5000, -10),
+        Some(AbsolutePointerEvent { x: 1919, y: 0 })
+    );
+    assert_eq!(
+        layout.to_absolute(1, -10, 5000),
+        Some(AbsolutePointerEvent { x: 1920, y: 1079 })
+    );
+}
+
+#[test]
+fn layout_positions_map_back_to_their_desktop() {
+    let layout = DesktopLayout::from_rects([
+        (0, rect(-1920, -200, 1920, 1080)),
+        (1, rect(0, 0, 2560, 1440)),
+    ]);
+    for (loded_id, x, y) in [(0, 0, 0), (0, 1919, 1079), (1, 100, 50), (1, 0, 0)] {
+        let position = layout.to_absolute(loded_id, x, y).unwrap();
+        assert_eq!(
+            layout.to_desktop(position.x, position.y),
+            Some((loded_id, x, y))
+        );
+    }
+    // Below the 1080p monitor, where no desktop is
+    assert_eq!(layout.to_desktop(0, 1500), None);
+}
+
+#[test]
+fn empty_layout_has_no_size() {
+    let layout = DesktopLayout::from_rects([]);
+    assert_eq!(layout.size(), (0, 0));
+    assert_eq!(layout.to_absolute(0, 0, 0), None);
+}
+
+#[test]
+fn wheel_deltas_become_hi_res_units() {
+    // One notch of a regular mouse in each unit browsers report
+    for (delta, mode) in [
+        (100.0, DeltaMode::Pixel),
+        (3.0, DeltaMode::Line),
+        (0.1, DeltaMode::Page),
+    ] {
+        let down = MouseMoveEvent::from_wheel_delta(0.0, delta, mode);
+        assert_eq!((down.wheel, down.hwheel), (-WHEEL_NOTCH, 0), "{mode:?}");
+
+        let right = MouseMoveEvent::from_wheel_delta(delta, 0.0, mode);
+        assert_eq!((right.wheel, right.hwheel), (0, WHEEL_NOTCH), "{mode:?}");
+    }
+
+    let touchpad = MouseMoveEvent::from_wheel_delta(-12.5, 4.0, DeltaMode::Pixel);
+    assert_eq!((touchpad.wheel, touchpad.hwheel), (-5, -15));
+}
+
+#[test]
+fn scrolling_uses_the_hi_res_axes() {
+    let events = MouseMoveEvent::new(0, 0, 30, -60).get_input_events();
+    let axes = events
+        .iter()
+        .map(|evt| (RelativeAxisType(evt.code()), evt.value()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        axes,
+        [
+            (RelativeAxisType::REL_WHEEL_HI_RES, 30),
+            (RelativeAxisType::REL_HWHEEL_HI_RES, -60)
+        ]
+    );
+}
+
+#[test]
+fn legacy_wheel_counts_whole_notches() {
+    let legacy = |acc: &mut WheelAccumulator, wheel: i32, hwheel: i32| {
+        acc.legacy_events(&MouseMoveEvent::new(0, 0, wheel, hwheel))
+            .iter()
+            .map(|evt| (RelativeAxisType(evt.code()), evt.value()))
+            .collect::<Vec<_>>()
+    };
+
+    let mut acc = WheelAccumulator::default();
+    assert!(legacy(&mut acc, 60, 0).is_empty());
+    assert_eq!(legacy(&mut acc, 60, 0), [(RelativeAxisType::REL_WHEEL, 1)]);
+    assert_eq!(
+        legacy(&mut acc, 2 * WHEEL_NOTCH, -WHEEL_NOTCH),
+        [
+            (RelativeAxisType::REL_WHEEL, 2),
+            (RelativeAxisType::REL_HWHEEL, -1)
+        ]
+    );
+
+    // Turning back starts a new notch rather than undoing the partial one
+    assert!(legacy(&mut acc, 100, 0).is_empty());
+    assert!(legacy(&mut acc, -100, 0).is_empty());
+    assert_eq!(
+        legacy(&mut acc, -20, 0),
+        [(RelativeAxisType::REL_WHEEL, -1)]
+    );
+}
+
+fn touch_events(slots: &mut TouchSlots, event: TouchEvent) -> Vec<(EventType, u16, i32)> {
+    slots
+        .events(CLIENT, &event)
+        .iter()
+        .map(|evt: &InputEvent| (evt.event_type(), evt.code(), evt.value()))
+        .collect()
+}
+
+fn down(contact: u32, x: i32, y: i32) -> TouchEvent {
+    TouchEvent::Down {
+        contact,
+        position: AbsolutePointerEvent { x, y },
+    }
+}
+
+const ABS: EventType = EventType::ABSOLUTE;
+const CLIENT: u64 = 1;
+
+#[test]
+fn touch_contacts_get_their_own_slots() {
+    let mut slots = TouchSlots::default();
+
+    assert_eq!(
+        touch_events(&mut slots, down(7, 10, 20)),
+        [
+            (ABS, AbsoluteAxisType::ABS_MT_SLOT.0, 0),
+            (ABS, AbsoluteAxisType::ABS_MT_TRACKING_ID.0, 0),
+            (ABS, AbsoluteAxisType::ABS_MT_POSITION_X.0, 10),
+            (ABS, AbsoluteAxisType::ABS_MT_POSITION_Y.0, 20),
+            (ABS, AbsoluteAxisType::ABS_X.0, 10),
+            (ABS, AbsoluteAxisType::ABS_Y.0, 20),
+            (EventType::KEY, Key::BTN_TOUCH.code(), 1),
+        ]
+    );
+
+    // A second finger is not mirrored onto the single touch axes
+    assert_eq!(
+        touch_events(&mut slots, down(3, 30, 40)),
+        [
+            (ABS, AbsoluteAxisType::ABS_MT_SLOT.0, 1),
+            (ABS, AbsoluteAxisType::ABS_MT_TRACKING_ID.0, 1),
+            (ABS, AbsoluteAxisType::ABS_MT_POSITION_X.0, 30),
+            (ABS, AbsoluteAxisType::ABS_MT_POSITION_Y.0, 40),
+        ]
+    );
+
+    assert_eq!(
+        touch_events(
+            &mut slots,
+            TouchEvent::Move {
+                contact: 3,
+                position: AbsolutePointerEvent { x: 31, y: 41 },
+            }
+        ),
+        [
+            (ABS, AbsoluteAxisType::ABS_MT_SLOT.0, 1),
+            (ABS, AbsoluteAxisType::ABS_MT_POSITION_X.0, 31),
+            (ABS, AbsoluteAxisType::ABS_MT_POSITION_Y.0, 41),
+        ]
+    );
+
+    assert_eq!(
+        touch_events(&mut slots, TouchEvent::Up { contact: 7 }),
+        [
+            (ABS, AbsoluteAxisType::ABS_MT_SLOT.0, 0),
+            (ABS, AbsoluteAxisType::ABS_MT_TRACKING_ID.0, -1),
+        ]
+    );
+    assert_eq!(
+        touch_events(&mut slots, TouchEvent::Up { contact: 3 }),
+        [
+            (ABS, AbsoluteAxisType::ABS_MT_SLOT.0, 1),
+            (ABS, AbsoluteAxisType::ABS_MT_TRACKING_ID.0, -1),
+            (EventType::KEY, Key::BTN_TOUCH.code(), 0),
+        ]
+    );
+
+    // Freed slots are reused with a new tracking ID
+    assert_eq!(
+        touch_events(&mut slots, down(7, 0, 0))[..2],
+        [
+            (ABS, AbsoluteAxisType::ABS_MT_SLOT.0, 0),
+            (ABS, AbsoluteAxisType::ABS_MT_TRACKING_ID.0, 2),
+        ]
+    );
+}
+
+#[test]
+fn unknown_and_excess_contacts_are_ignored() {
+    let mut slots = TouchSlots::default();
+
+    assert!(touch_events(&mut slots, TouchEvent::Up { contact: 1 }).is_empty());
+    assert!(touch_events(
+        &mut slots,
+        TouchEvent::Move {
+            contact: 1,
+            position: AbsolutePointerEvent { x: 0, y: 0 },
+        }
+    )
+    .is_empty());
+
+    for contact in 0..MAX_TOUCH_CONTACTS as u32 {
+        assert!(!touch_events(&mut slots, down(contact, 0, 0)).is_empty());
+    }
+    assert!(touch_events(&mut slots, down(100, 0, 0)).is_empty());
+}
+
+fn pen(buttons: PenButtons, pressure: u16) -> PenEvent {
+    PenEvent {
+        position: AbsolutePointerEvent { x: 5, y: 6 },
+        pressure,
+        tilt_x: -30,
+        tilt_y: 45,
+        buttons,
+    }
+}
+
+fn pen_events(state: &mut PenState, event: PenEvent) -> Vec<(EventType, u16, i32)> {
+    state
+        .events(CLIENT, &event)
+        .iter()
+        .map(|evt| (evt.event_type(), evt.code(), evt.value()))
+        .collect()
+}
+
+#[test]
+fn pen_enters_draws_and_leaves() {
+    let mut state = PenState::default();
+    let hovering = PenButtons(PenButtons::IN_RANGE.0 | PenButtons::BARREL.0);
+
+    assert_eq!(
+        pen_events(&mut state, pen(hovering, 1000)),
+        [
+            (ABS, AbsoluteAxisType::ABS_X.0, 5),
+            (ABS, AbsoluteAxisType::ABS_Y.0, 6),
+            (ABS, AbsoluteAxisType::ABS_TILT_X.0, -30),
+            (ABS, AbsoluteAxisType::ABS_TILT_Y.0, 45),
+            // Pressure only counts while the tip touches
+            (ABS, AbsoluteAxisType::ABS_PRESSURE.0, 0),
+            (EventType::KEY, Key::BTN_TOOL_PEN.code(), 1),
+            (EventType::KEY, Key::BTN_TOUCH.code(), 0),
+            (EventType::KEY, Key::BTN_STYLUS.code(), 1),
+            (EventType::KEY, Key::BTN_STYLUS2.code(), 0),
+        ]
+    );
+
+    let touching = PenButtons(PenButtons::IN_RANGE.0 | PenButtons::TIP.0);
+    let events = pen_events(&mut state, pen(touching, 1000));
+    assert!(events.contains(&(ABS, AbsoluteAxisType::ABS_PRESSURE.0, 1000)));
+    assert!(events.contains(&(EventType::KEY, Key::BTN_TOUCH.code(), 1)));
+    assert!(!events.iter().any(|evt| evt.1 == Key::BTN_TOOL_PEN.code()));
+
+    assert_eq!(
+        pen_events(&mut state, pen(PenButtons::NONE, 0)),
+        [
+            (EventType::KEY, Key::BTN_TOUCH.code(), 0),
+            (EventType::KEY, Key::BTN_STYLUS.code(), 0),
+            (EventType::KEY, Key::BTN_STYLUS2.code(), 0),
+            (ABS, AbsoluteAxisType::ABS_PRESSURE.0, 0),
+            (EventType::KEY, Key::BTN_TOOL_PEN.code(), 0),
+        ]
+    );
+    assert!(pen_events(&mut state, pen(PenButtons::NONE, 0)).is_empty());
+}
+
+#[test]
+fn flipping_the_pen_switches_tools_in_separate_frames() {
+    let mut state = PenState::default();
+    pen_events(&mut state, pen(PenButtons::IN_RANGE, 0));
+
+    let eraser = PenButtons(PenButtons::IN_RANGE.0 | PenButtons::ERASER.0);
+    let events = pen_events(&mut state, pen(eraser, 0));
+    let leave = events
+        .iter()
+        .position(|evt| *evt == (EventType::KEY, Key::BTN_TOOL_PEN.code(), 0))
+        .unwrap();
+    let syn = events
+        .iter()
+        .position(|evt| evt.0 == EventType::SYNCHRONIZATION)
+        .unwrap();
+    let enter = events
+        .iter()
+        .position(|evt| *evt == (EventType::KEY, Key::BTN_TOOL_RUBBER.code(), 1))
+        .unwrap();
+    assert!(leave < syn && syn < enter);
+}
+
+#[test]
+fn gamepad_state_maps_onto_an_xbox_controller() {
+    let state = GamepadState {
+        buttons: GamepadButtons(
+            GamepadButtons::A.0 | GamepadButtons::GUIDE.0 | GamepadButtons::DPAD_UP.0,
+        ),
+        left_x: -32768,
+        left_y: 100,
+        right_x: 32767,
+        right_y: 0,
+        left_trigger: 255,
+        right_trigger: 7,
+    };
+    let events = state
+        .get_input_events()
+        .iter()
+        .map(|evt| (evt.event_type(), evt.code(), evt.value()))
+        .collect::<Vec<_>>();
+
+    for (key, value) in [
+        (Key::BTN_SOUTH, 1),
+        (Key::BTN_EAST, 0),
+        (Key::BTN_MODE, 1),
+        (Key::BTN_THUMBL, 0),
+    ] {
+        assert!(
+            events.contains(&(EventType::KEY, key.code(), value)),
+            "{key:?}"
+        );
+    }
+    for (axis, value) in [
+        (AbsoluteAxisType::ABS_X, -32768),
+        (AbsoluteAxisType::ABS_Y, 100),
+        (AbsoluteAxisType::ABS_RX, 32767),
+        (AbsoluteAxisType::ABS_Z, 255),
+        (AbsoluteAxisType::ABS_RZ, 7),
+        (AbsoluteAxisType::ABS_HAT0X, 0),
+        (AbsoluteAxisType::ABS_HAT0Y, -1),
+    ] {
+        assert!(events.contains(&(ABS, axis.0, value)), "{axis:?}");
+    }
+}
+
+#[test]
+fn leaving_clients_release_what_only_they_hold() {
+    let mut held = HeldKeys::default();
+    held.update(1, Key::KEY_LEFTSHIFT, KeyDirection::Down);
+    held.update(1, Key::KEY_A, KeyDirection::RepeatingDown);
+    held.update(1, Key::KEY_B, KeyDirection::Down);
+    held.update(1, Key::KEY_B, KeyDirection::Up);
+    held.update(2, Key::KEY_A, KeyDirection::Down);
+
+    let released = held
+        .release(1)
+        .iter()
+        .map(|evt| (evt.event_type(), evt.code(), evt.value()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        released,
+        [(EventType::KEY, Key::KEY_LEFTSHIFT.code(), 0)],
+        "KEY_A is still held by client 2"
+    );
+    assert!(held.release(1).is_empty());
+    assert_eq!(held.release(2).len(), 1);
+}
+
+#[test]
+fn leaving_clients_lift_their_fingers_and_pen() {
+    let mut slots = TouchSlots::default();
+    touch_events(&mut slots, down(0, 1, 1));
+    slots.events(2, &down(0, 2, 2));
+    touch_events(&mut slots, down(1, 3, 3));
+
+    let lifted = slots.release(CLIENT);
+    let tracking_ids = lifted
+        .iter()
+        .filter(|evt| evt.code() == AbsoluteAxisType::ABS_MT_TRACKING_ID.0)
+        .map(|evt| evt.value())
+        .collect::<Vec<_>>();
+    assert_eq!(tracking_ids, [-1, -1]);
+    // The other client's finger keeps the screen touched
+    assert!(!lifted.iter().any(|evt| evt.code() == Key::BTN_TOUCH.code()));
+    assert!(slots.release(CLIENT).is_empty());
+
+    let mut state = PenState::default();
+    pen_events(&mut state, pen(PenButtons::IN_RANGE, 0));
+    assert!(state.release(2).is_empty());
+    assert!(state
+        .release(CLIENT)
+        .iter()
+        .any(|evt| (evt.code(), evt.value()) == (Key::BTN_TOOL_PEN.code(), 0)));
+    assert!(state.release(CLIENT).is_empty());
+}
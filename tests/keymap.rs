@@ -0,0 +1,67 @@
+use evdev::Key;
+use loded::{testing::KeyEvent, KeyDirection, KeymapNames, TextTyper};
+
+fn typer(layout: &str) -> TextTyper {
+    TextTyper::new(&KeymapNames {
+        layout: layout.to_owned(),
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+fn press(modifiers: &[Key], key: Key) -> Vec<KeyEvent> {
+    let event = |key, direction| KeyEvent { key, direction };
+    let mut out = modifiers
+        .iter()
+        .map(|m| event(*m, KeyDirection::Down))
+        .collect::<Vec<_>>();
+    out.push(event(key, KeyDirection::Down));
+    out.push(event(key, KeyDirection::Up));
+    out.extend(modifiers.iter().rev().map(|m| event(*m, KeyDirection::Up)));
+    out
+}
+
+#[test]
+fn text_is_typed_on_the_layout() {
+    let us = typer("us");
+    let expected = [
+        press(&[], Key::KEY_H),
+        press(&[Key::KEY_LEFTSHIFT], Key::KEY_1),
+        press(&[], Key::KEY_ENTER),
+    ]
+    .concat();
+    assert_eq!(us.key_events("h!\n"), expected);
+
+    let de = typer("de");
+    let expected = [
+        press(&[], Key::KEY_Y),
+        press(&[], Key::KEY_MINUS),
+        press(&[Key::KEY_RIGHTALT], Key::KEY_Q),
+    ]
+    .concat();
+    assert_eq!(de.key_events("zß@"), expected);
+}
+
+#[test]
+fn missing_characters_are_typed_as_code_points() {
+    let us = typer("us");
+    assert!(!us.has_key('é'));
+
+    let ctrl_shift = [Key::KEY_LEFTCTRL, Key::KEY_LEFTSHIFT];
+    let expected = [
+        press(&ctrl_shift, Key::KEY_U),
+        press(&[], Key::KEY_E),
+        press(&[], Key::KEY_9),
+        press(&[], Key::KEY_SPACE),
+    ]
+    .concat();
+    assert_eq!(us.key_events("é"), expected);
+}
+
+#[test]
+fn code_points_need_hex_digits_on_the_layout() {
+    // Cyrillic layouts have no latin letters to spell out a code point with
+    let ru = typer("ru");
+    assert!(ru.has_key('ж'));
+    assert!(ru.key_events("é").is_empty());
+}
@@ -0,0 +1,382 @@
+//! A stand-in for xdg-desktop-portal's ScreenCast interface on a private session bus
+//!
+//! Every request is answered with the response code and results of a [Script] instead of asking
+//! a user, and what loded asked for is kept in a [Log] to be checked afterwards.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+};
+
+use zbus::{dbus_interface, Connection, ConnectionBuilder, MessageHeader, ObjectServer};
+use zvariant::{OwnedObjectPath, OwnedValue, SerializeDict, Type};
+
+const DESTINATION: &str = "org.freedesktop.portal.Desktop";
+const PATH: &str = "/org/freedesktop/portal/desktop";
+
+pub const SUCCESS: u32 = 0;
+pub const CANCELLED: u32 = 1;
+pub const UNKNOWN_ENDED: u32 = 2;
+
+/// The properties of a stream handed out by Start, left out where they are `None`
+#[derive(SerializeDict, Type, Debug, Clone, Default)]
+#[zvariant(signature = "dict")]
+pub struct StreamProperties {
+    pub id: Option<String>,
+    pub position: Option<(i32, i32)>,
+    pub size: Option<(i32, i32)>,
+    pub source_type: Option<u32>,
+}
+
+impl StreamProperties {
+    /// A monitor with every property set
+    pub fn monitor(id: &str, position: (i32, i32), size: (i32, i32)) -> Self {
+        Self {
+            id: Some(id.to_owned()),
+            position: Some(position),
+            size: Some(size),
+            source_type: Some(1),
+        }
+    }
+}
+
+#[derive(SerializeDict, Type, Debug)]
+#[zvariant(signature = "dict")]
+struct CreateSessionResults {
+    session_handle: Option<String>,
+}
+
+#[derive(SerializeDict, Type, Debug, Default)]
+#[zvariant(signature = "dict")]
+struct EmptyResults {}
+
+#[derive(SerializeDict, Type, Debug)]
+#[zvariant(signature = "dict")]
+struct StartResults {
+    streams: Option<Vec<(u32, StreamProperties)>>,
+    restore_token: Option<String>,
+}
+
+/// How the portal answers each request
+#[derive(Debug, Clone)]
+pub struct Script {
+    pub create_session: u32,
+    pub select_sources: u32,
+    pub start: u32,
+    /// The PipeWire node ids and properties of the streams Start hands out
+    pub streams: Vec<(u32, StreamProperties)>,
+    pub restore_token: Option<String>,
+    /// Whether CreateSession's results include the session handle
+    pub session_handle: bool,
+    /// Returns request handles other than the ones the responses are sent on, like portals
+    /// predating handle tokens did
+    pub mismatched_handles: bool,
+}
+
+impl Default for Script {
+    /// One monitor is shared and the permission may be restored
+    fn default() -> Self {
+        Self {
+            create_session: SUCCESS,
+            select_sources: SUCCESS,
+            start: SUCCESS,
+            streams: vec![(40, StreamProperties::monitor("0", (0, 0), (1920, 1080)))],
+            restore_token: Some("restore-1".to_owned()),
+            session_handle: true,
+            mismatched_handles: false,
+        }
+    }
+}
+
+/// What loded asked the portal for
+#[derive(Debug, Clone, Default)]
+pub struct Log {
+    /// The methods called, in order
+    pub calls: Vec<&'static str>,
+    /// The restore token passed to SelectSources
+    pub restore_token: Option<String>,
+    /// The persist mode passed to SelectSources
+    pub persist_mode: Option<u32>,
+    /// The handle of the last session created
+    pub session: Option<OwnedObjectPath>,
+    /// Whether loded closed the session
+    pub session_closed: bool,
+}
+
+type Options = HashMap<String, OwnedValue>;
+
+fn option<T: TryFrom<OwnedValue>>(options: &Options, key: &str) -> Option<T> {
+    options
+        .get(key)
+        .and_then(|value| T::try_from(value.clone()).ok())
+}
+
+/// The path of a request or session object, which the portal derives from the caller's unique
+/// name and the token it chose
+fn handle_path(kind: &str, header: &MessageHeader<'_>, token: &str) -> OwnedObjectPath {
+    let sender = header
+        .sender()
+        .unwrap()
+        .expect("Method calls have a sender")
+        .trim_start_matches(':')
+        .replace('.', "_");
+    OwnedObjectPath::try_from(format!("{PATH}/{kind}/{sender}/{token}")).unwrap()
+}
+
+struct Request;
+
+#[dbus_interface(name = "org.freedesktop.portal.Request")]
+impl Request {
+    fn close(&self) {}
+}
+
+struct Session {
+    log: Arc<Mutex<Log>>,
+}
+
+#[dbus_interface(name = "org.freedesktop.portal.Session")]
+impl Session {
+    fn close(&self) {
+        self.log.lock().unwrap().session_closed = true;
+    }
+
+    #[dbus_interface(property)]
+    fn version(&self) -> u32 {
+        1
+    }
+}
+
+struct ScreenCast {
+    script: Script,
+    log: Arc<Mutex<Log>>,
+}
+
+impl ScreenCast {
+    /// Serves a request object at the path the caller expects and answers it right away
+    async fn respond<R: serde::Serialize + Type>(
+        &self,
+        connection: &Connection,
+        server: &ObjectServer,
+        header: &MessageHeader<'_>,
+        options: &Options,
+        code: u32,
+        results: R,
+    ) -> zbus::fdo::Result<OwnedObjectPath> {
+        let token = option::<String>(options, "handle_token")
+            .ok_or_else(|| zbus::fdo::Error::InvalidArgs("handle_token is missing".to_owned()))?;
+        let path = handle_path("request", header, &token);
+        server.at(path.as_str(), Request).await?;
+
+        connection
+            .emit_signal(
+                None::<&str>,
+                path.as_str(),
+                "org.freedesktop.portal.Request",
+                "Response",
+                &(code, results),
+            )
+            .await?;
+
+        if self.script.mismatched_handles {
+            return Ok(handle_path("request", header, "mismatched"));
+        }
+        Ok(path)
+    }
+}
+
+#[dbus_interface(name = "org.freedesktop.portal.ScreenCast")]
+impl ScreenCast {
+    async fn create_session(
+        &self,
+        options: Options,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(object_server)] server: &ObjectServer,
+    ) -> zbus::fdo::Result<OwnedObjectPath> {
+        let token = option::<String>(&options, "session_handle_token").ok_or_else(|| {
+            zbus::fdo::Error::InvalidArgs("session_handle_token is missing".to_owned())
+        })?;
+        let session = handle_path("session", &header, &token);
+        server
+            .at(
+                session.as_str(),
+                Session {
+                    log: self.log.clone(),
+                },
+            )
+            .await?;
+        {
+            let mut log = self.log.lock().unwrap();
+            log.calls.push("CreateSession");
+            log.session = Some(session.clone());
+        }
+
+        let results = CreateSessionResults {
+            session_handle: self.script.session_handle.then(|| session.to_string()),
+        };
+        self.respond(
+            connection,
+            server,
+            &header,
+            &options,
+            self.script.create_session,
+            results,
+        )
+        .await
+    }
+
+    async fn select_sources(
+        &self,
+        _session_handle: OwnedObjectPath,
+        options: Options,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(object_server)] server: &ObjectServer,
+    ) -> zbus::fdo::Result<OwnedObjectPath> {
+        {
+            let mut log = self.log.lock().unwrap();
+            log.calls.push("SelectSources");
+            log.restore_token = option(&options, "restore_token");
+            log.persist_mode = option(&options, "persist_mode");
+        }
+
+        self.respond(
+            connection,
+            server,
+            &header,
+            &options,
+            self.script.select_sources,
+            EmptyResults::default(),
+        )
+        .await
+    }
+
+    async fn start(
+        &self,
+        _session_handle: OwnedObjectPath,
+        _parent_window: String,
+        options: Options,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(object_server)] server: &ObjectServer,
+    ) -> zbus::fdo::Result<OwnedObjectPath> {
+        self.log.lock().unwrap().calls.push("Start");
+
+        let results = StartResults {
+            streams: Some(self.script.streams.clone()),
+            restore_token: self.script.restore_token.clone(),
+        };
+        self.respond(
+            connection,
+            server,
+            &header,
+            &options,
+            self.script.start,
+            results,
+        )
+        .await
+    }
+
+    #[dbus_interface(property)]
+    fn available_source_types(&self) -> u32 {
+        1
+    }
+
+    #[dbus_interface(property)]
+    fn available_cursor_modes(&self) -> u32 {
+        1 | 2
+    }
+
+    #[dbus_interface(property)]
+    fn version(&self) -> u32 {
+        4
+    }
+}
+
+/// Kills the bus when dropped, even if setting up the portal on it failed
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A private `dbus-daemon` with the mock portal on it, both of which go away when this is dropped
+pub struct MockPortal {
+    address: String,
+    log: Arc<Mutex<Log>>,
+    server: Connection,
+    _daemon: Daemon,
+}
+
+impl MockPortal {
+    pub async fn start(script: Script) -> Self {
+        let mut daemon = Daemon(
+            Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("dbus-daemon has to be installed to run the portal tests"),
+        );
+        let mut address = String::new();
+        BufReader::new(daemon.0.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim().to_owned();
+
+        let log = Arc::new(Mutex::new(Log::default()));
+        let screencast = ScreenCast {
+            script,
+            log: log.clone(),
+        };
+        let server = ConnectionBuilder::address(address.as_str())
+            .unwrap()
+            .name(DESTINATION)
+            .unwrap()
+            .serve_at(PATH, screencast)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        Self {
+            address,
+            log,
+            server,
+            _daemon: daemon,
+        }
+    }
+
+    /// A new connection to the private bus, for loded to reach the portal through
+    pub async fn connect(&self) -> Connection {
+        ConnectionBuilder::address(self.address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap()
+    }
+
+    /// Closes the last session created, as if the user revoked it
+    pub async fn close_session(&self) {
+        let session = self.log().session.expect("No session was created");
+        self.server
+            .emit_signal(
+                None::<&str>,
+                session.as_str(),
+                "org.freedesktop.portal.Session",
+                "Closed",
+                &(Options::new(),),
+            )
+            .await
+            .unwrap();
+    }
+
+    pub fn log(&self) -> Log {
+        self.log.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,272 @@
+mod mock_portal;
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use loded::{CaptureManager, Desktop, PortalError};
+
+use mock_portal::{MockPortal, Script, StreamProperties, CANCELLED, UNKNOWN_ENDED};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// An empty directory of its own for each test to keep the restore token in
+fn token_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("loded-portal-{}-{test}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the screencast handshake against a portal following `script`
+async fn open_session(script: Script, token_dir: &Path) -> (MockPortal, Result<Vec<Desktop>>) {
+    let portal = MockPortal::start(script).await;
+    let mut capture = CaptureManager::with_connection(portal.connect().await, token_dir);
+    let desktops = tokio::time::timeout(Duration::from_secs(10), capture.open_session(None))
+        .await
+        .expect("The handshake got stuck");
+    (portal, desktops)
+}
+
+/// The id, portal id, node, size and position of a desktop
+type Summary<'a> = (u64, &'a str, u32, i32, i32, Option<(i32, i32)>);
+
+/// The [PortalError] the handshake failed with
+fn portal_error(res: Result<Vec<Desktop>>) -> PortalError {
+    match res {
+        Ok(desktops) => panic!("The handshake succeeded with {desktops:?}"),
+        Err(e) => match e.downcast::<PortalError>() {
+            Ok(e) => *e,
+            Err(e) => panic!("The handshake failed with something else: {e}"),
+        },
+    }
+}
+
+fn summary(desktops: &[Desktop]) -> Vec<Summary<'_>> {
+    desktops
+        .iter()
+        .map(|d| {
+            (
+                d.loded_id,
+                d.id.as_str(),
+                d.pipewire_path,
+                d.width,
+                d.height,
+                d.position,
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn handshake_returns_the_shared_monitors() {
+    let dir = token_dir("handshake");
+    let script = Script {
+        streams: vec![
+            (40, StreamProperties::monitor("0", (0, 0), (1920, 1080))),
+            (41, StreamProperties::monitor("1", (1920, 0), (2560, 1440))),
+        ],
+        ..Script::default()
+    };
+    let (portal, desktops) = open_session(script, &dir).await;
+
+    assert_eq!(
+        summary(&desktops.unwrap()),
+        [
+            (0, "0", 40, 1920, 1080, Some((0, 0))),
+            (1, "1", 41, 2560, 1440, Some((1920, 0))),
+        ]
+    );
+
+    let log = portal.log();
+    assert_eq!(log.calls, ["CreateSession", "SelectSources", "Start"]);
+    assert_eq!(log.restore_token, None);
+    // The permission is kept until the user revokes it
+    assert_eq!(log.persist_mode, Some(2));
+    assert!(log.session.is_some());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("token")).unwrap(),
+        "restore-1"
+    );
+}
+
+#[tokio::test]
+async fn stored_restore_token_is_handed_back() {
+    let dir = token_dir("restore");
+    std::fs::write(dir.join("token"), "restore-0").unwrap();
+    let (portal, desktops) = open_session(Script::default(), &dir).await;
+
+    assert_eq!(desktops.unwrap().len(), 1);
+    assert_eq!(portal.log().restore_token.as_deref(), Some("restore-0"));
+    // Tokens are single use, the new one replaces it
+    assert_eq!(
+        std::fs::read_to_string(dir.join("token")).unwrap(),
+        "restore-1"
+    );
+}
+
+#[tokio::test]
+async fn cancelled_source_selection_fails_the_handshake() {
+    let dir = token_dir("cancel-select");
+    let script = Script {
+        select_sources: CANCELLED,
+        ..Script::default()
+    };
+    let (portal, desktops) = open_session(script, &dir).await;
+
+    assert!(matches!(portal_error(desktops), PortalError::Cancelled));
+    assert_eq!(portal.log().calls, ["CreateSession", "SelectSources"]);
+    assert!(!dir.join("token").exists());
+}
+
+#[tokio::test]
+async fn ended_start_fails_the_handshake() {
+    for code in [CANCELLED, UNKNOWN_ENDED, 7] {
+        let dir = token_dir(&format!("end-start-{code}"));
+        let script = Script {
+            start: code,
+            ..Script::default()
+        };
+        let (portal, desktops) = open_session(script, &dir).await;
+
+        let error = portal_error(desktops);
+        match code {
+            CANCELLED => assert!(matches!(error, PortalError::Cancelled)),
+            UNKNOWN_ENDED => assert!(matches!(error, PortalError::UnknownEnded)),
+            _ => assert!(matches!(error, PortalError::UnknownResponse(7))),
+        }
+        assert_eq!(
+            portal.log().calls,
+            ["CreateSession", "SelectSources", "Start"]
+        );
+        assert!(!dir.join("token").exists());
+    }
+}
+
+#[tokio::test]
+async fn streams_missing_their_size_or_id_are_left_out() {
+    let dir = token_dir("missing-properties");
+    let script = Script {
+        streams: vec![
+            (
+                40,
+                StreamProperties {
+                    size: None,
+                    ..StreamProperties::monitor("0", (0, 0), (1920, 1080))
+                },
+            ),
+            (
+                41,
+                StreamProperties {
+                    id: None,
+                    ..StreamProperties::monitor("1", (1920, 0), (1920, 1080))
+                },
+            ),
+            (
+                42,
+                StreamProperties {
+                    position: None,
+                    ..StreamProperties::monitor("2", (3840, 0), (1280, 720))
+                },
+            ),
+        ],
+        ..Script::default()
+    };
+    let (_portal, desktops) = open_session(script, &dir).await;
+
+    // Desktops keep the index of their stream as their id
+    assert_eq!(summary(&desktops.unwrap()), [(2, "2", 42, 1280, 720, None)]);
+}
+
+#[tokio::test]
+async fn session_can_only_be_opened_once() {
+    let dir = token_dir("twice");
+    let portal = MockPortal::start(Script::default()).await;
+    let mut capture = CaptureManager::with_connection(portal.connect().await, &dir);
+
+    capture.open_session(None).await.unwrap();
+    assert!(capture.session_handle().is_some());
+    assert!(capture.open_session(None).await.is_err());
+    assert_eq!(
+        portal.log().calls,
+        ["CreateSession", "SelectSources", "Start"]
+    );
+}
+
+#[tokio::test]
+async fn missing_restore_token_is_not_fatal() {
+    let dir = token_dir("no-restore-token");
+    let script = Script {
+        restore_token: None,
+        ..Script::default()
+    };
+    let (_portal, desktops) = open_session(script, &dir).await;
+
+    assert_eq!(desktops.unwrap().len(), 1);
+    assert!(!dir.join("token").exists());
+}
+
+#[tokio::test]
+async fn missing_session_handle_fails_the_handshake() {
+    let dir = token_dir("no-session-handle");
+    let script = Script {
+        session_handle: false,
+        ..Script::default()
+    };
+    let (portal, desktops) = open_session(script, &dir).await;
+
+    assert!(matches!(
+        portal_error(desktops),
+        PortalError::MissingField("session_handle")
+    ));
+    assert_eq!(portal.log().calls, ["CreateSession"]);
+}
+
+#[tokio::test]
+async fn mismatched_request_handle_fails_the_handshake() {
+    let dir = token_dir("mismatched-handle");
+    let script = Script {
+        mismatched_handles: true,
+        ..Script::default()
+    };
+    let (_portal, desktops) = open_session(script, &dir).await;
+
+    let PortalError::PathMismatch { expected, found } = portal_error(desktops) else {
+        panic!("The mismatched handle was accepted");
+    };
+    assert!(found.as_str().ends_with("/mismatched"));
+    assert_ne!(expected, found);
+}
+
+#[tokio::test]
+async fn closed_session_is_noticed_and_reopened_with_the_restore_token() {
+    let dir = token_dir("closed");
+    let portal = MockPortal::start(Script::default()).await;
+    let mut capture = CaptureManager::with_connection(portal.connect().await, &dir);
+
+    capture.open_session(None).await.unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), capture.closed())
+            .await
+            .is_err(),
+        "The session closed on its own"
+    );
+
+    portal.close_session().await;
+    tokio::time::timeout(Duration::from_secs(10), capture.closed())
+        .await
+        .expect("The closed session went unnoticed");
+
+    capture.end_session();
+    assert!(capture.session_handle().is_none());
+    assert_eq!(capture.open_session(None).await.unwrap().len(), 1);
+    let log = portal.log();
+    assert_eq!(
+        log.calls,
+        ["CreateSession", "SelectSources", "Start"].repeat(2)
+    );
+    // The session was closed by the portal, loded had nothing left to close
+    assert!(!log.session_closed);
+    assert_eq!(log.restore_token.as_deref(), Some("restore-1"));
+}
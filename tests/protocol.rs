@@ -0,0 +1,1001 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use proptest::prelude::*;
+
+use evdev::Key;
+use loded::{
+    protocol::{
+        ClipboardMime, Decode, Encode, LodestarCapabilities, LodestarClipboardDataPacket,
+        LodestarClipboardEntry, LodestarClipboardOfferPacket, LodestarClipboardRequestPacket,
+        LodestarCloseReason, LodestarDesktop, LodestarDesktopPacket, LodestarEndPacket,
+        LodestarHandshakePacket, LodestarHandshakeResultPacket, LodestarHandshakeStatus,
+        LodestarInput, LodestarInputEvent, LodestarInputPacket, LodestarKeyframeRequestPacket,
+        LodestarPacket, LodestarPacketParsingError, LodestarPacketType,
+        LodestarSessionClosedPacket, LodestarSwitchSourcePacket, LodestarVideoFramePacket,
+        PairingKey, API_REVISION, MIN_API_REVISION, PACKET_HEADER_LENGTH,
+    },
+    testing::{
+        DeltaMode, GamepadButtons, GamepadState, KeyEvent, MouseButtonEvent, MouseMoveEvent,
+        PenButtons,
+    },
+    KeyDirection,
+};
+
+/// Encodes `packet` with its header, decodes it back and checks nothing was lost
+fn round_trip<T: Encode + Decode + PartialEq + std::fmt::Debug>(packet: &T) {
+    let mut encoded = LodestarPacket::encode_packet(packet);
+    assert_eq!(encoded.len(), PACKET_HEADER_LENGTH + packet.encoded_len());
+
+    let decoded = LodestarPacket::decode(&mut encoded).unwrap();
+    assert!(encoded.is_empty());
+    assert_eq!(decoded.packet_type(), T::PACKET_TYPE);
+    assert_eq!(&decoded.parse_packet::<T>().unwrap(), packet);
+}
+
+fn raw_packet(packet_type: u64, body: &[u8]) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_u64_le(packet_type);
+    buf.put_u64_le(body.len() as u64);
+    buf.put_slice(body);
+    buf.freeze()
+}
+
+fn desktop() -> impl Strategy<Value = LodestarDesktop> {
+    (
+        any::<u64>(),
+        1..=i32::MAX,
+        1..=i32::MAX,
+        proptest::option::of("\\PC{0,63}"),
+        proptest::option::of(any::<(i32, i32)>()),
+    )
+        .prop_map(
+            |(loded_id, width, height, name, position)| LodestarDesktop {
+                loded_id,
+                width,
+                height,
+                name,
+                position,
+            },
+        )
+}
+
+fn direction() -> impl Strategy<Value = KeyDirection> {
+    prop_oneof![
+        Just(KeyDirection::Up),
+        Just(KeyDirection::Down),
+        Just(KeyDirection::RepeatingDown),
+    ]
+}
+
+fn input() -> impl Strategy<Value = LodestarInput> {
+    prop_oneof![
+        (prop_oneof![0x01u16..=0xff, 0x160u16..=0x2bf], direction()).prop_map(
+            |(code, direction)| LodestarInput::Key(KeyEvent {
+                key: Key::new(code),
+                direction,
+            })
+        ),
+        any::<(i32, i32)>()
+            .prop_map(|(x, y)| LodestarInput::RelativeMotion(MouseMoveEvent::new(x, y, 0, 0))),
+        any::<(u64, i32, i32)>().prop_map(|(loded_id, x, y)| LodestarInput::AbsoluteMotion {
+            loded_id,
+            x,
+            y
+        }),
+        (Key::BTN_LEFT.code()..=Key::BTN_TASK.code(), direction()).prop_map(|(code, direction)| {
+            LodestarInput::Button(MouseButtonEvent {
+                key: Key::new(code),
+                direction,
+            })
+        }),
+        any::<i32>().prop_map(|wheel| LodestarInput::Scroll(MouseMoveEvent::new(0, 0, wheel, 0))),
+        (
+            -1e6..1e6f64,
+            -1e6..1e6f64,
+            prop_oneof![
+                Just(DeltaMode::Pixel),
+                Just(DeltaMode::Line),
+                Just(DeltaMode::Page)
+            ],
+        )
+            .prop_map(|(delta_x, delta_y, mode)| LodestarInput::Wheel {
+                delta_x,
+                delta_y,
+                mode
+            }),
+        any::<(u64, u32, i32, i32)>().prop_map(|(loded_id, contact, x, y)| {
+            LodestarInput::TouchStart {
+                loded_id,
+                contact,
+                x,
+                y,
+            }
+        }),
+        any::<(u64, u32, i32, i32)>().prop_map(|(loded_id, contact, x, y)| {
+            LodestarInput::TouchMove {
+                loded_id,
+                contact,
+                x,
+                y,
+            }
+        }),
+        any::<u32>().prop_map(|contact| LodestarInput::TouchEnd { contact }),
+        (
+            any::<(u64, i32, i32, u16)>(),
+            -90i8..=90,
+            -90i8..=90,
+            0..=PenButtons::ALL.0
+        )
+            .prop_map(|((loded_id, x, y, pressure), tilt_x, tilt_y, buttons)| {
+                LodestarInput::Pen {
+                    loded_id,
+                    x,
+                    y,
+                    pressure,
+                    tilt_x,
+                    tilt_y,
+                    buttons: PenButtons(buttons),
+                }
+            }),
+        (
+            any::<u32>(),
+            any::<u32>(),
+            any::<(i16, i16, i16, i16, u8, u8)>()
+        )
+            .prop_map(
+                |(
+                    pad,
+                    buttons,
+                    (left_x, left_y, right_x, right_y, left_trigger, right_trigger),
+                )| {
+                    LodestarInput::Gamepad {
+                        pad,
+                        state: GamepadState {
+                            buttons: GamepadButtons(buttons & GamepadButtons::ALL.0),
+                            left_x,
+                            left_y,
+                            right_x,
+                            right_y,
+                            left_trigger,
+                            right_trigger,
+                        },
+                    }
+                }
+            ),
+        any::<u32>().prop_map(|pad| LodestarInput::GamepadRemoved { pad }),
+        ".{0,32}".prop_map(LodestarInput::Text),
+    ]
+}
+
+fn clipboard_mime() -> impl Strategy<Value = ClipboardMime> {
+    proptest::sample::select(ClipboardMime::ALL.to_vec())
+}
+
+fn clipboard_entry(mime_type: ClipboardMime) -> impl Strategy<Value = LodestarClipboardEntry> {
+    let data = match mime_type {
+        ClipboardMime::Text | ClipboardMime::Html => "\\PC{0,64}"
+            .prop_map(|text| Bytes::from(text.into_bytes()))
+            .boxed(),
+        ClipboardMime::Png => proptest::collection::vec(any::<u8>(), 0..256)
+            .prop_map(Bytes::from)
+            .boxed(),
+    };
+    data.prop_map(move |data| LodestarClipboardEntry { mime_type, data })
+}
+
+proptest! {
+    #[test]
+    fn legacy_handshake_round_trips(api_revision in any::<u64>(), accepted in any::<bool>()) {
+        round_trip(&LodestarHandshakePacket::Legacy { api_revision, accepted });
+    }
+
+    #[test]
+    fn hello_handshake_round_trips(
+        (min_revision, max_revision) in any::<(u64, u64)>().prop_map(|(a, b)| (a.min(b), a.max(b))),
+        capabilities in any::<u64>(),
+        pairing_key in proptest::option::of(any::<[u8; PairingKey::LENGTH]>().prop_map(PairingKey)),
+    ) {
+        round_trip(&LodestarHandshakePacket::Hello {
+            min_revision,
+            max_revision,
+            capabilities: LodestarCapabilities(capabilities),
+            pairing_key,
+        });
+    }
+
+    #[test]
+    fn handshake_result_round_trips(
+        min_revision in any::<u64>(),
+        max_revision in any::<u64>(),
+        capabilities in any::<u64>(),
+    ) {
+        round_trip(&LodestarHandshakeResultPacket::negotiate(
+            min_revision,
+            max_revision,
+            LodestarCapabilities(capabilities),
+        ));
+    }
+
+    #[test]
+    fn desktop_list_round_trips(desktops in proptest::collection::vec(desktop(), 0..8)) {
+        round_trip(&LodestarDesktopPacket::new(desktops));
+    }
+
+    #[test]
+    fn switch_source_round_trips(new_source in any::<u64>()) {
+        round_trip(&LodestarSwitchSourcePacket { new_source });
+    }
+
+    #[test]
+    fn keyframe_request_round_trips(loded_id in any::<u64>()) {
+        round_trip(&LodestarKeyframeRequestPacket { loded_id });
+    }
+
+    #[test]
+    fn input_round_trips(
+        events in proptest::collection::vec(
+            (any::<u64>(), input()).prop_map(|(timestamp, input)| LodestarInputEvent { timestamp, input }),
+            0..32,
+        )
+    ) {
+        round_trip(&LodestarInputPacket { events });
+    }
+
+    #[test]
+    fn video_frame_round_trips(
+        loded_id in any::<u64>(),
+        pts in any::<u64>(),
+        keyframe in any::<bool>(),
+        data in proptest::collection::vec(any::<u8>(), 0..512),
+    ) {
+        round_trip(&LodestarVideoFramePacket {
+            loded_id,
+            pts,
+            keyframe,
+            data: data.into(),
+        });
+    }
+
+    #[test]
+    fn clipboard_offer_round_trips(
+        mime_types in proptest::sample::subsequence(ClipboardMime::ALL.to_vec(), 0..=3).prop_shuffle(),
+    ) {
+        round_trip(&LodestarClipboardOfferPacket { mime_types });
+    }
+
+    #[test]
+    fn clipboard_request_round_trips(mime_type in clipboard_mime()) {
+        round_trip(&LodestarClipboardRequestPacket { mime_type });
+    }
+
+    #[test]
+    fn clipboard_data_round_trips(
+        entries in proptest::sample::subsequence(ClipboardMime::ALL.to_vec(), 0..=3)
+            .prop_flat_map(|mimes| mimes.into_iter().map(clipboard_entry).collect::<Vec<_>>()),
+    ) {
+        round_trip(&LodestarClipboardDataPacket { entries });
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+        let mut buf = Bytes::from(data);
+        if let Ok(packet) = LodestarPacket::decode(&mut buf) {
+            let _ = packet.parse_packet::<LodestarHandshakePacket>();
+            let _ = packet.parse_packet::<LodestarHandshakeResultPacket>();
+            let _ = packet.parse_packet::<LodestarDesktopPacket>();
+            let _ = packet.parse_packet::<LodestarSwitchSourcePacket>();
+            let _ = packet.parse_packet::<LodestarEndPacket>();
+            let _ = packet.parse_packet::<LodestarInputPacket>();
+            let _ = packet.parse_packet::<LodestarVideoFramePacket>();
+            let _ = packet.parse_packet::<LodestarKeyframeRequestPacket>();
+            let _ = packet.parse_packet::<LodestarClipboardOfferPacket>();
+            let _ = packet.parse_packet::<LodestarClipboardRequestPacket>();
+            let _ = packet.parse_packet::<LodestarClipboardDataPacket>();
+            let _ = packet.parse_packet::<LodestarSessionClosedPacket>();
+        }
+    }
+}
+
+#[test]
+fn end_round_trips() {
+    round_trip(&LodestarEndPacket {});
+}
+
+#[test]
+fn session_closed_round_trips() {
+    for reason in [
+        LodestarCloseReason::Revoked,
+        LodestarCloseReason::ReopenFailed,
+        LodestarCloseReason::PipelineFailed,
+    ] {
+        for reopening in [false, true] {
+            round_trip(&LodestarSessionClosedPacket { reason, reopening });
+        }
+    }
+}
+
+#[test]
+fn negotiation_picks_highest_common_revision() {
+    let result = LodestarHandshakeResultPacket::negotiate(
+        MIN_API_REVISION,
+        u64::MAX,
+        LodestarCapabilities::KEYBOARD | LodestarCapabilities(1 << 63),
+    );
+    assert_eq!(result.status, LodestarHandshakeStatus::Accepted);
+    assert_eq!(result.revision, API_REVISION);
+    assert_eq!(result.capabilities, LodestarCapabilities::KEYBOARD);
+
+    let result =
+        LodestarHandshakeResultPacket::negotiate(0, MIN_API_REVISION, LodestarCapabilities::NONE);
+    assert_eq!(result.status, LodestarHandshakeStatus::Accepted);
+    assert_eq!(result.revision, MIN_API_REVISION);
+}
+
+#[test]
+fn negotiation_keeps_only_the_preferred_codec() {
+    let result = LodestarHandshakeResultPacket::negotiate(
+        MIN_API_REVISION,
+        API_REVISION,
+        LodestarCapabilities::KEYBOARD
+            | LodestarCapabilities::CODEC_VP8
+            | LodestarCapabilities::CODEC_H264
+            | LodestarCapabilities::CODEC_AV1,
+    );
+    assert_eq!(
+        result.capabilities,
+        LodestarCapabilities::KEYBOARD | LodestarCapabilities::CODEC_H264
+    );
+
+    let result = LodestarHandshakeResultPacket::negotiate(
+        MIN_API_REVISION,
+        API_REVISION,
+        (LodestarCapabilities::CODEC_H264 | LodestarCapabilities::CODEC_VP9)
+            .restrict_codecs(LodestarCapabilities::CODEC_VP9 | LodestarCapabilities::CODEC_RAW),
+    );
+    assert_eq!(result.capabilities, LodestarCapabilities::CODEC_VP9);
+    assert_eq!(
+        result.capabilities.preferred_codec(),
+        Some(LodestarCapabilities::CODEC_VP9)
+    );
+}
+
+#[test]
+fn negotiation_rejects_disjoint_ranges() {
+    let result = LodestarHandshakeResultPacket::negotiate(
+        API_REVISION + 1,
+        API_REVISION + 5,
+        LodestarCapabilities::KEYBOARD,
+    );
+    assert_eq!(result.status, LodestarHandshakeStatus::NoCommonRevision);
+    assert_eq!(result.revision, 0);
+    assert_eq!(result.capabilities, LodestarCapabilities::NONE);
+    assert_eq!(result.min_revision, MIN_API_REVISION);
+    assert_eq!(result.max_revision, API_REVISION);
+}
+
+#[test]
+fn inverted_revision_range_is_rejected() {
+    let mut body = BytesMut::new();
+    body.put_u64_le(3);
+    body.put_u64_le(2);
+    body.put_u64_le(0);
+    let packet =
+        LodestarPacket::decode(&mut raw_packet(LodestarPacketType::Handshake as u64, &body))
+            .unwrap();
+    assert!(matches!(
+        packet.parse_packet::<LodestarHandshakePacket>(),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+}
+
+#[test]
+fn pairing_keys_are_hex() {
+    let key = PairingKey([0xab; PairingKey::LENGTH]);
+    let hex = key.to_string();
+    assert_eq!(hex, "ab".repeat(PairingKey::LENGTH));
+    assert_eq!(hex.parse::<PairingKey>().unwrap(), key);
+    // Stored keys may end in a newline
+    assert_eq!(format!("{hex}\n").parse::<PairingKey>().unwrap(), key);
+
+    assert!("ab"
+        .repeat(PairingKey::LENGTH - 1)
+        .parse::<PairingKey>()
+        .is_err());
+    assert!("zz"
+        .repeat(PairingKey::LENGTH)
+        .parse::<PairingKey>()
+        .is_err());
+    assert!("é"
+        .repeat(PairingKey::LENGTH)
+        .parse::<PairingKey>()
+        .is_err());
+    // Keys are never logged
+    assert_eq!(format!("{key:?}"), "PairingKey(..)");
+}
+
+#[test]
+fn pairing_keys_are_verified_in_full() {
+    let key = PairingKey::generate();
+    assert!(key.verify(&key.clone()));
+
+    let mut wrong = key.clone();
+    wrong.0[PairingKey::LENGTH - 1] ^= 1;
+    assert!(!key.verify(&wrong));
+    assert_ne!(PairingKey::generate(), key);
+}
+
+#[test]
+fn unauthorized_handshake_round_trips() {
+    let result = LodestarHandshakeResultPacket::unauthorized();
+    assert_eq!(result.status, LodestarHandshakeStatus::Unauthorized);
+    assert_eq!(result.revision, 0);
+    round_trip(&result);
+}
+
+#[test]
+fn unknown_handshake_status_is_rejected() {
+    let mut body = BytesMut::new();
+    body.put_u64_le(u64::MAX);
+    body.put_slice(&[0; 32]);
+    let packet = LodestarPacket::decode(&mut raw_packet(
+        LodestarPacketType::HandshakeResult as u64,
+        &body,
+    ))
+    .unwrap();
+    assert!(matches!(
+        packet.parse_packet::<LodestarHandshakeResultPacket>(),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+}
+
+#[test]
+fn desktop_list_layout() {
+    let packet = LodestarDesktopPacket::new(vec![
+        LodestarDesktop {
+            loded_id: 1,
+            width: 1920,
+            height: 1080,
+            name: None,
+            position: None,
+        },
+        LodestarDesktop {
+            loded_id: 2,
+            width: 800,
+            height: 600,
+            name: Some("DP-1".to_owned()),
+            position: Some((1920, 0)),
+        },
+    ]);
+
+    let mut expected = BytesMut::new();
+    expected.put_u64_le(2);
+    expected.put_u64_le(1);
+    expected.put_i32_le(1920);
+    expected.put_i32_le(1080);
+    expected.put_u8(0);
+    expected.put_u64_le(2);
+    expected.put_i32_le(800);
+    expected.put_i32_le(600);
+    expected.put_u8(0b11);
+    expected.put_u8(4);
+    expected.put_slice(b"DP-1");
+    expected.put_i32_le(1920);
+    expected.put_i32_le(0);
+
+    let encoded = LodestarPacket::encode_packet(&packet);
+    assert_eq!(&encoded[PACKET_HEADER_LENGTH..], &expected[..]);
+}
+
+#[test]
+fn long_desktop_names_are_truncated() {
+    let packet = LodestarDesktopPacket::new(vec![LodestarDesktop {
+        loded_id: 0,
+        width: 1,
+        height: 1,
+        name: Some("é".repeat(200)),
+        position: None,
+    }]);
+
+    let mut encoded = LodestarPacket::encode_packet(&packet);
+    let decoded = LodestarPacket::decode(&mut encoded)
+        .unwrap()
+        .parse_packet::<LodestarDesktopPacket>()
+        .unwrap();
+    let name = decoded.get_desktops()[0].name.as_ref().unwrap();
+    assert!(name.len() <= LodestarDesktop::MAX_NAME_LENGTH);
+    assert!(name.chars().all(|c| c == 'é'));
+}
+
+#[test]
+fn unknown_packet_type_is_rejected() {
+    let mut buf = raw_packet(u64::MAX, &[]);
+    assert!(matches!(
+        LodestarPacket::decode(&mut buf),
+        Err(LodestarPacketParsingError::UnknownPacketType(u64::MAX))
+    ));
+}
+
+#[test]
+fn fixed_length_mismatch_is_rejected() {
+    let mut buf = raw_packet(LodestarPacketType::SwitchSource as u64, &[0; 9]);
+    assert!(matches!(
+        LodestarPacket::decode(&mut buf),
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    ));
+}
+
+#[test]
+fn keyframe_request_length_is_fixed() {
+    let mut buf = raw_packet(LodestarPacketType::KeyframeRequest as u64, &[0; 16]);
+    assert!(matches!(
+        LodestarPacket::decode(&mut buf),
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    ));
+}
+
+#[test]
+fn truncated_body_is_rejected() {
+    let mut buf = raw_packet(LodestarPacketType::Handshake as u64, &[0; 16]);
+    let mut truncated = buf.split_to(buf.len() - 1);
+    assert!(matches!(
+        LodestarPacket::decode(&mut truncated),
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    ));
+}
+
+#[test]
+fn invalid_handshake_bool_is_rejected() {
+    let mut body = BytesMut::new();
+    body.put_u64_le(1);
+    body.put_u64_le(2);
+    let packet =
+        LodestarPacket::decode(&mut raw_packet(LodestarPacketType::Handshake as u64, &body))
+            .unwrap();
+    assert!(matches!(
+        packet.parse_packet::<LodestarHandshakePacket>(),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+}
+
+#[test]
+fn hellos_with_stray_bytes_are_rejected() {
+    for extra in [1, 31, 33] {
+        let mut body = BytesMut::new();
+        body.put_u64_le(1);
+        body.put_u64_le(2);
+        body.put_u64_le(0);
+        body.put_bytes(0, extra);
+        assert!(
+            matches!(
+                LodestarHandshakePacket::decode(&mut body.freeze()),
+                Err(LodestarPacketParsingError::InvalidPacketLength)
+            ),
+            "{extra} stray bytes"
+        );
+    }
+}
+
+#[test]
+fn mismatched_packet_type_is_rejected() {
+    let mut encoded = LodestarPacket::encode_packet(&LodestarEndPacket {});
+    let packet = LodestarPacket::decode(&mut encoded).unwrap();
+    assert!(matches!(
+        packet.parse_packet::<LodestarSwitchSourcePacket>(),
+        Err(LodestarPacketParsingError::UnexpectedPacketType {
+            expected: LodestarPacketType::SwitchSource,
+            found: LodestarPacketType::End,
+        })
+    ));
+}
+
+#[test]
+fn oversized_desktop_count_is_rejected() {
+    let mut body = BytesMut::new();
+    body.put_u64_le(u64::MAX);
+    let packet = LodestarPacket::decode(&mut raw_packet(
+        LodestarPacketType::DesktopList as u64,
+        &body,
+    ))
+    .unwrap();
+    assert!(matches!(
+        packet.parse_packet::<LodestarDesktopPacket>(),
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    ));
+}
+
+#[test]
+fn trailing_desktop_list_bytes_are_rejected() {
+    let mut body = BytesMut::new();
+    body.put_u64_le(0);
+    body.put_u8(0);
+    let packet = LodestarPacket::decode(&mut raw_packet(
+        LodestarPacketType::DesktopList as u64,
+        &body,
+    ))
+    .unwrap();
+    assert!(matches!(
+        packet.parse_packet::<LodestarDesktopPacket>(),
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    ));
+}
+
+#[test]
+fn invalid_desktop_fields_are_rejected() {
+    let entry = |width: i32, flags: u8, tail: &[u8]| {
+        let mut body = BytesMut::new();
+        body.put_u64_le(1);
+        body.put_u64_le(0);
+        body.put_i32_le(width);
+        body.put_i32_le(1);
+        body.put_u8(flags);
+        body.put_slice(tail);
+        LodestarPacket::decode(&mut raw_packet(
+            LodestarPacketType::DesktopList as u64,
+            &body,
+        ))
+        .unwrap()
+        .parse_packet::<LodestarDesktopPacket>()
+    };
+
+    assert!(entry(1, 0, &[]).is_ok());
+    assert!(matches!(
+        entry(0, 0, &[]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        entry(1, 1 << 7, &[]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        entry(1, 1, &[2, 0xff, 0xfe]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        entry(1, 1, &[5, b'a']),
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    ));
+    assert!(matches!(
+        entry(1, 1 << 2, &[]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+}
+
+#[test]
+fn invalid_input_events_are_rejected() {
+    let event = |tag: u8, payload: &[u8]| {
+        let mut body = BytesMut::new();
+        body.put_u64_le(1);
+        body.put_u8(tag);
+        body.put_u64_le(0);
+        body.put_slice(payload);
+        LodestarPacket::decode(&mut raw_packet(LodestarPacketType::Input as u64, &body))
+            .unwrap()
+            .parse_packet::<LodestarInputPacket>()
+    };
+
+    let key_a = Key::KEY_A.code().to_le_bytes();
+    let btn_left = Key::BTN_LEFT.code().to_le_bytes();
+
+    assert!(event(0, &[key_a[0], key_a[1], 1]).is_ok());
+    assert!(matches!(
+        event(0, &[key_a[0], key_a[1], 3]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        event(0, &[btn_left[0], btn_left[1], 1]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(event(3, &[btn_left[0], btn_left[1], 0]).is_ok());
+    assert!(matches!(
+        event(3, &[key_a[0], key_a[1], 0]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        event(200, &[0, 0, 0]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        event(2, &[0; 15]),
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    ));
+
+    let pen = |tilt_x: i8, tilt_y: i8, buttons: u8| {
+        let mut payload = vec![0; 18];
+        payload.extend([tilt_x as u8, tilt_y as u8, buttons]);
+        event(9, &payload)
+    };
+    assert!(pen(-90, 90, PenButtons::ALL.0).is_ok());
+    assert!(matches!(
+        pen(91, 0, 0),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        pen(0, -91, 0),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        pen(0, 0, 1 << 7),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+
+    let gamepad = |buttons: u32| {
+        let mut payload = vec![0; 4];
+        payload.extend(buttons.to_le_bytes());
+        payload.extend([0; 10]);
+        event(10, &payload)
+    };
+    assert!(gamepad(GamepadButtons::ALL.0).is_ok());
+    // The triggers are sent as analog values only
+    assert!(matches!(
+        gamepad(1 << 6),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        gamepad(1 << 17),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+
+    let text = |len: u32, text: &[u8]| {
+        let mut payload = len.to_le_bytes().to_vec();
+        payload.extend(text);
+        event(12, &payload)
+    };
+    assert!(text(2, "ä".as_bytes()).is_ok());
+    assert!(matches!(
+        text(2, &[0xc3, 0x28]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        text(LodestarInput::MAX_TEXT_LENGTH as u32 + 1, &[]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+}
+
+#[test]
+fn vertical_only_scroll_is_still_accepted() {
+    let mut body = BytesMut::new();
+    body.put_u64_le(1);
+    body.put_u8(4);
+    body.put_u64_le(0);
+    body.put_i32_le(-240);
+
+    let packet = LodestarPacket::decode(&mut raw_packet(LodestarPacketType::Input as u64, &body))
+        .unwrap()
+        .parse_packet::<LodestarInputPacket>()
+        .unwrap();
+    assert_eq!(
+        packet.events[0].input,
+        LodestarInput::Scroll(MouseMoveEvent::new(0, 0, -240, 0))
+    );
+}
+
+#[test]
+fn legacy_scroll_keeps_its_hi_res_units() {
+    // Older clients already sent hi-res units, positive scrolling up
+    assert_eq!(
+        LodestarInput::Scroll(MouseMoveEvent::new(0, 0, -240, 0)).hi_res_scroll(),
+        Some(MouseMoveEvent::new(0, 0, -240, 0))
+    );
+
+    // Browsers count downwards in whatever unit their deltaMode says
+    let wheel = LodestarInput::Wheel {
+        delta_x: 0.0,
+        delta_y: 200.0,
+        mode: DeltaMode::Pixel,
+    };
+    assert_eq!(
+        wheel.hi_res_scroll(),
+        Some(MouseMoveEvent::new(0, 0, -240, 0))
+    );
+    let wheel = LodestarInput::Wheel {
+        delta_x: 1.0,
+        delta_y: 0.0,
+        mode: DeltaMode::Line,
+    };
+    assert_eq!(
+        wheel.hi_res_scroll(),
+        Some(MouseMoveEvent::new(0, 0, 0, 40))
+    );
+}
+
+#[test]
+fn invalid_wheel_deltas_are_rejected() {
+    let parse = |delta_y: f64, mode: u8| {
+        let mut body = BytesMut::new();
+        body.put_u64_le(1);
+        body.put_u8(5);
+        body.put_u64_le(0);
+        body.put_f64_le(0.0);
+        body.put_f64_le(delta_y);
+        body.put_u8(mode);
+        LodestarPacket::decode(&mut raw_packet(LodestarPacketType::Input as u64, &body))
+            .unwrap()
+            .parse_packet::<LodestarInputPacket>()
+    };
+
+    assert!(parse(-3.0, 1).is_ok());
+    assert!(matches!(
+        parse(-3.0, 3),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    for delta_y in [f64::NAN, f64::INFINITY] {
+        assert!(matches!(
+            parse(delta_y, 0),
+            Err(LodestarPacketParsingError::InvalidField)
+        ));
+    }
+}
+
+#[test]
+fn long_text_is_truncated() {
+    let text = "ä".repeat(LodestarInput::MAX_TEXT_LENGTH);
+    let packet = LodestarInputPacket {
+        events: vec![LodestarInputEvent {
+            timestamp: 0,
+            input: LodestarInput::Text(text.clone()),
+        }],
+    };
+
+    let decoded = LodestarPacket::decode(&mut LodestarPacket::encode_packet(&packet))
+        .unwrap()
+        .parse_packet::<LodestarInputPacket>()
+        .unwrap();
+    let LodestarInput::Text(decoded) = &decoded.events[0].input else {
+        panic!("{decoded:?} is not text");
+    };
+    assert_eq!(decoded.len(), LodestarInput::MAX_TEXT_LENGTH);
+    assert!(text.starts_with(decoded.as_str()));
+}
+
+#[test]
+fn host_mime_types_map_to_clipboard_formats() {
+    assert_eq!(
+        ClipboardMime::from_mime_type("UTF8_STRING"),
+        Some(ClipboardMime::Text)
+    );
+    assert_eq!(
+        ClipboardMime::from_mime_type("text/html"),
+        Some(ClipboardMime::Html)
+    );
+    assert_eq!(
+        ClipboardMime::from_mime_type("image/png"),
+        Some(ClipboardMime::Png)
+    );
+    assert_eq!(ClipboardMime::from_mime_type("image/jpeg"), None);
+    for mime in ClipboardMime::ALL {
+        assert_eq!(
+            ClipboardMime::from_mime_type(mime.mime_types()[0]),
+            Some(mime)
+        );
+    }
+}
+
+#[test]
+fn invalid_clipboard_offers_are_rejected() {
+    let parse = |body: &[u8]| {
+        LodestarPacket::decode(&mut raw_packet(
+            LodestarPacketType::ClipboardOffer as u64,
+            body,
+        ))
+        .unwrap()
+        .parse_packet::<LodestarClipboardOfferPacket>()
+    };
+
+    assert!(parse(&[1, 2]).is_ok());
+    // Unknown format
+    assert!(matches!(
+        parse(&[1, 3]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    // The same format twice
+    assert!(matches!(
+        parse(&[2, 0, 0]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    // More formats than there are
+    assert!(matches!(
+        parse(&[4, 0, 1, 2, 0]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+}
+
+#[test]
+fn clipboard_request_length_is_fixed() {
+    let mut buf = raw_packet(LodestarPacketType::ClipboardRequest as u64, &[0; 2]);
+    assert!(matches!(
+        LodestarPacket::decode(&mut buf),
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    ));
+}
+
+#[test]
+fn invalid_clipboard_data_is_rejected() {
+    let parse = |entries: &[(u8, &[u8])]| {
+        let mut body = BytesMut::new();
+        body.put_u8(entries.len() as u8);
+        for (mime, data) in entries {
+            body.put_u8(*mime);
+            body.put_u32_le(data.len() as u32);
+            body.put_slice(data);
+        }
+        LodestarPacket::decode(&mut raw_packet(
+            LodestarPacketType::ClipboardData as u64,
+            &body,
+        ))
+        .unwrap()
+        .parse_packet::<LodestarClipboardDataPacket>()
+    };
+    let invalid = |entries: &[(u8, &[u8])]| {
+        matches!(
+            parse(entries),
+            Err(LodestarPacketParsingError::InvalidField)
+        )
+    };
+
+    assert!(parse(&[(0, b"text"), (2, &[0xff])]).is_ok());
+    assert!(invalid(&[(3, b"")]));
+    assert!(invalid(&[(2, b""), (2, b"")]));
+    // Text and HTML have to be UTF-8, images don't
+    assert!(invalid(&[(0, &[0xff])]));
+    assert!(invalid(&[(1, &[0xc3])]));
+
+    let half = vec![0; LodestarClipboardDataPacket::MAX_LENGTH / 2];
+    assert!(parse(&[(0, &half), (1, &half)]).is_ok());
+    assert!(invalid(&[(0, &half), (1, &half), (2, &[0])]));
+}
+
+#[test]
+fn clipboard_data_length_is_checked_before_reading() {
+    let mut body = BytesMut::new();
+    body.put_u8(1);
+    body.put_u8(ClipboardMime::Png as u8);
+    body.put_u32_le(u32::MAX);
+    let packet = LodestarPacket::decode(&mut raw_packet(
+        LodestarPacketType::ClipboardData as u64,
+        &body,
+    ))
+    .unwrap();
+    assert!(matches!(
+        packet.parse_packet::<LodestarClipboardDataPacket>(),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+
+    let mut body = BytesMut::new();
+    body.put_u8(1);
+    body.put_u8(ClipboardMime::Png as u8);
+    body.put_u32_le(4);
+    body.put_slice(&[0; 3]);
+    let packet = LodestarPacket::decode(&mut raw_packet(
+        LodestarPacketType::ClipboardData as u64,
+        &body,
+    ))
+    .unwrap();
+    assert!(matches!(
+        packet.parse_packet::<LodestarClipboardDataPacket>(),
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    ));
+}
+
+#[test]
+fn invalid_session_closed_packets_are_rejected() {
+    let parse = |body: &[u8]| {
+        LodestarPacket::decode(&mut raw_packet(
+            LodestarPacketType::SessionClosed as u64,
+            body,
+        ))
+        .and_then(|packet| packet.parse_packet::<LodestarSessionClosedPacket>())
+    };
+
+    assert!(parse(&[2, 0]).is_ok());
+    assert!(matches!(
+        parse(&[3, 0]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        parse(&[0, 2]),
+        Err(LodestarPacketParsingError::InvalidField)
+    ));
+    assert!(matches!(
+        parse(&[0]),
+        Err(LodestarPacketParsingError::InvalidPacketLength)
+    ));
+}